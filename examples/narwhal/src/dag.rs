@@ -1,14 +1,13 @@
 use crate::transaction::Transaction;
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
-pub struct DAG {
+pub struct Dag {
     pub transactions: HashMap<String, Transaction>,
 }
 
-impl DAG {
+impl Dag {
     pub fn new() -> Self {
-        DAG {
+        Dag {
             transactions: HashMap::new(),
         }
     }
@@ -27,39 +26,13 @@ impl DAG {
             .all(|parent_id| self.transactions.contains_key(parent_id))
     }
 
-    pub fn print_dag(&self) {
-        for (id, txn) in &self.transactions {
-            println!(
-                "Transaction ID: {}, Data: {}, Parents: {:?}",
-                id, txn.data, txn.parents
-            );
-        }
-    }
-
     pub fn get_all_transactions(&self) -> Vec<&Transaction> {
         self.transactions.values().collect()
     }
 }
 
-impl Default for DAG {
+impl Default for Dag {
     fn default() -> Self {
         Self::new()
     }
 }
-
-impl Transaction {
-    pub fn data(&self) -> &str {
-        &self.data
-    }
-
-    pub fn parents(&self) -> &Vec<String> {
-        &self.parents
-    }
-
-    pub fn hash(&self) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.data);
-        hasher.update(&self.id);
-        format!("{:x}", hasher.finalize())
-    }
-}
@@ -2,9 +2,9 @@ mod dag;
 mod message;
 mod transaction;
 
-use narwhal::p2plane::network::{Node, NodeConfig};
+use narwhal::p2plane::{admin, network::{Node, NodeConfig}};
 use crate::message::TransactionMessage;
-use crate::dag::DAG;
+use crate::dag::Dag;
 use crate::transaction::Transaction;
 use std::error::Error;
 use std::sync::Arc;
@@ -19,16 +19,27 @@ use axum::{
     routing::get,
 };
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use log::{info, error};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(long, default_value = "8000")]
-    port: u16,
+    /// TCP port to listen on. Overrides `listen_addr` from the config.
+    #[arg(long)]
+    port: Option<u16>,
 
     #[arg(long)]
     bootstrap: Option<String>,
+
+    /// TOML or YAML config file. `P2PLANE_*` environment variables override
+    /// its values.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration and exit.
+    #[arg(long)]
+    print_default_config: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,7 +50,7 @@ struct TransactionRequest {
 #[derive(Clone)]
 struct ApiState {
     node: Arc<Mutex<Node<TransactionMessage>>>,
-    dag: Arc<Mutex<DAG>>,
+    dag: Arc<Mutex<Dag>>,
 }
 
 async fn handle_transaction(
@@ -101,10 +112,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
     let args = Args::parse();
 
-    let config = NodeConfig {
-        listen_addr: format!("/ip4/127.0.0.1/tcp/{}", args.port).parse()?,
-        bootstrap_addr: args.bootstrap.map(|addr| addr.parse()).transpose()?,
-    };
+    if args.print_default_config {
+        print!("{}", NodeConfig::default().to_toml()?);
+        return Ok(());
+    }
+
+    let mut config = NodeConfig::load(args.config.as_deref())?;
+    let port = args.port.unwrap_or(8000);
+    // Without a port or a configured listen address, listen on 8000 as before.
+    if args.port.is_some() || config.listen_addr == NodeConfig::default().listen_addr {
+        config.listen_addr = format!("/ip4/127.0.0.1/tcp/{}", port);
+    }
+    if let Some(bootstrap) = args.bootstrap {
+        config.bootstrap_addr = Some(bootstrap.parse()?);
+    }
 
     println!("Starting node on {}", config.listen_addr);
     if let Some(ref bootstrap) = config.bootstrap_addr {
@@ -113,8 +134,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         println!("Running as bootstrap node");
     }
 
-    let node = Arc::new(Mutex::new(Node::<TransactionMessage>::new(config).await?));
-    let dag = Arc::new(Mutex::new(DAG::new()));
+    let admin_token = config.admin.token.clone();
+    let node = Node::<TransactionMessage>::new(config).await?;
+    let handle = node.handle();
+    let node = Arc::new(Mutex::new(node));
+    let dag = Arc::new(Mutex::new(Dag::new()));
     
     // Setup API state
     let api_state = ApiState {
@@ -123,11 +147,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     // Setup HTTP server
-    let api_port = args.port + 1000; // API port will be node port + 1000
+    let api_port = port + 1000; // API port will be node port + 1000
     let app = Router::new()
         .route("/health", get(health_check))
         .route("/transaction", post(handle_transaction))
-        .with_state(api_state);
+        .with_state(api_state)
+        .nest("/admin", admin::router(handle, admin_token));
 
     // Spawn HTTP server
     let addr = SocketAddr::from(([127, 0, 0, 1], api_port));
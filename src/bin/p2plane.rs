@@ -0,0 +1,411 @@
+//! `p2plane`: operational tooling for p2plane nodes.
+//!
+//! ```text
+//! p2plane key generate --out node.key
+//! p2plane key generate --type secp256k1 --encrypt --out node.key
+//! p2plane key import --type secp256k1 --out node.key validator.hex
+//! p2plane key inspect node.key
+//! p2plane key swarm-key --out swarm.key
+//! p2plane peers list --store peers.json
+//! p2plane peers add --store peers.json <peer-id> /ip4/10.0.0.1/tcp/8000
+//! p2plane config validate node.toml
+//! p2plane node peers --admin http://127.0.0.1:9000 --token secret
+//! ```
+
+use clap::{Args, Parser, Subcommand};
+use libp2p::{identity::Keypair, Multiaddr, PeerId};
+use narwhal::p2plane::{
+    address_book::AddressRecord,
+    admin::{AdminClient, DialRequest, ADMIN_TOKEN_ENV},
+    identity::{self, KeyType, Keystore, DEFAULT_PASSPHRASE_ENV},
+    storage::{self, StoredPeer},
+    ErrorChain, NodeConfig,
+};
+#[cfg(feature = "pnet")]
+use narwhal::p2plane::swarm_key;
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser)]
+#[command(
+    name = "p2plane",
+    version,
+    about = "Manage p2plane keys, peer stores and nodes"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate and inspect identity keys.
+    #[command(subcommand)]
+    Key(KeyCommand),
+    /// Edit a peer storage file.
+    #[command(subcommand)]
+    Peers(PeersCommand),
+    /// Check node configuration files.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Query a running node through its admin API.
+    #[command(subcommand)]
+    Node(NodeCommand),
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Generate a new identity and print its peer id.
+    Generate {
+        #[command(flatten)]
+        out: KeyOut,
+        /// ed25519, secp256k1 or ecdsa.
+        #[arg(long = "type", default_value = "ed25519")]
+        key_type: KeyType,
+    },
+    /// Convert an existing key, such as a raw hex secp256k1 secret, into a
+    /// key file and print its peer id.
+    Import {
+        #[command(flatten)]
+        out: KeyOut,
+        /// Type of raw secret keys in the input.
+        #[arg(long = "type", default_value = "ed25519")]
+        key_type: KeyType,
+        /// Key to import, in any format a key file may have.
+        file: PathBuf,
+    },
+    /// Print the type and peer id of a key file.
+    Inspect { path: PathBuf },
+    /// Generate a pre-shared key for a private network and print its
+    /// fingerprint.
+    #[cfg(feature = "pnet")]
+    SwarmKey {
+        /// File to write the key to, in the go-libp2p `swarm.key` format.
+        #[arg(long)]
+        out: PathBuf,
+        /// Overwrite an existing file.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+struct KeyOut {
+    /// File to write the key to, protobuf-encoded unless --encrypt is given.
+    #[arg(long)]
+    out: PathBuf,
+    /// Write a keystore encrypted with the passphrase in
+    /// $P2PLANE_KEY_PASSPHRASE.
+    #[arg(long)]
+    encrypt: bool,
+    /// Overwrite an existing file.
+    #[arg(long)]
+    force: bool,
+}
+
+fn check_overwrite(out: &Path, force: bool) -> Result<()> {
+    if out.exists() && !force {
+        return Err(format!("{} already exists, pass --force to overwrite it", out.display()).into());
+    }
+    Ok(())
+}
+
+impl KeyOut {
+    fn write(self, keypair: &Keypair) -> Result<()> {
+        check_overwrite(&self.out, self.force)?;
+        let passphrase = if self.encrypt {
+            Some(passphrase()?)
+        } else {
+            None
+        };
+        identity::save_key(&self.out, keypair, passphrase.as_deref())?;
+        println!("{}", keypair.public().to_peer_id());
+        Ok(())
+    }
+}
+
+fn passphrase() -> Result<String> {
+    std::env::var(DEFAULT_PASSPHRASE_ENV)
+        .ok()
+        .filter(|passphrase| !passphrase.is_empty())
+        .ok_or_else(|| format!("set {} to the keystore passphrase", DEFAULT_PASSPHRASE_ENV).into())
+}
+
+#[derive(Args)]
+struct StoreArg {
+    /// Peer storage file. `.sqlite` and `.db` files are SQLite databases
+    /// (`sqlite` feature), anything else is JSON.
+    #[arg(long)]
+    store: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum PeersCommand {
+    /// Print the stored peers and their addresses.
+    List {
+        #[command(flatten)]
+        store: StoreArg,
+        /// Print the entries as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Add a peer, or addresses to a known peer.
+    Add {
+        #[command(flatten)]
+        store: StoreArg,
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+    },
+    /// Remove a peer.
+    Remove {
+        #[command(flatten)]
+        store: StoreArg,
+        peer_id: PeerId,
+    },
+    /// Add or replace the peers of a JSON export.
+    Import {
+        #[command(flatten)]
+        store: StoreArg,
+        /// File to read; `-` reads standard input.
+        file: PathBuf,
+    },
+    /// Write all peers as JSON.
+    Export {
+        #[command(flatten)]
+        store: StoreArg,
+        /// File to write; standard output if not given.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Load a config file with `P2PLANE_*` overrides applied and validate it.
+    Validate {
+        path: PathBuf,
+        /// Print the resulting configuration.
+        #[arg(long)]
+        print: bool,
+    },
+}
+
+#[derive(Args)]
+struct AdminArgs {
+    /// Address of the admin API, e.g. http://127.0.0.1:9000.
+    #[arg(long)]
+    admin: String,
+    /// Bearer token of the admin API. Defaults to $P2PLANE_ADMIN_TOKEN.
+    #[arg(long)]
+    token: Option<String>,
+}
+
+impl AdminArgs {
+    fn client(self) -> Result<AdminClient> {
+        let token = self.token.or_else(|| std::env::var(ADMIN_TOKEN_ENV).ok());
+        Ok(AdminClient::new(&self.admin, token)?)
+    }
+}
+
+#[derive(Subcommand)]
+enum NodeCommand {
+    /// List the peers known to the node.
+    Peers {
+        #[command(flatten)]
+        admin: AdminArgs,
+        /// Print the peers as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Ask the node to dial an address or a known peer.
+    Dial {
+        #[command(flatten)]
+        admin: AdminArgs,
+        /// A multiaddr or a peer id.
+        target: String,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", ErrorChain(&*e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Command::Key(command) => key(command),
+        Command::Peers(command) => peers(command),
+        Command::Config(ConfigCommand::Validate { path, print }) => {
+            let config = NodeConfig::load(Some(&path))?;
+            if print {
+                print!("{}", config.to_toml()?);
+            } else {
+                println!("{}: ok", path.display());
+            }
+            Ok(())
+        }
+        Command::Node(command) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(node(command)),
+    }
+}
+
+fn key(command: KeyCommand) -> Result<()> {
+    match command {
+        KeyCommand::Generate { out, key_type } => out.write(&key_type.generate())?,
+        KeyCommand::Import {
+            out,
+            key_type,
+            file,
+        } => {
+            let passphrase = std::env::var(DEFAULT_PASSPHRASE_ENV).ok();
+            let keypair = identity::decode_key(&fs::read(&file)?, key_type, passphrase.as_deref())?;
+            out.write(&keypair)?;
+        }
+        KeyCommand::Inspect { path } => {
+            let data = fs::read(&path)?;
+            // Keystores are described without decrypting them.
+            if let Ok(keystore) = Keystore::from_json(&String::from_utf8_lossy(&data)) {
+                println!("type:      {}", keystore.key_type());
+                println!("peer id:   {}", keystore.peer_id());
+                println!("encrypted: yes");
+                return Ok(());
+            }
+            let keypair = Keypair::from_protobuf_encoding(&data)?;
+            println!("type:      {:?}", keypair.key_type());
+            println!("peer id:   {}", keypair.public().to_peer_id());
+            println!("encrypted: no");
+        }
+        #[cfg(feature = "pnet")]
+        KeyCommand::SwarmKey { out, force } => {
+            check_overwrite(&out, force)?;
+            let key = swarm_key::generate();
+            swarm_key::save(&out, &key)?;
+            println!("{}", key.fingerprint());
+        }
+    }
+    Ok(())
+}
+
+fn peers(command: PeersCommand) -> Result<()> {
+    match command {
+        PeersCommand::List { store, json } => {
+            let peers = storage::open_path(&store.store)?.load()?;
+            if json {
+                println!("{}", to_json(&peers)?);
+                return Ok(());
+            }
+            for peer in peers {
+                let addrs: Vec<String> =
+                    peer.addresses.iter().map(|a| a.addr.to_string()).collect();
+                println!("{} {}", peer.peer_id, addrs.join(" "));
+            }
+        }
+        PeersCommand::Add {
+            store,
+            peer_id,
+            addrs,
+        } => {
+            let mut store = storage::open_path(&store.store)?;
+            let mut peer = store
+                .load()?
+                .into_iter()
+                .find(|peer| peer.peer_id == peer_id)
+                .unwrap_or_else(|| StoredPeer::new(peer_id));
+            for addr in addrs {
+                if !peer.addresses.iter().any(|record| record.addr == addr) {
+                    peer.addresses.push(AddressRecord::manual(addr));
+                }
+            }
+            store.upsert(&peer)?;
+            store.flush()?;
+        }
+        PeersCommand::Remove { store, peer_id } => {
+            let mut store = storage::open_path(&store.store)?;
+            if !store.load()?.iter().any(|peer| peer.peer_id == peer_id) {
+                return Err(format!("unknown peer {}", peer_id).into());
+            }
+            store.delete(&peer_id)?;
+            store.flush()?;
+        }
+        PeersCommand::Import { store, file } => {
+            let json = if file.as_os_str() == "-" {
+                let mut json = String::new();
+                io::stdin().read_to_string(&mut json)?;
+                json
+            } else {
+                fs::read_to_string(&file)?
+            };
+            let peers: Vec<StoredPeer> = serde_json::from_str(&json)?;
+            let mut store = storage::open_path(&store.store)?;
+            for peer in &peers {
+                store.upsert(peer)?;
+            }
+            store.flush()?;
+            eprintln!("imported {} peers", peers.len());
+        }
+        PeersCommand::Export { store, out } => {
+            let json = to_json(&storage::open_path(&store.store)?.load()?)?;
+            match out {
+                Some(out) => fs::write(out, json)?,
+                None => println!("{}", json),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn to_json(value: &impl serde::Serialize) -> Result<String> {
+    Ok(serde_json::to_string_pretty(value)?)
+}
+
+async fn node(command: NodeCommand) -> Result<()> {
+    match command {
+        NodeCommand::Peers { admin, json } => {
+            let peers = admin.client()?.peers().await?;
+            if json {
+                println!("{}", to_json(&peers)?);
+                return Ok(());
+            }
+            for peer in peers {
+                let state = if peer.connected { "connected" } else { "known" };
+                let rtt = peer
+                    .rtt_ms
+                    .map(|rtt| format!("{:.1}ms", rtt))
+                    .unwrap_or_else(|| "-".to_string());
+                let addrs: Vec<String> = peer.addresses.iter().map(ToString::to_string).collect();
+                println!("{} {} {} {}", peer.peer_id, state, rtt, addrs.join(" "));
+            }
+        }
+        NodeCommand::Dial { admin, target } => {
+            let request = match target.parse::<PeerId>() {
+                Ok(peer_id) => DialRequest {
+                    peer_id: Some(peer_id),
+                    ..Default::default()
+                },
+                Err(_) => DialRequest {
+                    addr: Some(target.parse().map_err(|e| {
+                        format!("{} is neither a peer id nor a multiaddr: {}", target, e)
+                    })?),
+                    ..Default::default()
+                },
+            };
+            admin.client()?.dial(&request).await?;
+            println!("dialing {}", target);
+        }
+    }
+    Ok(())
+}
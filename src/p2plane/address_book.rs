@@ -0,0 +1,290 @@
+//! Known addresses of remote peers.
+//!
+//! A peer can be reachable over several interfaces and transports, so the
+//! [`AddressBook`] keeps every address learned for it together with where it
+//! came from and how well it has worked. Addresses learned from the network
+//! expire unless they are refreshed or dialed successfully, addresses that
+//! keep failing are dropped, and [`AddressBook::dial_candidates`] returns the
+//! rest best first.
+
+use crate::p2plane::peer_manager::is_relayed;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+/// Addresses kept per peer. When full, the lowest ranked address learned
+/// from the network makes room for the new one.
+pub const MAX_ADDRESSES_PER_PEER: usize = 16;
+
+/// Consecutive failed dials after which an address is forgotten. Manual
+/// addresses are never forgotten.
+pub const MAX_ADDRESS_FAILURES: u32 = 5;
+
+/// How an address was learned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressSource {
+    /// The address a connection to the peer was dialed on.
+    Dialed,
+    /// A listen address the peer announced over Identify.
+    Identify,
+    /// An address from the Kademlia routing table.
+    Kademlia,
+    /// An address discovered with mDNS on the local network.
+    Mdns,
+    /// Added by the application. Manual addresses never expire.
+    Manual,
+}
+
+impl AddressSource {
+    /// How long an address from this source is kept without being refreshed
+    /// or dialed successfully. `None` means forever.
+    pub fn ttl(self) -> Option<Duration> {
+        match self {
+            AddressSource::Manual => None,
+            AddressSource::Dialed => Some(Duration::from_secs(24 * 60 * 60)),
+            AddressSource::Identify | AddressSource::Kademlia => Some(Duration::from_secs(60 * 60)),
+            AddressSource::Mdns => Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
+
+/// One address of a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressRecord {
+    pub addr: Multiaddr,
+    /// Where the address was first learned.
+    pub source: AddressSource,
+    /// When a connection was last established on this address.
+    #[serde(default, with = "humantime_serde")]
+    pub last_success: Option<SystemTime>,
+    /// Failed dials since the last success.
+    #[serde(default)]
+    pub failures: u32,
+    /// When the address is dropped unless refreshed. `None` never expires.
+    #[serde(default, with = "humantime_serde")]
+    pub expires_at: Option<SystemTime>,
+}
+
+impl AddressRecord {
+    pub(crate) fn new(addr: Multiaddr, source: AddressSource, now: SystemTime) -> Self {
+        Self {
+            addr,
+            source,
+            last_success: None,
+            failures: 0,
+            expires_at: source.ttl().map(|ttl| now + ttl),
+        }
+    }
+
+    /// An address added by hand, which never expires.
+    pub fn manual(addr: Multiaddr) -> Self {
+        Self::new(addr, AddressSource::Manual, SystemTime::now())
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Direct addresses first, then the most recently successful ones, then
+    /// the ones that failed least.
+    fn rank(&self) -> impl Ord {
+        (
+            is_relayed(&self.addr),
+            Reverse(self.last_success),
+            self.failures,
+        )
+    }
+
+    fn extend_expiry(&mut self, ttl: Option<Duration>, now: SystemTime) {
+        self.expires_at = match (self.expires_at, ttl) {
+            (Some(current), Some(ttl)) => Some(current.max(now + ttl)),
+            _ => None,
+        };
+    }
+}
+
+/// Addresses of every known peer, each list kept in rank order.
+#[derive(Debug, Default, Clone)]
+pub struct AddressBook {
+    peers: HashMap<PeerId, Vec<AddressRecord>>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `addr` for `peer`. A known address keeps its original source
+    /// but has its expiry extended; adding it manually pins it.
+    ///
+    /// Returns true if the address was not known before.
+    pub fn add(&mut self, peer: PeerId, addr: Multiaddr, source: AddressSource) -> bool {
+        let now = SystemTime::now();
+        let addr = without_peer_id(addr, &peer);
+        let records = self.peers.entry(peer).or_default();
+        if let Some(record) = records.iter_mut().find(|r| r.addr == addr) {
+            if source == AddressSource::Manual {
+                record.source = source;
+            }
+            record.extend_expiry(source.ttl(), now);
+            return false;
+        }
+
+        if records.len() >= MAX_ADDRESSES_PER_PEER {
+            let evict = records
+                .iter()
+                .rposition(|r| r.source != AddressSource::Manual);
+            match evict {
+                Some(index) => {
+                    records.remove(index);
+                }
+                None => return false,
+            }
+        }
+        records.push(AddressRecord::new(addr, source, now));
+        sort(records);
+        true
+    }
+
+    /// Records a connection established on `addr`, which moves it to the
+    /// front of the peer's candidates.
+    pub fn record_success(&mut self, peer: &PeerId, addr: &Multiaddr) {
+        let now = SystemTime::now();
+        if let Some(record) = self.find_mut(peer, addr) {
+            record.last_success = Some(now);
+            record.failures = 0;
+            record.extend_expiry(AddressSource::Dialed.ttl(), now);
+        }
+        if let Some(records) = self.peers.get_mut(peer) {
+            sort(records);
+        }
+    }
+
+    /// Records a failed dial of `addr`, forgetting the address once it has
+    /// failed [`MAX_ADDRESS_FAILURES`] times in a row.
+    pub fn record_failure(&mut self, peer: &PeerId, addr: &Multiaddr) {
+        let Some(record) = self.find_mut(peer, addr) else {
+            return;
+        };
+        record.failures += 1;
+        let forget =
+            record.failures >= MAX_ADDRESS_FAILURES && record.source != AddressSource::Manual;
+        let addr = record.addr.clone();
+        if let Some(records) = self.peers.get_mut(peer) {
+            if forget {
+                records.retain(|r| r.addr != addr);
+            }
+            sort(records);
+        }
+    }
+
+    /// Forgets `addr`. Returns true if it was known.
+    pub fn remove(&mut self, peer: &PeerId, addr: &Multiaddr) -> bool {
+        let addr = without_peer_id(addr.clone(), peer);
+        let Some(records) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        let len = records.len();
+        records.retain(|r| r.addr != addr);
+        len != records.len()
+    }
+
+    /// Forgets every address of `peer`.
+    pub fn remove_peer(&mut self, peer: &PeerId) -> Vec<AddressRecord> {
+        self.peers.remove(peer).unwrap_or_default()
+    }
+
+    /// Drops addresses that expired before `now` and returns how many there
+    /// were.
+    pub fn prune_expired(&mut self, now: SystemTime) -> usize {
+        let mut pruned = 0;
+        for records in self.peers.values_mut() {
+            let len = records.len();
+            records.retain(|r| !r.is_expired(now));
+            pruned += len - records.len();
+        }
+        self.peers.retain(|_, records| !records.is_empty());
+        pruned
+    }
+
+    /// All addresses recorded for `peer`, best first, including expired ones
+    /// that have not been pruned yet.
+    pub fn records(&self, peer: &PeerId) -> &[AddressRecord] {
+        self.peers.get(peer).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The unexpired addresses of `peer` in the order they should be dialed.
+    pub fn dial_candidates(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        let now = SystemTime::now();
+        self.records(peer)
+            .iter()
+            .filter(|r| !r.is_expired(now))
+            .map(|r| r.addr.clone())
+            .collect()
+    }
+
+    /// The best unexpired address of `peer`.
+    pub fn best(&self, peer: &PeerId) -> Option<&Multiaddr> {
+        let now = SystemTime::now();
+        self.records(peer)
+            .iter()
+            .find(|r| !r.is_expired(now))
+            .map(|r| &r.addr)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    /// Every peer with its records, e.g. for persisting the book.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &[AddressRecord])> {
+        self.peers
+            .iter()
+            .map(|(peer, records)| (peer, records.as_slice()))
+    }
+
+    /// Restores records saved with [`AddressBook::iter`]. Records for the
+    /// same address are merged.
+    pub fn insert_records(
+        &mut self,
+        peer: PeerId,
+        records: impl IntoIterator<Item = AddressRecord>,
+    ) {
+        let existing = self.peers.entry(peer).or_default();
+        for record in records {
+            if !existing.iter().any(|r| r.addr == record.addr) {
+                existing.push(record);
+            }
+        }
+        sort(existing);
+        existing.truncate(MAX_ADDRESSES_PER_PEER);
+    }
+
+    fn find_mut(&mut self, peer: &PeerId, addr: &Multiaddr) -> Option<&mut AddressRecord> {
+        let addr = without_peer_id(addr.clone(), peer);
+        self.peers
+            .get_mut(peer)?
+            .iter_mut()
+            .find(|r| r.addr == addr)
+    }
+}
+
+fn sort(records: &mut [AddressRecord]) {
+    records.sort_by_key(|r| r.rank());
+}
+
+/// Strips a trailing `/p2p/<peer>` so the same address dialed with and
+/// without the peer ID is only stored once.
+fn without_peer_id(mut addr: Multiaddr, peer: &PeerId) -> Multiaddr {
+    if let Some(Protocol::P2p(id)) = addr.iter().last() {
+        if id == *peer {
+            addr.pop();
+        }
+    }
+    addr
+}
@@ -0,0 +1,378 @@
+//! HTTP API for inspecting and controlling a running node.
+//!
+//! The server is started by [`Node::start`](crate::p2plane::network::Node::start)
+//! when `admin.listen_addr` is set, or can be mounted into an application's
+//! own axum server with [`router`]. All bodies are JSON.
+//!
+//! ```text
+//! GET  /peer-id          local peer id
+//! GET  /listen-addrs     addresses the node listens on
+//! GET  /peers            known peers with their metadata
+//! GET  /peers/connected  ids of connected peers
+//! GET  /routing-table    Kademlia routing table
+//! GET  /bans             banned peers
+//! POST /dial             {"addr": "/ip4/..."} or {"peer_id": "12D3..."}
+//! POST /disconnect       {"peer_id": "12D3..."}
+//! POST /ban              {"peer_id": "12D3..."}
+//! POST /unban            {"peer_id": "12D3..."}
+//! ```
+//!
+//! With `admin.token` set, every request needs an
+//! `Authorization: Bearer <token>` header.
+//!
+//! [`AdminClient`] talks to the API from another process, such as the
+//! `p2plane` command-line tool.
+
+use crate::p2plane::{
+    error::{ErrorChain, P2PlaneError},
+    handle::NodeHandle,
+    traits::Message,
+    PeerInfo, PeerMetadata, Result, RoutingEntry,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use libp2p::{Multiaddr, PeerId};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Environment variable the `p2plane` tool reads the API token from when
+/// it is not given on the command line.
+pub const ADMIN_TOKEN_ENV: &str = "P2PLANE_ADMIN_TOKEN";
+
+/// Settings of the admin HTTP server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address to serve the API on. The server is disabled if unset.
+    pub listen_addr: Option<SocketAddr>,
+    /// Bearer token required on every request. Anyone who can reach
+    /// `listen_addr` can control the node if unset.
+    pub token: Option<String>,
+}
+
+/// Known peer as reported by `GET /peers`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminPeer {
+    pub peer_id: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    pub connected: bool,
+    pub rtt_ms: Option<f64>,
+    pub missed_pings: u32,
+    pub metadata: Option<PeerMetadata>,
+}
+
+impl AdminPeer {
+    fn new(info: PeerInfo, connected: bool) -> Self {
+        Self {
+            peer_id: info.peer_id,
+            addresses: info.addresses,
+            connected,
+            rtt_ms: info.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            missed_pings: info.missed_pings,
+            metadata: info.metadata,
+        }
+    }
+}
+
+/// Body of `POST /dial`. Exactly one of the fields must be set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DialRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr: Option<Multiaddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<PeerId>,
+}
+
+/// Body of the requests that act on a single peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerRequest {
+    pub peer_id: PeerId,
+}
+
+/// Error response, with the message in an `error` field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+struct ApiError(StatusCode, String);
+
+impl From<P2PlaneError> for ApiError {
+    fn from(error: P2PlaneError) -> Self {
+        let status = match error {
+            P2PlaneError::NodeStopped => StatusCode::SERVICE_UNAVAILABLE,
+            P2PlaneError::Dial { .. } => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, ErrorChain(&error).to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorBody { error: self.1 })).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+/// Routes of the admin API for the node behind `handle`.
+pub fn router<M: Message>(handle: NodeHandle<M>, token: Option<String>) -> Router {
+    let router = Router::new()
+        .route("/peer-id", get(peer_id::<M>))
+        .route("/listen-addrs", get(listen_addrs::<M>))
+        .route("/peers", get(peers::<M>))
+        .route("/peers/connected", get(connected_peers::<M>))
+        .route("/routing-table", get(routing_table::<M>))
+        .route("/bans", get(bans::<M>))
+        .route("/dial", post(dial::<M>))
+        .route("/disconnect", post(disconnect::<M>))
+        .route("/ban", post(ban::<M>))
+        .route("/unban", post(unban::<M>))
+        .with_state(handle);
+    match token {
+        Some(token) => router.layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_token,
+        )),
+        None => router,
+    }
+}
+
+/// Serves the admin API on `listener` until the server fails.
+pub async fn serve<M: Message>(
+    listener: TcpListener,
+    handle: NodeHandle<M>,
+    token: Option<String>,
+) -> Result<()> {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Admin API listening on http://{}", addr);
+    }
+    axum::serve(listener, router(handle, token))
+        .await
+        .map_err(P2PlaneError::transport)
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+    if authorized {
+        next.run(request).await
+    } else {
+        ApiError(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid bearer token".to_string(),
+        )
+        .into_response()
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct PeerIdBody {
+    peer_id: PeerId,
+}
+
+async fn peer_id<M: Message>(State(handle): State<NodeHandle<M>>) -> Json<PeerIdBody> {
+    Json(PeerIdBody {
+        peer_id: handle.local_peer_id(),
+    })
+}
+
+async fn listen_addrs<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+) -> ApiResult<Vec<Multiaddr>> {
+    Ok(Json(handle.listen_addrs().await?))
+}
+
+async fn peers<M: Message>(State(handle): State<NodeHandle<M>>) -> ApiResult<Vec<AdminPeer>> {
+    let connected: HashSet<PeerId> = handle.connected_peers().await?.into_iter().collect();
+    let peers = handle
+        .known_peers()
+        .await?
+        .into_iter()
+        .map(|info| {
+            let is_connected = connected.contains(&info.peer_id);
+            AdminPeer::new(info, is_connected)
+        })
+        .collect();
+    Ok(Json(peers))
+}
+
+async fn connected_peers<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+) -> ApiResult<Vec<PeerId>> {
+    Ok(Json(handle.connected_peers().await?))
+}
+
+async fn routing_table<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+) -> ApiResult<Vec<RoutingEntry>> {
+    Ok(Json(handle.routing_table().await?))
+}
+
+async fn bans<M: Message>(State(handle): State<NodeHandle<M>>) -> ApiResult<Vec<PeerId>> {
+    Ok(Json(handle.banned_peers().await?))
+}
+
+async fn dial<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+    Json(request): Json<DialRequest>,
+) -> std::result::Result<StatusCode, ApiError> {
+    match (request.addr, request.peer_id) {
+        (Some(addr), None) => handle.dial(addr).await?,
+        (None, Some(peer)) => handle.dial_peer(peer).await?,
+        _ => {
+            return Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                "exactly one of addr and peer_id must be given".to_string(),
+            ))
+        }
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Serialize)]
+struct DisconnectBody {
+    disconnected: bool,
+}
+
+async fn disconnect<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+    Json(request): Json<PeerRequest>,
+) -> ApiResult<DisconnectBody> {
+    let disconnected = handle.disconnect(request.peer_id).await?;
+    Ok(Json(DisconnectBody { disconnected }))
+}
+
+async fn ban<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+    Json(request): Json<PeerRequest>,
+) -> std::result::Result<StatusCode, ApiError> {
+    handle.ban_peer(request.peer_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unban<M: Message>(
+    State(handle): State<NodeHandle<M>>,
+    Json(request): Json<PeerRequest>,
+) -> std::result::Result<StatusCode, ApiError> {
+    handle.unban_peer(request.peer_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Client for the admin API of a node. Speaks plain HTTP/1.1 and opens a
+/// new connection per request, which is plenty for operational tooling.
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    addr: String,
+    token: Option<String>,
+}
+
+impl AdminClient {
+    /// `url` is `http://<host>:<port>`; the scheme may be left out.
+    pub fn new(url: &str, token: Option<String>) -> Result<Self> {
+        let addr = url
+            .strip_prefix("http://")
+            .unwrap_or(url)
+            .trim_end_matches('/');
+        if addr.is_empty() || addr.contains("://") || addr.contains('/') {
+            return Err(P2PlaneError::config(format!(
+                "invalid admin API address {}, expected http://<host>:<port>",
+                url
+            )));
+        }
+        Ok(Self {
+            addr: addr.to_string(),
+            token,
+        })
+    }
+
+    pub async fn peer_id(&self) -> Result<PeerId> {
+        #[derive(Deserialize)]
+        struct Body {
+            peer_id: PeerId,
+        }
+        let body: Body = self.get("/peer-id").await?;
+        Ok(body.peer_id)
+    }
+
+    pub async fn peers(&self) -> Result<Vec<AdminPeer>> {
+        self.get("/peers").await
+    }
+
+    pub async fn dial(&self, request: &DialRequest) -> Result<()> {
+        let body = serde_json::to_string(request).map_err(P2PlaneError::transport)?;
+        self.request("POST", "/dial", &body).await.map(drop)
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<T> {
+        let body = self.request("GET", path, "").await?;
+        serde_json::from_str(&body).map_err(|e| P2PlaneError::Codec(Box::new(e)))
+    }
+
+    /// Sends a request and returns the body of a successful response.
+    async fn request(&self, method: &str, path: &str, body: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(P2PlaneError::transport)?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            self.addr,
+            body.len()
+        );
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(P2PlaneError::transport)?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .await
+            .map_err(P2PlaneError::transport)?;
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status: u16 = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| P2PlaneError::transport("malformed HTTP response"))?;
+        if (200..300).contains(&status) {
+            return Ok(body.to_string());
+        }
+        let message = serde_json::from_str::<ErrorBody>(body)
+            .map(|body| body.error)
+            .unwrap_or_else(|_| body.to_string());
+        Err(P2PlaneError::transport(format!(
+            "admin API returned {}: {}",
+            status, message
+        )))
+    }
+}
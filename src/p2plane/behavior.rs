@@ -1,10 +1,20 @@
 use libp2p::{
+    allow_block_list::{self, BlockedPeers},
+    core::Endpoint,
     Multiaddr, PeerId,
-    swarm::NetworkBehaviour,
+    StreamProtocol,
+    mdns,
+    ping,
+    relay,
+    swarm::{
+        behaviour::toggle::Toggle, dummy, ConnectionDenied, ConnectionId, FromSwarm,
+        NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
     request_response::{
         cbor::Behaviour as RequestResponse,
+        Config as RequestResponseConfig,
         Event as RequestResponseEvent,
-        ResponseChannel,
+        ProtocolSupport,
     },
     kad::{
         store::MemoryStore,
@@ -16,41 +26,140 @@ use libp2p::{
         Event as IdentifyEvent,
     },
 };
-use crate::p2plane::traits::Message;
+use crate::p2plane::{
+    content::{ContentRequest, ContentResponse},
+    keep_alive::KeepAlive,
+    limits::AdmissionControl,
+    stream::Streams,
+    traits::Message,
+};
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+/// Protocol used to fetch content from the providers found through Kademlia.
+pub const CONTENT_PROTOCOL: &str = "/p2plane/content/1.0.0";
 
 // Define Event enum before the Behavior struct
 #[derive(Debug)]
-pub enum Event<M> {
+pub enum Event<M, E = Infallible> {
     Kad(KadEvent),
     Identify(IdentifyEvent),
+    Mdns(mdns::Event),
     RequestResponse(RequestResponseEvent<M, M>),
+    /// Event of the protocol reserved for high-priority messages, see
+    /// [`Behavior::with_high_priority`].
+    HighPriority(RequestResponseEvent<M, M>),
+    Content(RequestResponseEvent<ContentRequest, ContentResponse>),
+    Ping(ping::Event),
+    RelayServer(relay::Event),
+    RelayClient(relay::client::Event),
+    /// Event from the application's own behaviour added with
+    /// [`Behavior::with_extension`].
+    Extension(E),
 }
 
 // Implement From traits for each event type
-impl<M> From<KadEvent> for Event<M> {
+impl<M, E> From<KadEvent> for Event<M, E> {
     fn from(event: KadEvent) -> Self {
         Event::Kad(event)
     }
 }
 
-impl<M> From<IdentifyEvent> for Event<M> {
+impl<M, E> From<IdentifyEvent> for Event<M, E> {
     fn from(event: IdentifyEvent) -> Self {
         Event::Identify(event)
     }
 }
 
-impl<M> From<RequestResponseEvent<M, M>> for Event<M> {
+impl<M, E> From<RequestResponseEvent<M, M>> for Event<M, E> {
     fn from(event: RequestResponseEvent<M, M>) -> Self {
         Event::RequestResponse(event)
     }
 }
 
+impl<M, E> From<RequestResponseEvent<ContentRequest, ContentResponse>> for Event<M, E> {
+    fn from(event: RequestResponseEvent<ContentRequest, ContentResponse>) -> Self {
+        Event::Content(event)
+    }
+}
+
+impl<M, E> From<mdns::Event> for Event<M, E> {
+    fn from(event: mdns::Event) -> Self {
+        Event::Mdns(event)
+    }
+}
+
+impl<M, E> From<ping::Event> for Event<M, E> {
+    fn from(event: ping::Event) -> Self {
+        Event::Ping(event)
+    }
+}
+
+impl<M, E> From<relay::Event> for Event<M, E> {
+    fn from(event: relay::Event) -> Self {
+        Event::RelayServer(event)
+    }
+}
+
+impl<M, E> From<relay::client::Event> for Event<M, E> {
+    fn from(event: relay::client::Event) -> Self {
+        Event::RelayClient(event)
+    }
+}
+
+impl<M, E> From<Infallible> for Event<M, E> {
+    fn from(event: Infallible) -> Self {
+        match event {}
+    }
+}
+
+impl<M, E> From<void::Void> for Event<M, E> {
+    fn from(event: void::Void) -> Self {
+        void::unreachable(event)
+    }
+}
+
+impl<M, E> From<HighPriorityEvent<M>> for Event<M, E> {
+    fn from(event: HighPriorityEvent<M>) -> Self {
+        Event::HighPriority(event.0)
+    }
+}
+
+impl<M, E> From<ExtensionEvent<E>> for Event<M, E> {
+    fn from(event: ExtensionEvent<E>) -> Self {
+        Event::Extension(event.0)
+    }
+}
+
+/// The node's behaviour. `X` is an optional application-defined behaviour,
+/// see [`Behavior::with_extension`].
+pub type Behavior<M, X = dummy::Behaviour> = NodeBehavior<M, X>;
+
 #[derive(NetworkBehaviour)]
-#[behaviour(out_event = "Event<M>")]
-pub struct Behavior<M: Message> {
-    pub kad: Kademlia<MemoryStore>,
-    pub identify: Identify,
+#[behaviour(out_event = "Event<M, X::ToSwarm>")]
+pub struct NodeBehavior<M: Message, X: NetworkBehaviour> {
+    // Listed first so that connections from banned peers or over the
+    // limits are refused before any other behaviour sets up a handler for
+    // them.
+    /// Banned peers, whose connections are refused.
+    pub blocked: allow_block_list::Behaviour<BlockedPeers>,
+    pub limits: AdmissionControl,
+    /// Keeps selected connections open however long they are idle.
+    pub keep_alive: KeepAlive,
+    pub kad: Toggle<Kademlia<MemoryStore>>,
+    pub identify: Toggle<Identify>,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     pub request_response: RequestResponse<M, M>,
+    pub high_priority: HighPriority<M>,
+    pub content: RequestResponse<ContentRequest, ContentResponse>,
+    /// Raw streams, see [`StreamControl`](crate::p2plane::stream::StreamControl).
+    pub streams: Streams,
+    pub ping: ping::Behaviour,
+    pub relay_server: Toggle<relay::Behaviour>,
+    pub relay_client: Toggle<relay::client::Behaviour>,
+    pub extension: Extension<X>,
 }
 
 impl<M: Message> Behavior<M> {
@@ -59,10 +168,282 @@ impl<M: Message> Behavior<M> {
         identify: Identify,
         request_response: RequestResponse<M, M>,
     ) -> Self {
+        Self::from_parts(Some(kad), Some(identify), request_response)
+    }
+
+    /// Like [`new`](Self::new), but with Kademlia and Identify optional.
+    pub fn from_parts(
+        kad: Option<Kademlia<MemoryStore>>,
+        identify: Option<Identify>,
+        request_response: RequestResponse<M, M>,
+    ) -> Self {
+        let content = RequestResponse::new(
+            [(StreamProtocol::new(CONTENT_PROTOCOL), ProtocolSupport::Full)],
+            RequestResponseConfig::default(),
+        );
+
         Self {
-            kad,
-            identify,
+            blocked: allow_block_list::Behaviour::default(),
+            limits: AdmissionControl::default(),
+            keep_alive: KeepAlive::default(),
+            kad: Toggle::from(kad),
+            identify: Toggle::from(identify),
+            mdns: Toggle::from(None),
             request_response,
+            high_priority: HighPriority(Toggle::from(None)),
+            content,
+            streams: Streams::new(),
+            ping: ping::Behaviour::default(),
+            relay_server: Toggle::from(None),
+            relay_client: Toggle::from(None),
+            extension: Extension(dummy::Behaviour),
         }
     }
 }
+
+impl<M: Message, X: NetworkBehaviour> Behavior<M, X> {
+    /// Adds an application-defined behaviour. Its events are reported as
+    /// [`Event::Extension`].
+    pub fn with_extension<Y: NetworkBehaviour>(self, extension: Y) -> Behavior<M, Y> {
+        NodeBehavior {
+            blocked: self.blocked,
+            limits: self.limits,
+            keep_alive: self.keep_alive,
+            kad: self.kad,
+            identify: self.identify,
+            mdns: self.mdns,
+            request_response: self.request_response,
+            high_priority: self.high_priority,
+            content: self.content,
+            streams: self.streams,
+            ping: self.ping,
+            relay_server: self.relay_server,
+            relay_client: self.relay_client,
+            extension: Extension(extension),
+        }
+    }
+
+    /// Enforces connection limits. Without this the node accepts any number
+    /// of connections.
+    pub fn with_limits(mut self, limits: AdmissionControl) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Keeps the connections to the peers of a
+    /// [`KeepAlivePolicy`](crate::p2plane::keep_alive::KeepAlivePolicy) open.
+    /// Without this every connection closes once it is idle.
+    pub fn with_keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Replaces the default ping behaviour, e.g. to change the ping interval.
+    pub fn with_ping(mut self, ping: ping::Behaviour) -> Self {
+        self.ping = ping;
+        self
+    }
+
+    /// Discovers peers on the local network.
+    pub fn with_mdns(mut self, mdns: mdns::tokio::Behaviour) -> Self {
+        self.mdns = Toggle::from(Some(mdns));
+        self
+    }
+
+    /// Sends and receives high-priority messages over a protocol of their
+    /// own, so they do not share streams with bulk traffic. Its events are
+    /// reported as [`Event::HighPriority`].
+    pub fn with_high_priority(mut self, high_priority: RequestResponse<M, M>) -> Self {
+        self.high_priority = HighPriority(Toggle::from(Some(high_priority)));
+        self
+    }
+
+    /// Replaces the behaviour serving content requests, e.g. to change its
+    /// timeouts.
+    pub fn with_content(mut self, content: RequestResponse<ContentRequest, ContentResponse>) -> Self {
+        self.content = content;
+        self
+    }
+
+    /// Lets this node relay traffic for peers that cannot accept inbound
+    /// connections.
+    pub fn with_relay_server(mut self, relay_server: relay::Behaviour) -> Self {
+        self.relay_server = Toggle::from(Some(relay_server));
+        self
+    }
+
+    /// Lets this node listen on and dial `/p2p-circuit` addresses. The client
+    /// must be the one returned alongside the relay transport.
+    pub fn with_relay_client(mut self, relay_client: relay::client::Behaviour) -> Self {
+        self.relay_client = Toggle::from(Some(relay_client));
+        self
+    }
+}
+
+/// Event emitted by [`HighPriority`].
+#[derive(Debug)]
+pub struct HighPriorityEvent<M>(pub RequestResponseEvent<M, M>);
+
+/// Optional request-response behaviour for high-priority messages. Its
+/// events are wrapped in [`HighPriorityEvent`] to tell them apart from
+/// those of the shared message protocol.
+pub struct HighPriority<M: Message>(pub Toggle<RequestResponse<M, M>>);
+
+impl<M: Message> HighPriority<M> {
+    /// The behaviour, if the protocol is enabled.
+    pub fn as_mut(&mut self) -> Option<&mut RequestResponse<M, M>> {
+        self.0.as_mut()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+}
+
+impl<M: Message> NetworkBehaviour for HighPriority<M> {
+    type ConnectionHandler = THandler<Toggle<RequestResponse<M, M>>>;
+    type ToSwarm = HighPriorityEvent<M>;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.0
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.0
+            .handle_established_inbound_connection(connection_id, peer, local_addr, remote_addr)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.0.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.0
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        self.0.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.0.on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.0.poll(cx).map(|event| event.map_out(HighPriorityEvent))
+    }
+}
+
+/// Event emitted by an [`Extension`].
+#[derive(Debug)]
+pub struct ExtensionEvent<E>(pub E);
+
+/// Application-defined behaviour running next to the built-in ones. Events
+/// are wrapped in [`ExtensionEvent`] so they can be told apart from the
+/// built-in ones even if the types coincide.
+#[derive(Debug)]
+pub struct Extension<X>(pub X);
+
+impl<X: NetworkBehaviour> NetworkBehaviour for Extension<X> {
+    type ConnectionHandler = X::ConnectionHandler;
+    type ToSwarm = ExtensionEvent<X::ToSwarm>;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.0
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.0
+            .handle_established_inbound_connection(connection_id, peer, local_addr, remote_addr)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.0.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.0
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        self.0.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.0.on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.0.poll(cx).map(|event| event.map_out(ExtensionEvent))
+    }
+}
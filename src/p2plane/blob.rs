@@ -0,0 +1,754 @@
+//! Chunked transfer of large blobs over [streams](crate::p2plane::stream).
+//!
+//! A blob is split into chunks of [`CHUNK_SIZE`] bytes. Its [`Manifest`]
+//! lists the SHA-256 of every chunk and the blob's [`BlobId`] is the SHA-256
+//! of the manifest, so a receiver checks the manifest against the id it
+//! asked for and every chunk against the manifest as it arrives. Chunks that
+//! arrived before a transfer was interrupted are kept, and the next attempt
+//! only transfers the missing ones.
+//!
+//! Blobs pushed by peers are refused unless the store was told to accept
+//! them with [`BlobStore::accept_puts`]. A [`BlobConfig`] bounds the bytes a
+//! store holds, how long unfinished blobs are kept and how many transfers
+//! peers may run at once.
+
+use crate::p2plane::{hex, stream::StreamControl, ErrorChain, P2PlaneError, Result};
+use libp2p::{
+    futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    PeerId, StreamProtocol,
+};
+use log::debug;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Protocol blobs are pushed and fetched over.
+pub const BLOB_PROTOCOL: &str = "/p2plane/blob/1.0.0";
+
+/// Size of the chunks blobs are split into.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest chunk size accepted in a manifest from a peer.
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Largest blob accepted from a peer.
+pub const MAX_BLOB_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Largest control message; a manifest of a blob of [`MAX_BLOB_SIZE`] fits.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Attempts made by `send_blob` and `fetch_blob` before giving up.
+const ATTEMPTS: usize = 3;
+
+type Sha256Hash = [u8; 32];
+
+/// Decides whether a blob pushed by a peer is accepted.
+type PutFilter = dyn Fn(&PeerId, &Manifest) -> bool + Send + Sync;
+
+fn sha256(data: &[u8]) -> Sha256Hash {
+    Sha256::digest(data).into()
+}
+
+fn parse_hash(s: &str) -> Option<Sha256Hash> {
+    hex::decode(s)?.try_into().ok()
+}
+
+/// Identifies a blob by the SHA-256 of its [`Manifest`]. Written as 64 hex
+/// digits.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobId(Sha256Hash);
+
+impl BlobId {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlobId({})", self)
+    }
+}
+
+impl FromStr for BlobId {
+    type Err = P2PlaneError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_hash(s)
+            .map(BlobId)
+            .ok_or_else(|| P2PlaneError::Codec(format!("invalid blob id {:?}", s).into()))
+    }
+}
+
+impl Serialize for BlobId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlobId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Chunk hashes written as hex strings, which keeps manifests compact.
+mod hex_hashes {
+    use super::{hex, parse_hash, Sha256Hash};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        hashes: &[Sha256Hash],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(hashes.iter().map(|hash| hex::encode(hash)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Sha256Hash>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| parse_hash(s).ok_or_else(|| D::Error::custom(format!("invalid hash {:?}", s))))
+            .collect()
+    }
+}
+
+/// Size, chunk size and chunk hashes of a blob.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunk_size: u32,
+    #[serde(with = "hex_hashes")]
+    pub chunks: Vec<[u8; 32]>,
+}
+
+impl Manifest {
+    pub fn new(data: &[u8], chunk_size: usize) -> Self {
+        Self {
+            size: data.len() as u64,
+            chunk_size: chunk_size as u32,
+            chunks: data.chunks(chunk_size).map(sha256).collect(),
+        }
+    }
+
+    pub fn id(&self) -> BlobId {
+        let mut hasher = Sha256::new();
+        hasher.update(self.size.to_be_bytes());
+        hasher.update(self.chunk_size.to_be_bytes());
+        for chunk in &self.chunks {
+            hasher.update(chunk);
+        }
+        BlobId(hasher.finalize().into())
+    }
+
+    /// Length of chunk `index`; only the last chunk may be short.
+    fn chunk_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.chunk_size as u64;
+        (self.size - start).min(self.chunk_size as u64) as usize
+    }
+
+    /// Whether `data` is chunk `index` of the blob.
+    pub fn verify_chunk(&self, index: usize, data: &[u8]) -> bool {
+        index < self.chunks.len()
+            && data.len() == self.chunk_len(index)
+            && sha256(data) == self.chunks[index]
+    }
+
+    /// Checks a manifest received from a peer.
+    fn validate(&self) -> Result<()> {
+        if self.chunk_size == 0 || self.chunk_size > MAX_CHUNK_SIZE {
+            return Err(P2PlaneError::stream(format!(
+                "chunk size {} is not between 1 and {}",
+                self.chunk_size, MAX_CHUNK_SIZE
+            )));
+        }
+        if self.size > MAX_BLOB_SIZE {
+            return Err(P2PlaneError::stream(format!(
+                "blob of {} bytes exceeds the limit of {}",
+                self.size, MAX_BLOB_SIZE
+            )));
+        }
+        if self.chunks.len() as u64 != self.size.div_ceil(self.chunk_size as u64) {
+            return Err(P2PlaneError::stream(format!(
+                "{} chunk hashes do not match a size of {} bytes",
+                self.chunks.len(),
+                self.size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the blobs a node holds and serves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobConfig {
+    /// Bytes held by complete blobs and reserved by unfinished ones.
+    /// Blobs stored locally always fit; transfers from peers that would
+    /// exceed it are refused.
+    pub max_bytes: u64,
+    /// How long an unfinished blob is kept after its last chunk arrived.
+    #[serde(with = "humantime_serde")]
+    pub partial_timeout: Duration,
+    /// Blob streams opened by peers that are served at once. Further
+    /// streams are dropped.
+    pub max_transfers: usize,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 2 * MAX_BLOB_SIZE,
+            partial_timeout: Duration::from_secs(10 * 60),
+            max_transfers: 16,
+        }
+    }
+}
+
+/// A complete blob.
+#[derive(Debug)]
+pub(crate) struct Blob {
+    manifest: Manifest,
+    data: Vec<u8>,
+}
+
+impl Blob {
+    fn chunk(&self, index: u32) -> Option<&[u8]> {
+        let index = index as usize;
+        if index >= self.manifest.chunks.len() {
+            return None;
+        }
+        let start = index * self.manifest.chunk_size as usize;
+        Some(&self.data[start..start + self.manifest.chunk_len(index)])
+    }
+}
+
+/// A blob whose chunks are still arriving.
+#[derive(Debug)]
+struct Partial {
+    manifest: Manifest,
+    chunks: Vec<Option<Vec<u8>>>,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Blobs {
+    config: BlobConfig,
+    complete: HashMap<BlobId, Arc<Blob>>,
+    partial: HashMap<BlobId, Partial>,
+    accept: Option<Arc<PutFilter>>,
+}
+
+impl Blobs {
+    /// Bytes of complete blobs plus the full size of unfinished ones.
+    fn used_bytes(&self) -> u64 {
+        let complete: u64 = self.complete.values().map(|blob| blob.data.len() as u64).sum();
+        let partial: u64 = self.partial.values().map(|partial| partial.manifest.size).sum();
+        complete + partial
+    }
+
+    /// Drops unfinished blobs that received no chunk for `partial_timeout`.
+    fn evict_stale(&mut self) {
+        let timeout = self.config.partial_timeout;
+        self.partial.retain(|id, partial| {
+            let fresh = partial.updated.elapsed() < timeout;
+            if !fresh {
+                debug!("Dropping unfinished blob {}", id);
+            }
+            fresh
+        });
+    }
+}
+
+impl fmt::Debug for Blobs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blobs")
+            .field("config", &self.config)
+            .field("complete", &self.complete.keys().collect::<Vec<_>>())
+            .field("partial", &self.partial.keys().collect::<Vec<_>>())
+            .field("accept", &self.accept.is_some())
+            .finish()
+    }
+}
+
+/// Blobs held by a node: the ones it sent or received, which it serves to
+/// peers, and the chunks of interrupted downloads. Cheap to clone; clones
+/// share their contents.
+#[derive(Debug, Clone, Default)]
+pub struct BlobStore {
+    inner: Arc<Mutex<Blobs>>,
+}
+
+impl BlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: BlobConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Blobs {
+                config,
+                ..Blobs::default()
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Blobs> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stores `data` so peers can fetch it, and returns its id.
+    pub fn insert(&self, data: Vec<u8>) -> BlobId {
+        let manifest = Manifest::new(&data, CHUNK_SIZE);
+        let id = manifest.id();
+        let mut blobs = self.lock();
+        blobs.partial.remove(&id);
+        blobs.complete.insert(id, Arc::new(Blob { manifest, data }));
+        id
+    }
+
+    /// The contents of a complete blob.
+    pub fn get(&self, id: &BlobId) -> Option<Vec<u8>> {
+        self.lock().complete.get(id).map(|blob| blob.data.clone())
+    }
+
+    pub fn contains(&self, id: &BlobId) -> bool {
+        self.lock().complete.contains_key(id)
+    }
+
+    /// Drops a blob, or the chunks received so far of an unfinished one.
+    pub fn remove(&self, id: &BlobId) -> bool {
+        let mut blobs = self.lock();
+        blobs.complete.remove(id).is_some() | blobs.partial.remove(id).is_some()
+    }
+
+    /// Chunks received and total chunks of an unfinished blob.
+    pub fn progress(&self, id: &BlobId) -> Option<(usize, usize)> {
+        self.lock().partial.get(id).map(|partial| {
+            let received = partial
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.is_some())
+                .count();
+            (received, partial.chunks.len())
+        })
+    }
+
+    /// Bytes held by complete blobs and reserved by unfinished ones, which
+    /// count with their full size.
+    pub fn used_bytes(&self) -> u64 {
+        self.lock().used_bytes()
+    }
+
+    /// Accepts blobs pushed by peers for which `filter` returns true,
+    /// replacing any earlier filter. Without one, pushed blobs are refused.
+    pub fn accept_puts(&self, filter: impl Fn(&PeerId, &Manifest) -> bool + Send + Sync + 'static) {
+        self.lock().accept = Some(Arc::new(filter));
+    }
+
+    /// Whether a blob with `manifest` pushed by `peer` is accepted.
+    pub(crate) fn accepts(&self, peer: &PeerId, manifest: &Manifest) -> bool {
+        // The filter is called without the lock, so it may use the store.
+        let filter = self.lock().accept.clone();
+        filter.is_some_and(|filter| filter(peer, manifest))
+    }
+
+    pub(crate) fn blob(&self, id: &BlobId) -> Option<Arc<Blob>> {
+        self.lock().complete.get(id).cloned()
+    }
+
+    /// Indices of the chunks of `manifest` still to be received, starting
+    /// a download unless one is under way. Empty once the blob is complete.
+    /// Fails if a new download does not fit within `max_bytes`; unfinished
+    /// blobs that timed out are dropped first.
+    pub(crate) fn missing(&self, manifest: &Manifest) -> Result<Vec<u32>> {
+        let id = manifest.id();
+        let mut blobs = self.lock();
+        if blobs.complete.contains_key(&id) {
+            return Ok(Vec::new());
+        }
+        blobs.evict_stale();
+        if !blobs.partial.contains_key(&id) {
+            let used = blobs.used_bytes();
+            if used.saturating_add(manifest.size) > blobs.config.max_bytes {
+                return Err(P2PlaneError::stream(format!(
+                    "blob {} of {} bytes exceeds the remaining {} of {} bytes",
+                    id,
+                    manifest.size,
+                    blobs.config.max_bytes.saturating_sub(used),
+                    blobs.config.max_bytes
+                )));
+            }
+        }
+        let partial = blobs.partial.entry(id).or_insert_with(|| Partial {
+            manifest: manifest.clone(),
+            chunks: vec![None; manifest.chunks.len()],
+            updated: Instant::now(),
+        });
+        let missing: Vec<u32> = (0..partial.chunks.len() as u32)
+            .filter(|index| partial.chunks[*index as usize].is_none())
+            .collect();
+        if missing.is_empty() {
+            // Only an empty blob has no chunks to wait for.
+            let partial = blobs
+                .partial
+                .remove(&id)
+                .expect("partial blob was just looked up");
+            blobs.complete.insert(
+                id,
+                Arc::new(Blob {
+                    manifest: partial.manifest,
+                    data: Vec::new(),
+                }),
+            );
+        }
+        Ok(missing)
+    }
+
+    /// Adds chunk `index` of the unfinished blob `id` after checking it
+    /// against the manifest. Returns true once the blob is complete.
+    pub(crate) fn add_chunk(&self, id: &BlobId, index: u32, data: Vec<u8>) -> Result<bool> {
+        let mut blobs = self.lock();
+        if blobs.complete.contains_key(id) {
+            return Ok(true);
+        }
+        let partial = blobs
+            .partial
+            .get_mut(id)
+            .ok_or_else(|| P2PlaneError::stream(format!("no download of blob {} under way", id)))?;
+        if !partial.manifest.verify_chunk(index as usize, &data) {
+            return Err(P2PlaneError::stream(format!(
+                "chunk {} of blob {} failed verification",
+                index, id
+            )));
+        }
+        partial.chunks[index as usize] = Some(data);
+        partial.updated = Instant::now();
+        if partial.chunks.iter().any(Option::is_none) {
+            return Ok(false);
+        }
+
+        let partial = blobs
+            .partial
+            .remove(id)
+            .expect("partial blob was just looked up");
+        let data = partial.chunks.into_iter().flatten().flatten().collect();
+        blobs.complete.insert(
+            *id,
+            Arc::new(Blob {
+                manifest: partial.manifest,
+                data,
+            }),
+        );
+        Ok(true)
+    }
+}
+
+/// Control messages of [`BLOB_PROTOCOL`]. Each `Chunk` is followed by a
+/// frame holding the chunk's bytes.
+#[derive(Debug, Serialize, Deserialize)]
+enum BlobMessage {
+    /// Asks for the manifest of `id`.
+    Get {
+        id: BlobId,
+    },
+    /// Offers a blob.
+    Put {
+        manifest: Manifest,
+    },
+    /// Answer to `Put` if the receiver does not take the blob.
+    Refused,
+    /// Answer to `Get`; `None` if the blob is unknown.
+    Manifest {
+        manifest: Option<Manifest>,
+    },
+    /// Chunks the receiver still needs.
+    Want {
+        chunks: Vec<u32>,
+    },
+    Chunk {
+        index: u32,
+    },
+}
+
+pub(crate) async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> Result<()> {
+    stream
+        .write_all(&(data.len() as u32).to_be_bytes())
+        .await
+        .map_err(P2PlaneError::stream)?;
+    stream.write_all(data).await.map_err(P2PlaneError::stream)
+}
+
+pub(crate) async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S, max_len: usize) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream
+        .read_exact(&mut len)
+        .await
+        .map_err(P2PlaneError::stream)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(P2PlaneError::stream(format!(
+            "frame of {} bytes exceeds the limit of {}",
+            len, max_len
+        )));
+    }
+    let mut data = vec![0; len];
+    stream
+        .read_exact(&mut data)
+        .await
+        .map_err(P2PlaneError::stream)?;
+    Ok(data)
+}
+
+async fn send_message<S: AsyncWrite + Unpin>(stream: &mut S, message: &BlobMessage) -> Result<()> {
+    let data = serde_json::to_vec(message).map_err(|e| P2PlaneError::Codec(Box::new(e)))?;
+    write_frame(stream, &data).await?;
+    stream.flush().await.map_err(P2PlaneError::stream)
+}
+
+async fn recv_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<BlobMessage> {
+    let data = read_frame(stream, MAX_MESSAGE_SIZE).await?;
+    serde_json::from_slice(&data).map_err(|e| P2PlaneError::Codec(Box::new(e)))
+}
+
+fn unexpected(message: BlobMessage) -> P2PlaneError {
+    P2PlaneError::stream(format!("unexpected blob message {:?}", message))
+}
+
+async fn send_chunks<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    blob: &Blob,
+    chunks: &[u32],
+) -> Result<()> {
+    for &index in chunks {
+        let data = blob
+            .chunk(index)
+            .ok_or_else(|| P2PlaneError::stream(format!("blob has no chunk {}", index)))?;
+        send_message(stream, &BlobMessage::Chunk { index }).await?;
+        write_frame(stream, data).await?;
+    }
+    stream.flush().await.map_err(P2PlaneError::stream)
+}
+
+/// Receives the chunks listed in `wanted`. Chunks that fail verification
+/// are dropped and reported once all of them have been read.
+async fn recv_chunks<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    store: &BlobStore,
+    manifest: &Manifest,
+    wanted: &[u32],
+) -> Result<()> {
+    let id = manifest.id();
+    let mut rejected = None;
+    for _ in wanted {
+        let index = match recv_message(stream).await? {
+            BlobMessage::Chunk { index } => index,
+            other => return Err(unexpected(other)),
+        };
+        let data = read_frame(stream, manifest.chunk_size as usize).await?;
+        if let Err(e) = store.add_chunk(&id, index, data) {
+            rejected = Some(e);
+        }
+    }
+    rejected.map_or(Ok(()), Err)
+}
+
+/// Runs `transfer` up to [`ATTEMPTS`] times until it succeeds.
+async fn with_retries<T, F, Fut>(what: &str, mut transfer: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match transfer().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < ATTEMPTS => {
+                debug!(
+                    "{} failed (attempt {} of {}): {}",
+                    what, attempt, ATTEMPTS, e
+                );
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Stores `data` in `store` and pushes it to `peer`. Fails without retrying
+/// if the peer refuses the blob.
+pub(crate) async fn send_blob(
+    control: &StreamControl,
+    store: &BlobStore,
+    peer: PeerId,
+    data: Vec<u8>,
+) -> Result<BlobId> {
+    let id = store.insert(data);
+    let blob = store
+        .blob(&id)
+        .ok_or_else(|| P2PlaneError::stream(format!("blob {} was removed while sending it", id)))?;
+    let sent = with_retries(&format!("Sending blob {} to {}", id, peer), || async {
+        let mut stream = control
+            .open_stream(peer, StreamProtocol::new(BLOB_PROTOCOL))
+            .await?;
+        send_message(
+            &mut stream,
+            &BlobMessage::Put {
+                manifest: blob.manifest.clone(),
+            },
+        )
+        .await?;
+        let wanted = match recv_message(&mut stream).await? {
+            BlobMessage::Want { chunks } => chunks,
+            BlobMessage::Refused => return Ok(false),
+            other => return Err(unexpected(other)),
+        };
+        send_chunks(&mut stream, &blob, &wanted).await?;
+        match recv_message(&mut stream).await? {
+            BlobMessage::Want { chunks } if chunks.is_empty() => {}
+            BlobMessage::Want { chunks } => {
+                return Err(P2PlaneError::stream(format!(
+                    "{} rejected {} chunks of blob {}",
+                    peer,
+                    chunks.len(),
+                    id
+                )))
+            }
+            other => return Err(unexpected(other)),
+        }
+        let _ = stream.close().await;
+        Ok(true)
+    })
+    .await?;
+    if !sent {
+        return Err(P2PlaneError::stream(format!("{} refused blob {}", peer, id)));
+    }
+    Ok(id)
+}
+
+/// Fetches blob `id` from `peer` into `store`, resuming a download that was
+/// interrupted before. `None` if the peer does not have the blob.
+pub(crate) async fn fetch_blob(
+    control: &StreamControl,
+    store: &BlobStore,
+    peer: PeerId,
+    id: BlobId,
+) -> Result<Option<Vec<u8>>> {
+    if let Some(data) = store.get(&id) {
+        return Ok(Some(data));
+    }
+    with_retries(&format!("Fetching blob {} from {}", id, peer), || async {
+        let mut stream = control
+            .open_stream(peer, StreamProtocol::new(BLOB_PROTOCOL))
+            .await?;
+        send_message(&mut stream, &BlobMessage::Get { id }).await?;
+        let manifest = match recv_message(&mut stream).await? {
+            BlobMessage::Manifest {
+                manifest: Some(manifest),
+            } => manifest,
+            BlobMessage::Manifest { manifest: None } => return Ok(None),
+            other => return Err(unexpected(other)),
+        };
+        manifest.validate()?;
+        if manifest.id() != id {
+            return Err(P2PlaneError::stream(format!(
+                "{} sent a manifest that does not match blob {}",
+                peer, id
+            )));
+        }
+        let wanted = store.missing(&manifest)?;
+        send_message(
+            &mut stream,
+            &BlobMessage::Want {
+                chunks: wanted.clone(),
+            },
+        )
+        .await?;
+        recv_chunks(&mut stream, store, &manifest, &wanted).await?;
+        let _ = stream.close().await;
+        store
+            .get(&id)
+            .map(Some)
+            .ok_or_else(|| P2PlaneError::stream(format!("blob {} is incomplete", id)))
+    })
+    .await
+}
+
+/// Serves a blob stream opened by `peer`. Returns the id of the blob the
+/// peer pushed if this completed it.
+pub(crate) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    store: &BlobStore,
+    peer: PeerId,
+    mut stream: S,
+) -> Result<Option<BlobId>> {
+    match recv_message(&mut stream).await? {
+        BlobMessage::Get { id } => {
+            let blob = store.blob(&id);
+            let manifest = blob.as_ref().map(|blob| blob.manifest.clone());
+            send_message(&mut stream, &BlobMessage::Manifest { manifest }).await?;
+            if let Some(blob) = blob {
+                let wanted = match recv_message(&mut stream).await? {
+                    BlobMessage::Want { chunks } => chunks,
+                    other => return Err(unexpected(other)),
+                };
+                send_chunks(&mut stream, &blob, &wanted).await?;
+            }
+            let _ = stream.close().await;
+            Ok(None)
+        }
+        BlobMessage::Put { manifest } => {
+            manifest.validate()?;
+            let wanted = if store.accepts(&peer, &manifest) {
+                store.missing(&manifest)
+            } else {
+                Err(P2PlaneError::stream(format!(
+                    "blob {} is not accepted",
+                    manifest.id()
+                )))
+            };
+            let wanted = match wanted {
+                Ok(wanted) => wanted,
+                Err(e) => {
+                    send_message(&mut stream, &BlobMessage::Refused).await?;
+                    let _ = stream.close().await;
+                    return Err(e);
+                }
+            };
+            send_message(
+                &mut stream,
+                &BlobMessage::Want {
+                    chunks: wanted.clone(),
+                },
+            )
+            .await?;
+            if let Err(e) = recv_chunks(&mut stream, store, &manifest, &wanted).await {
+                debug!("Receiving blob {} failed: {}", manifest.id(), ErrorChain(&e));
+            }
+            let missing = store.missing(&manifest)?;
+            send_message(
+                &mut stream,
+                &BlobMessage::Want {
+                    chunks: missing.clone(),
+                },
+            )
+            .await?;
+            let _ = stream.close().await;
+            Ok((!wanted.is_empty() && missing.is_empty()).then(|| manifest.id()))
+        }
+        other => Err(unexpected(other)),
+    }
+}
@@ -0,0 +1,527 @@
+#[cfg(feature = "pnet")]
+use crate::p2plane::swarm_key::{self, PreSharedKey};
+#[cfg(feature = "pnet")]
+use libp2p::pnet::PnetConfig;
+use crate::p2plane::{
+    behavior::Behavior,
+    content::{ContentRequest, ContentResponse},
+    error::{BoxError, P2PlaneError},
+    keep_alive::KeepAlive,
+    limits::AdmissionControl,
+    network::{Node, NodeConfig, RelayMode},
+    traits::{AsyncPeerManagement, Message},
+    Result,
+};
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, MemoryTransport, Transport},
+        upgrade::Version,
+    },
+    dns,
+    futures::{future::Either, AsyncRead, AsyncWrite},
+    identify::{Behaviour as Identify, Config as IdentifyConfig},
+    identity::{Keypair, PublicKey},
+    kad::{store::MemoryStore, Behaviour as Kademlia, Config as KadConfig, Mode as KadMode},
+    mdns,
+    multiaddr::Protocol,
+    noise, ping, quic, relay,
+    request_response::{
+        cbor::Behaviour as RequestResponse, Config as RequestResponseConfig, ProtocolSupport,
+    },
+    swarm::{dummy, NetworkBehaviour, Swarm},
+    tcp, tls, websocket, yamux, PeerId, StreamProtocol, SwarmBuilder,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{fmt, marker::PhantomData, time::Duration};
+
+/// Protocol used for application messages.
+pub const MESSAGE_PROTOCOL: &str = "/p2plane/message/1.0.0";
+
+/// Protocol used for high-priority application messages when
+/// `outbound_queue.high_priority_protocol` is enabled.
+pub const HIGH_PRIORITY_PROTOCOL: &str = "/p2plane/message/high/1.0.0";
+
+/// Transports a node can listen on and dial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transports {
+    pub tcp: bool,
+    pub quic: bool,
+    /// WebSocket over TCP, for `/ws` addresses.
+    pub websocket: bool,
+    /// In-process `/memory/<n>` addresses.
+    pub memory: bool,
+    /// Resolves `/dns`, `/dns4`, `/dns6` and `/dnsaddr` addresses for TCP and
+    /// WebSocket.
+    pub dns: bool,
+}
+
+impl Default for Transports {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            quic: false,
+            websocket: false,
+            memory: true,
+            dns: false,
+        }
+    }
+}
+
+/// Security protocol used to authenticate and encrypt TCP, WebSocket, memory
+/// and relayed connections. QUIC always uses its built-in TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    #[default]
+    Noise,
+    Tls,
+}
+
+/// Stream multiplexer used on top of the secured connection.
+#[derive(Debug, Clone, Default)]
+pub enum Muxer {
+    #[default]
+    Yamux,
+    /// Yamux with a custom configuration.
+    YamuxWith(yamux::Config),
+}
+
+/// Discovery mechanisms a node runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discovery {
+    /// Kademlia DHT. Content routing needs it.
+    pub kademlia: bool,
+    /// Identify, which exchanges listen addresses and supported protocols.
+    pub identify: bool,
+    /// mDNS discovery of peers on the local network.
+    pub mdns: bool,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            kademlia: true,
+            identify: true,
+            mdns: false,
+        }
+    }
+}
+
+type IdentifyConfigFn = Box<dyn FnOnce(PublicKey) -> IdentifyConfig + Send>;
+
+/// Builds a [`Node`] from a [`NodeConfig`] plus the parts of the network
+/// stack that cannot be expressed as plain configuration: the identity,
+/// muxer, protocol configs and an optional application-defined
+/// [`NetworkBehaviour`]. The `transports`, `security` and `discovery` methods
+/// override the corresponding sections of the config.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use narwhal::p2plane::builder::{NodeBuilder, Security, Transports};
+/// # use narwhal::p2plane::network::NodeConfig;
+/// # use narwhal::p2plane::traits::Message;
+/// # use libp2p::ping;
+/// # #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+/// # struct MyMessage(String);
+/// # impl Message for MyMessage {
+/// #     fn protocol_id(&self) -> &'static str { "/my-app/1.0.0" }
+/// # }
+/// # async fn run() -> narwhal::p2plane::Result<()> {
+/// let mut node = NodeBuilder::<MyMessage>::new(NodeConfig::default())
+///     .transports(Transports { quic: true, ..Default::default() })
+///     .security(Security::Tls)
+///     .with_behaviour(ping::Behaviour::default())
+///     .build()
+///     .await?;
+/// let mut pings = node.extension_events().unwrap();
+/// # Ok(())
+/// # }
+/// ```
+pub struct NodeBuilder<M: Message, X: NetworkBehaviour = dummy::Behaviour> {
+    config: NodeConfig,
+    keypair: Option<Keypair>,
+    muxer: Muxer,
+    kad_config: Option<KadConfig>,
+    identify_config: Option<IdentifyConfigFn>,
+    mdns_config: mdns::Config,
+    extension: X,
+    peer_manager: Option<Box<dyn AsyncPeerManagement>>,
+    #[cfg(feature = "pnet")]
+    pre_shared_key: Option<PreSharedKey>,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M: Message, X: NetworkBehaviour> fmt::Debug for NodeBuilder<M, X> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeBuilder")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M: Message> NodeBuilder<M> {
+    pub fn new(config: NodeConfig) -> Self {
+        Self {
+            config,
+            keypair: None,
+            muxer: Muxer::default(),
+            kad_config: None,
+            identify_config: None,
+            mdns_config: mdns::Config::default(),
+            extension: dummy::Behaviour,
+            peer_manager: None,
+            #[cfg(feature = "pnet")]
+            pre_shared_key: None,
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<M: Message, X: NetworkBehaviour> NodeBuilder<M, X> {
+    /// Identity of the node. Overrides `NodeConfig::identity`.
+    pub fn keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    pub fn transports(mut self, transports: Transports) -> Self {
+        self.config.transports = transports;
+        self
+    }
+
+    pub fn security(mut self, security: Security) -> Self {
+        self.config.security = security;
+        self
+    }
+
+    pub fn muxer(mut self, muxer: Muxer) -> Self {
+        self.muxer = muxer;
+        self
+    }
+
+    pub fn discovery(mut self, discovery: Discovery) -> Self {
+        self.config.discovery = discovery;
+        self
+    }
+
+    /// Kademlia configuration. The provider publication interval and record
+    /// TTL are always taken from `NodeConfig::provider_reannounce_interval`.
+    pub fn kademlia_config(mut self, config: KadConfig) -> Self {
+        self.kad_config = Some(config);
+        self
+    }
+
+    /// Identify configuration, built from the node's public key.
+    pub fn identify_config(
+        mut self,
+        config: impl FnOnce(PublicKey) -> IdentifyConfig + Send + 'static,
+    ) -> Self {
+        self.identify_config = Some(Box::new(config));
+        self
+    }
+
+    pub fn mdns_config(mut self, config: mdns::Config) -> Self {
+        self.mdns_config = config;
+        self
+    }
+
+    /// How long message and content requests may take before they fail.
+    /// Overrides `NodeConfig::request_timeout`.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = timeout;
+        self
+    }
+
+    /// How long a connection without open streams is kept alive. Overrides
+    /// `NodeConfig::idle_connection_timeout`.
+    pub fn idle_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_connection_timeout = timeout;
+        self
+    }
+
+    /// Peer manager the node reports connections to and takes broadcast
+    /// targets and dial addresses from. By default a
+    /// [`PeerManager`](crate::p2plane::PeerManager) using
+    /// `NodeConfig::peer_storage` is created. Any
+    /// [`PeerManagement`](crate::p2plane::PeerManagement) implementation
+    /// that is `Sync` can be passed as well.
+    pub fn peer_manager(mut self, manager: impl AsyncPeerManagement + 'static) -> Self {
+        self.peer_manager = Some(Box::new(manager));
+        self
+    }
+
+    /// Pre-shared key of the private network the node belongs to. Overrides
+    /// `NodeConfig::swarm_key_file`.
+    #[cfg(feature = "pnet")]
+    pub fn pre_shared_key(mut self, key: PreSharedKey) -> Self {
+        self.pre_shared_key = Some(key);
+        self
+    }
+
+    /// Runs `behaviour` next to the built-in ones. Its events are available
+    /// from [`Node::extension_events`].
+    pub fn with_behaviour<Y: NetworkBehaviour>(self, behaviour: Y) -> NodeBuilder<M, Y> {
+        NodeBuilder {
+            config: self.config,
+            keypair: self.keypair,
+            muxer: self.muxer,
+            kad_config: self.kad_config,
+            identify_config: self.identify_config,
+            mdns_config: self.mdns_config,
+            extension: behaviour,
+            peer_manager: self.peer_manager,
+            #[cfg(feature = "pnet")]
+            pre_shared_key: self.pre_shared_key,
+            _message: PhantomData,
+        }
+    }
+
+    pub async fn build(mut self) -> Result<Node<M, X>> {
+        self.config.validate()?;
+        #[cfg(feature = "pnet")]
+        if let (Some(file), None) = (&self.config.swarm_key_file, &self.pre_shared_key) {
+            self.pre_shared_key = Some(swarm_key::load(&self.config.data_dir.join(file))?);
+        }
+        let keypair = match self.keypair.take() {
+            Some(keypair) => keypair,
+            None => self.config.identity.load(&self.config.data_dir)?,
+        };
+        let config = self.config.clone();
+        let peer_manager = self.peer_manager.take();
+        let swarm = self.build_swarm(keypair.clone())?;
+        Node::from_swarm(swarm, keypair, config, peer_manager)
+    }
+
+    fn build_swarm(self, local_key: Keypair) -> Result<Swarm<Behavior<M, X>>> {
+        let local_peer_id = PeerId::from(local_key.public());
+        info!("LocalPeerID: {local_peer_id}");
+        #[cfg(feature = "pnet")]
+        if let Some(psk) = &self.pre_shared_key {
+            info!("Private network with key fingerprint {}", psk.fingerprint());
+        }
+
+        let (relay_transport, relay_client) = match self.config.relay_mode {
+            RelayMode::Client => {
+                let (transport, behaviour) = relay::client::new(local_peer_id);
+                (Some(transport), Some(behaviour))
+            }
+            _ => (None, None),
+        };
+        let transport = self.build_transport(&local_key, relay_transport)?;
+        let idle_connection_timeout = self.config.idle_connection_timeout;
+        let behavior = self.build_behaviour(&local_key, relay_client)?;
+
+        let swarm = SwarmBuilder::with_existing_identity(local_key)
+            .with_tokio()
+            .with_other_transport(|_| transport)
+            .map_err(|e| P2PlaneError::Transport(Box::new(e)))?
+            .with_behaviour(|_| behavior)
+            .map_err(|e| P2PlaneError::Transport(Box::new(e)))?
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(idle_connection_timeout))
+            .build();
+        Ok(swarm)
+    }
+
+    fn build_transport(
+        &self,
+        key: &Keypair,
+        relay: Option<relay::client::Transport>,
+    ) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+        let mut transports = Vec::new();
+        // Relayed addresses start with the relay's own address, so the relay
+        // transport has to get the first chance to handle them.
+        if let Some(relay) = relay {
+            transports.push(self.upgrade(relay, key)?);
+        }
+        if self.config.transports.tcp {
+            transports.push(self.upgrade(self.raw_tcp()?, key)?);
+        }
+        if self.config.transports.websocket {
+            transports.push(self.upgrade(websocket::WsConfig::new(self.raw_tcp()?), key)?);
+        }
+        if self.config.transports.quic {
+            #[cfg(feature = "pnet")]
+            if self.pre_shared_key.is_some() {
+                return Err(P2PlaneError::config(
+                    "QUIC cannot be used in a private network",
+                ));
+            }
+            transports.push(
+                quic::tokio::Transport::new(quic::Config::new(key))
+                    .map(|(peer, connection), _| (peer, StreamMuxerBox::new(connection)))
+                    .boxed(),
+            );
+        }
+        if self.config.transports.memory {
+            // In-process transport for `/memory/<n>` addresses, used to run
+            // many nodes in one process without opening sockets.
+            #[cfg(any(test, feature = "testing"))]
+            if let Some(endpoint) = &self.config.simulation {
+                transports.push(self.upgrade(endpoint.memory_transport(), key)?);
+            } else {
+                transports.push(self.upgrade(MemoryTransport::default(), key)?);
+            }
+            #[cfg(not(any(test, feature = "testing")))]
+            transports.push(self.upgrade(MemoryTransport::default(), key)?);
+        }
+
+        transports
+            .into_iter()
+            .reduce(|a, b| {
+                a.or_transport(b)
+                    .map(|output, _| match output {
+                        Either::Left(output) | Either::Right(output) => output,
+                    })
+                    .boxed()
+            })
+            .ok_or_else(|| P2PlaneError::config("at least one transport must be enabled"))
+    }
+
+    fn raw_tcp(&self) -> Result<Boxed<tcp::tokio::TcpStream>> {
+        let tcp = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
+        if !self.config.transports.dns {
+            return Ok(tcp.boxed());
+        }
+        Ok(dns::tokio::Transport::system(tcp)
+            .map_err(P2PlaneError::transport)?
+            .boxed())
+    }
+
+    /// Secures and multiplexes `transport` with the configured protocols,
+    /// after the private network handshake if there is a pre-shared key.
+    fn upgrade<T>(&self, transport: T, key: &Keypair) -> Result<Boxed<(PeerId, StreamMuxerBox)>>
+    where
+        T: Transport + Send + Unpin + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Error: Send + Sync + 'static,
+        T::Dial: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+    {
+        #[cfg(feature = "pnet")]
+        if let Some(psk) = self.pre_shared_key {
+            return self.secure(
+                transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+                key,
+            );
+        }
+        self.secure(transport, key)
+    }
+
+    fn secure<T>(&self, transport: T, key: &Keypair) -> Result<Boxed<(PeerId, StreamMuxerBox)>>
+    where
+        T: Transport + Send + Unpin + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Error: Send + Sync + 'static,
+        T::Dial: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+    {
+        let muxer = match &self.muxer {
+            Muxer::Yamux => yamux::Config::default(),
+            Muxer::YamuxWith(config) => config.clone(),
+        };
+        let upgrade = transport.upgrade(Version::V1);
+        Ok(match self.config.security {
+            Security::Noise => upgrade
+                .authenticate(noise::Config::new(key).map_err(P2PlaneError::transport)?)
+                .multiplex(muxer)
+                .boxed(),
+            Security::Tls => upgrade
+                .authenticate(tls::Config::new(key).map_err(P2PlaneError::transport)?)
+                .multiplex(muxer)
+                .boxed(),
+        })
+    }
+
+    fn build_behaviour(
+        self,
+        key: &Keypair,
+        relay_client: Option<relay::client::Behaviour>,
+    ) -> Result<Behavior<M, X>> {
+        let config = &self.config;
+        let local_peer_id = PeerId::from(key.public());
+        let mut limits = config.limits.clone();
+        if let Some(Protocol::P2p(bootstrap_peer)) =
+            config.bootstrap_addr.as_ref().and_then(|addr| addr.iter().last())
+        {
+            limits.reserved_peers.insert(bootstrap_peer);
+        }
+
+        let kad = config.discovery.kademlia.then(|| {
+            let mut kad_config = self.kad_config.unwrap_or_default();
+            kad_config
+                .set_provider_publication_interval(Some(config.provider_reannounce_interval))
+                .set_provider_record_ttl(Some(config.provider_reannounce_interval * 4));
+            let mut kad =
+                Kademlia::with_config(local_peer_id, MemoryStore::new(local_peer_id), kad_config);
+            // Serve DHT queries even without a confirmed external address,
+            // otherwise provider lookups on private networks find nothing.
+            kad.set_mode(Some(KadMode::Server));
+            kad
+        });
+
+        let identify = config.discovery.identify.then(|| {
+            let identify_config = match self.identify_config {
+                Some(build) => build(key.public()),
+                None => IdentifyConfig::new("/p2plane/1.0.0".to_string(), key.public())
+                    .with_push_listen_addr_updates(true)
+                    .with_interval(Duration::from_secs(30)),
+            };
+            Identify::new(identify_config)
+        });
+
+        let rr_config = RequestResponseConfig::default()
+            .with_request_timeout(config.request_timeout)
+            .with_max_concurrent_streams(config.max_concurrent_streams);
+        let request_response = RequestResponse::<M, M>::new(
+            [(StreamProtocol::new(MESSAGE_PROTOCOL), ProtocolSupport::Full)],
+            rr_config.clone(),
+        );
+        let high_priority = config.outbound_queue.high_priority_protocol.then(|| {
+            RequestResponse::<M, M>::new(
+                [(StreamProtocol::new(HIGH_PRIORITY_PROTOCOL), ProtocolSupport::Full)],
+                rr_config.clone(),
+            )
+        });
+        let content = RequestResponse::<ContentRequest, ContentResponse>::new(
+            [(
+                StreamProtocol::new(crate::p2plane::behavior::CONTENT_PROTOCOL),
+                ProtocolSupport::Full,
+            )],
+            rr_config,
+        );
+
+        let mut behavior = Behavior::from_parts(kad, identify, request_response)
+            .with_content(content)
+            .with_limits(AdmissionControl::new(limits))
+            .with_keep_alive(KeepAlive::new(
+                config.keep_alive.clone(),
+                config.bootstrap_addr.as_ref(),
+            ))
+            .with_ping(ping::Behaviour::new(
+                ping::Config::new().with_interval(config.ping_interval),
+            ));
+        if let Some(high_priority) = high_priority {
+            behavior = behavior.with_high_priority(high_priority);
+        }
+        if config.discovery.mdns {
+            let mdns = mdns::tokio::Behaviour::new(self.mdns_config, local_peer_id)
+                .map_err(|e| P2PlaneError::Transport(Box::new(e) as BoxError))?;
+            behavior = behavior.with_mdns(mdns);
+        }
+        behavior = match config.relay_mode {
+            RelayMode::Disabled => behavior,
+            RelayMode::Server => behavior
+                .with_relay_server(relay::Behaviour::new(local_peer_id, relay::Config::default())),
+            RelayMode::Client => match relay_client {
+                Some(relay_client) => behavior.with_relay_client(relay_client),
+                None => behavior,
+            },
+        };
+        Ok(behavior.with_extension(self.extension))
+    }
+}
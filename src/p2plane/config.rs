@@ -0,0 +1,437 @@
+//! Node configuration and loading it from files and the environment.
+//!
+//! A config file mirrors the fields of [`NodeConfig`]. It is TOML, or YAML
+//! when the file ends in `.yaml`/`.yml` and the `yaml` feature is enabled.
+//! Every field is optional and falls back to [`NodeConfig::default`].
+//! Durations are written as strings such as `"15s"` or `"12h"`.
+//!
+//! Environment variables starting with `P2PLANE_` override values from the
+//! file. The rest of the name is the field path, with `__` between a section
+//! and its fields. Values are read as TOML where possible, so numbers,
+//! booleans and arrays keep their type, and are taken as plain strings
+//! otherwise:
+//!
+//! ```text
+//! P2PLANE_LISTEN_ADDR=/ip4/0.0.0.0/tcp/9000
+//! P2PLANE_PING_INTERVAL=30s
+//! P2PLANE_TRANSPORTS__QUIC=true
+//! P2PLANE_LIMITS__MAX_ESTABLISHED=64
+//! ```
+//!
+//! Variables in [`RESERVED_ENV_VARS`] share the prefix but are read by
+//! other parts of p2plane, and are not config overrides.
+
+#[cfg(any(test, feature = "testing"))]
+use crate::p2plane::testing::SimEndpoint;
+use crate::p2plane::{
+    admin::{AdminConfig, ADMIN_TOKEN_ENV},
+    blob::BlobConfig,
+    builder::{Discovery, Security, Transports},
+    error::P2PlaneError,
+    identity::{IdentityConfig, DEFAULT_PASSPHRASE_ENV},
+    keep_alive::KeepAlivePolicy,
+    limits::ConnectionLimits,
+    peer_manager::DEFAULT_FLUSH_INTERVAL,
+    queue::OutboundQueueConfig,
+    storage::PeerStorageKind,
+    Result,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml::{Table, Value};
+
+/// Prefix of environment variables that override config file values.
+pub const ENV_PREFIX: &str = "P2PLANE_";
+
+/// Variables starting with [`ENV_PREFIX`] that are skipped when applying
+/// environment overrides.
+pub const RESERVED_ENV_VARS: &[&str] = &[DEFAULT_PASSPHRASE_ENV, ADMIN_TOKEN_ENV];
+
+/// Whether a node takes part in circuit relaying.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    #[default]
+    Disabled,
+    /// Relay connections for other peers. The node's listen addresses are
+    /// advertised as external addresses so clients can make reservations.
+    Server,
+    /// Accept and dial connections through relays. Required for listening on
+    /// or dialing `/p2p-circuit` addresses.
+    Client,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Address to listen on. Besides TCP this may be an in-process
+    /// `/memory/<n>` address, or a relayed address of the form
+    /// `<relay-addr>/p2p/<relay-id>/p2p-circuit` when `relay_mode` is `Client`.
+    pub listen_addr: String,
+    /// Bootstrap peer to dial on start. May also be a `/p2p-circuit` address.
+    pub bootstrap_addr: Option<Multiaddr>,
+    /// How often provider records for keys passed to `start_providing` are
+    /// re-published to the DHT.
+    #[serde(with = "humantime_serde")]
+    pub provider_reannounce_interval: Duration,
+    pub relay_mode: RelayMode,
+    /// How often connected peers are pinged to measure round-trip time.
+    #[serde(with = "humantime_serde")]
+    pub ping_interval: Duration,
+    /// Consecutive failed pings after which a peer is disconnected.
+    pub max_missed_pings: u32,
+    pub peer_storage: PeerStorageKind,
+    /// Directory for state kept across restarts, such as the peer storage
+    /// file. Created when first written to.
+    pub data_dir: PathBuf,
+    /// How long peer storage changes are batched before the file is
+    /// rewritten. Zero writes every change immediately.
+    #[serde(with = "humantime_serde")]
+    pub storage_flush_interval: Duration,
+    /// Security protocol for all transports except QUIC.
+    pub security: Security,
+    pub transports: Transports,
+    pub discovery: Discovery,
+    /// Connection limits. The bootstrap peer, when its address ends in
+    /// `/p2p/<peer-id>`, is added to the reserved peers automatically.
+    pub limits: ConnectionLimits,
+    /// Per-peer limits on messages waiting to be sent.
+    pub outbound_queue: OutboundQueueConfig,
+    /// How long message and content requests may take before they fail.
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+    /// Inbound and outbound request streams open at once, per connection
+    /// and protocol.
+    pub max_concurrent_streams: usize,
+    /// How long a connection without open streams is kept before it is
+    /// closed. Connections to the peers of `keep_alive` are never closed
+    /// for idleness.
+    #[serde(with = "humantime_serde")]
+    pub idle_connection_timeout: Duration,
+    pub keep_alive: KeepAlivePolicy,
+    /// Limits of the blobs the node holds and serves to peers.
+    pub blobs: BlobConfig,
+    /// Admin HTTP API, see [`admin`](crate::p2plane::admin).
+    pub admin: AdminConfig,
+    /// Key type and key file of the node.
+    pub identity: IdentityConfig,
+    /// Pre-shared key file of a private network, relative to `data_dir`
+    /// unless absolute. Only nodes with the same key can connect; see the
+    /// `swarm_key` module. Requires the `pnet` feature.
+    pub swarm_key_file: Option<PathBuf>,
+    /// Routes `/memory/<n>` connections through a network simulator.
+    #[cfg(any(test, feature = "testing"))]
+    #[serde(skip)]
+    pub simulation: Option<SimEndpoint>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "/ip4/0.0.0.0/tcp/0".to_string(),
+            bootstrap_addr: None,
+            provider_reannounce_interval: Duration::from_secs(12 * 60 * 60),
+            relay_mode: RelayMode::Disabled,
+            ping_interval: Duration::from_secs(15),
+            max_missed_pings: 3,
+            peer_storage: PeerStorageKind::File,
+            data_dir: PathBuf::from("."),
+            storage_flush_interval: DEFAULT_FLUSH_INTERVAL,
+            security: Security::default(),
+            transports: Transports::default(),
+            discovery: Discovery::default(),
+            limits: ConnectionLimits::default(),
+            outbound_queue: OutboundQueueConfig::default(),
+            request_timeout: Duration::from_secs(10),
+            max_concurrent_streams: 100,
+            idle_connection_timeout: Duration::from_secs(30),
+            keep_alive: KeepAlivePolicy::default(),
+            blobs: BlobConfig::default(),
+            admin: AdminConfig::default(),
+            identity: IdentityConfig::default(),
+            swarm_key_file: None,
+            #[cfg(any(test, feature = "testing"))]
+            simulation: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Reads the config file at `path`, if any, applies `P2PLANE_*`
+    /// environment overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_with_env(path, std::env::vars())
+    }
+
+    /// Like [`NodeConfig::load`], but takes the environment overrides from
+    /// `env` instead of the process environment.
+    pub fn load_with_env(
+        path: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => read_file(path)?,
+            None => Table::new(),
+        };
+        apply_env(&mut table, env)?;
+        let config = Self::from_table(table)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a config file, choosing the format from its extension. Unlike
+    /// [`NodeConfig::load`] this neither applies environment overrides nor
+    /// validates the result.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_table(read_file(path)?)
+    }
+
+    pub fn from_toml_str(s: &str) -> Result<Self> {
+        Self::from_table(parse_toml(s)?)
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_str(s: &str) -> Result<Self> {
+        Self::from_table(parse_yaml(s)?)
+    }
+
+    /// Serializes the config as TOML, e.g. to print the default config for
+    /// users to start from.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).map_err(|e| P2PlaneError::Config {
+            message: "failed to serialize the configuration".to_string(),
+            source: Some(Box::new(e)),
+        })
+    }
+
+    /// Checks values that deserialize fine but cannot work together. All
+    /// problems are reported at once, each prefixed with its field path.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        match self.listen_addr.parse::<Multiaddr>() {
+            Ok(addr) => self.validate_listen_addr(&addr, &mut problems),
+            Err(e) => problems.push(format!("listen_addr: {}", e)),
+        }
+        if self.provider_reannounce_interval.is_zero() {
+            problems.push("provider_reannounce_interval: must be greater than zero".to_string());
+        }
+        if self.ping_interval.is_zero() {
+            problems.push("ping_interval: must be greater than zero".to_string());
+        }
+        if self.data_dir.exists() && !self.data_dir.is_dir() {
+            problems.push(format!(
+                "data_dir: {} is not a directory",
+                self.data_dir.display()
+            ));
+        }
+        if self.max_missed_pings == 0 {
+            problems.push("max_missed_pings: must be at least 1".to_string());
+        }
+        #[cfg(not(feature = "sqlite"))]
+        if self.peer_storage == PeerStorageKind::Sqlite {
+            problems.push("peer_storage: sqlite requires the `sqlite` feature".to_string());
+        }
+
+        let transports = &self.transports;
+        if !(transports.tcp || transports.quic || transports.websocket || transports.memory) {
+            problems.push("transports: at least one transport must be enabled".to_string());
+        }
+        if transports.dns && !(transports.tcp || transports.websocket) {
+            problems.push(
+                "transports.dns: requires transports.tcp or transports.websocket".to_string(),
+            );
+        }
+
+        let limits = &self.limits;
+        if limits.max_established_per_peer == Some(0) {
+            problems.push("limits.max_established_per_peer: must be at least 1".to_string());
+        }
+        for (name, limit) in [
+            ("max_established", limits.max_established),
+            ("max_established_incoming", limits.max_established_incoming),
+            ("max_established_outgoing", limits.max_established_outgoing),
+        ] {
+            if let Some(limit) = limit.filter(|&limit| limits.reserved_slots > limit) {
+                problems.push(format!(
+                    "limits.reserved_slots: {} exceeds limits.{} ({})",
+                    limits.reserved_slots, name, limit
+                ));
+            }
+        }
+
+        if self.outbound_queue.capacity == 0 {
+            problems.push("outbound_queue.capacity: must be at least 1".to_string());
+        }
+        if self.outbound_queue.max_in_flight == 0 {
+            problems.push("outbound_queue.max_in_flight: must be at least 1".to_string());
+        }
+        let weights = self.outbound_queue.weights;
+        for (name, weight) in [("high", weights.high), ("normal", weights.normal), ("low", weights.low)] {
+            if weight == 0 {
+                problems.push(format!("outbound_queue.weights.{}: must be at least 1", name));
+            }
+        }
+
+        if self.request_timeout.is_zero() {
+            problems.push("request_timeout: must be greater than zero".to_string());
+        }
+        if self.max_concurrent_streams == 0 {
+            problems.push("max_concurrent_streams: must be at least 1".to_string());
+        }
+        if self.blobs.partial_timeout.is_zero() {
+            problems.push("blobs.partial_timeout: must be greater than zero".to_string());
+        }
+        if self.blobs.max_transfers == 0 {
+            problems.push("blobs.max_transfers: must be at least 1".to_string());
+        }
+        if self.identity.key_file.as_ref().is_some_and(|file| file.as_os_str().is_empty()) {
+            problems.push("identity.key_file: must not be empty".to_string());
+        }
+        if self.identity.passphrase_env.is_empty() {
+            problems.push("identity.passphrase_env: must not be empty".to_string());
+        }
+        if self.swarm_key_file.is_some() {
+            #[cfg(not(feature = "pnet"))]
+            problems.push("swarm_key_file: requires the `pnet` feature".to_string());
+            if self.transports.quic {
+                problems.push("transports.quic: cannot be used with swarm_key_file".to_string());
+            }
+        }
+        if self.admin.token.as_deref().is_some_and(str::is_empty) {
+            problems.push("admin.token: must not be empty".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(P2PlaneError::config(problems.join("; ")))
+        }
+    }
+
+    fn validate_listen_addr(&self, addr: &Multiaddr, problems: &mut Vec<String>) {
+        let mut required = Vec::new();
+        let mut tcp = false;
+        let mut websocket = false;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::Memory(_) if !self.transports.memory => {
+                    required.push("transports.memory")
+                }
+                Protocol::QuicV1 if !self.transports.quic => required.push("transports.quic"),
+                Protocol::P2pCircuit if self.relay_mode != RelayMode::Client => {
+                    required.push("relay_mode = \"client\"")
+                }
+                Protocol::Tcp(_) => tcp = true,
+                Protocol::Ws(_) | Protocol::Wss(_) => websocket = true,
+                _ => {}
+            }
+        }
+        if websocket && !self.transports.websocket {
+            required.push("transports.websocket");
+        } else if tcp && !websocket && !self.transports.tcp {
+            required.push("transports.tcp");
+        }
+        for requirement in required {
+            problems.push(format!("listen_addr: {} requires {}", addr, requirement));
+        }
+    }
+
+    fn from_table(table: Table) -> Result<Self> {
+        serde_path_to_error::deserialize(Value::Table(table)).map_err(|e| {
+            let path = e.path().to_string();
+            let source = e.into_inner();
+            P2PlaneError::Config {
+                message: format!("{}: {}", path, source.message()),
+                source: Some(Box::new(source)),
+            }
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<Table> {
+    let contents = fs::read_to_string(path).map_err(|e| P2PlaneError::Config {
+        message: format!("failed to read config file {}", path.display()),
+        source: Some(Box::new(e)),
+    })?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        #[cfg(feature = "yaml")]
+        Some("yaml" | "yml") => parse_yaml(&contents),
+        #[cfg(not(feature = "yaml"))]
+        Some("yaml" | "yml") => Err(P2PlaneError::config(format!(
+            "{}: YAML config files require the `yaml` feature",
+            path.display()
+        ))),
+        _ => parse_toml(&contents),
+    }
+}
+
+fn parse_toml(s: &str) -> Result<Table> {
+    s.parse().map_err(|e| P2PlaneError::Config {
+        message: "invalid TOML".to_string(),
+        source: Some(Box::new(e)),
+    })
+}
+
+#[cfg(feature = "yaml")]
+fn parse_yaml(s: &str) -> Result<Table> {
+    // An empty document is null rather than an empty mapping.
+    if s.trim().is_empty() {
+        return Ok(Table::new());
+    }
+    serde_yaml::from_str(s).map_err(|e| P2PlaneError::Config {
+        message: "invalid YAML".to_string(),
+        source: Some(Box::new(e)),
+    })
+}
+
+/// Sets the value of every `P2PLANE_*` variable in `env` in `table`,
+/// except for the [`RESERVED_ENV_VARS`].
+fn apply_env(table: &mut Table, env: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+    for (name, raw) in env {
+        if RESERVED_ENV_VARS.contains(&name.as_str()) {
+            continue;
+        }
+        let Some(path) = name
+            .strip_prefix(ENV_PREFIX)
+            .filter(|path| !path.is_empty())
+        else {
+            continue;
+        };
+        let path: Vec<String> = path.split("__").map(str::to_ascii_lowercase).collect();
+        let (key, sections) = path
+            .split_last()
+            .expect("split yields at least one segment");
+
+        let mut current = &mut *table;
+        for section in sections {
+            current = match current
+                .entry(section.as_str())
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                Value::Table(table) => table,
+                _ => {
+                    return Err(P2PlaneError::config(format!(
+                        "{}: {} is not a section",
+                        name, section
+                    )))
+                }
+            };
+        }
+        current.insert(key.clone(), parse_env_value(&raw));
+    }
+    Ok(())
+}
+
+fn parse_env_value(raw: &str) -> Value {
+    format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
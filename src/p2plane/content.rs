@@ -0,0 +1,179 @@
+use libp2p::{
+    futures::channel::{mpsc, oneshot},
+    kad::{QueryId, RecordKey},
+    request_response::OutboundRequestId,
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Request sent to a provider asking for the content stored under `key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentRequest {
+    pub key: Vec<u8>,
+}
+
+/// Response to a [`ContentRequest`]. `content` is `None` when the provider
+/// no longer holds the requested key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentResponse {
+    pub content: Option<Vec<u8>>,
+}
+
+/// An in-progress `fetch`: providers are tried one after another until one
+/// of them returns the content or the provider lookup runs dry.
+#[derive(Debug)]
+struct PendingFetch {
+    key: RecordKey,
+    candidates: VecDeque<PeerId>,
+    seen: HashSet<PeerId>,
+    in_flight: Option<OutboundRequestId>,
+    lookup_finished: bool,
+    reply: Option<oneshot::Sender<Option<Vec<u8>>>>,
+}
+
+/// Bookkeeping for provider lookups and content fetches that span several
+/// swarm events.
+#[derive(Debug, Default)]
+pub(crate) struct ContentRouting {
+    store: HashMap<RecordKey, Vec<u8>>,
+    provider_lookups: HashMap<QueryId, ProviderLookup>,
+    fetches: HashMap<QueryId, PendingFetch>,
+    fetch_requests: HashMap<OutboundRequestId, QueryId>,
+}
+
+#[derive(Debug)]
+struct ProviderLookup {
+    seen: HashSet<PeerId>,
+    sender: mpsc::UnboundedSender<PeerId>,
+}
+
+impl ContentRouting {
+    pub fn insert_content(&mut self, key: RecordKey, content: Vec<u8>) {
+        self.store.insert(key, content);
+    }
+
+    pub fn remove_content(&mut self, key: &RecordKey) {
+        self.store.remove(key);
+    }
+
+    pub fn get_content(&self, key: &RecordKey) -> Option<Vec<u8>> {
+        self.store.get(key).cloned()
+    }
+
+    pub fn track_lookup(&mut self, query_id: QueryId, sender: mpsc::UnboundedSender<PeerId>) {
+        self.provider_lookups.insert(
+            query_id,
+            ProviderLookup {
+                seen: HashSet::new(),
+                sender,
+            },
+        );
+    }
+
+    pub fn track_fetch(
+        &mut self,
+        query_id: QueryId,
+        key: RecordKey,
+        local_peer_id: PeerId,
+        reply: oneshot::Sender<Option<Vec<u8>>>,
+    ) {
+        // The local node may show up as a provider of its own content; never
+        // send a request to ourselves.
+        self.fetches.insert(
+            query_id,
+            PendingFetch {
+                key,
+                candidates: VecDeque::new(),
+                seen: HashSet::from([local_peer_id]),
+                in_flight: None,
+                lookup_finished: false,
+                reply: Some(reply),
+            },
+        );
+    }
+
+    /// Feeds providers discovered by a `GetProviders` query into whichever
+    /// lookup or fetch owns it.
+    pub fn providers_found(
+        &mut self,
+        query_id: QueryId,
+        providers: impl IntoIterator<Item = PeerId>,
+    ) {
+        if let Some(lookup) = self.provider_lookups.get_mut(&query_id) {
+            for provider in providers {
+                if lookup.seen.insert(provider) {
+                    let _ = lookup.sender.unbounded_send(provider);
+                }
+            }
+        } else if let Some(fetch) = self.fetches.get_mut(&query_id) {
+            for provider in providers {
+                if fetch.seen.insert(provider) {
+                    fetch.candidates.push_back(provider);
+                }
+            }
+        }
+    }
+
+    /// Marks the `GetProviders` query as complete. Provider streams are closed
+    /// and fetches without remaining candidates resolve to `None`.
+    pub fn lookup_finished(&mut self, query_id: QueryId) {
+        self.provider_lookups.remove(&query_id);
+        if let Some(fetch) = self.fetches.get_mut(&query_id) {
+            fetch.lookup_finished = true;
+        }
+        self.finish_if_exhausted(query_id);
+    }
+
+    /// Returns the next provider a fetch should ask, if it is not already
+    /// waiting on one.
+    pub fn next_fetch_target(&mut self, query_id: QueryId) -> Option<(PeerId, RecordKey)> {
+        let fetch = self.fetches.get_mut(&query_id)?;
+        if fetch.in_flight.is_some() {
+            return None;
+        }
+        let peer = fetch.candidates.pop_front()?;
+        Some((peer, fetch.key.clone()))
+    }
+
+
+    pub fn fetch_sent(&mut self, query_id: QueryId, request_id: OutboundRequestId) {
+        if let Some(fetch) = self.fetches.get_mut(&query_id) {
+            fetch.in_flight = Some(request_id);
+            self.fetch_requests.insert(request_id, query_id);
+        }
+    }
+
+    /// Handles the outcome of a content request. Returns the fetch's query id
+    /// when it is still pending and should move on to the next provider.
+    pub fn fetch_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        content: Option<Vec<u8>>,
+    ) -> Option<QueryId> {
+        let query_id = self.fetch_requests.remove(&request_id)?;
+        let fetch = self.fetches.get_mut(&query_id)?;
+        fetch.in_flight = None;
+
+        if let Some(content) = content {
+            if let Some(reply) = self.fetches.remove(&query_id).and_then(|f| f.reply) {
+                let _ = reply.send(Some(content));
+            }
+            return None;
+        }
+
+        self.finish_if_exhausted(query_id);
+        self.fetches.contains_key(&query_id).then_some(query_id)
+    }
+
+    fn finish_if_exhausted(&mut self, query_id: QueryId) {
+        let exhausted = self.fetches.get(&query_id).is_some_and(|f| {
+            f.lookup_finished && f.in_flight.is_none() && f.candidates.is_empty()
+        });
+        if exhausted {
+            if let Some(reply) = self.fetches.remove(&query_id).and_then(|f| f.reply) {
+                let _ = reply.send(None);
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use libp2p::{swarm::DialError, Multiaddr, PeerId};
+use std::{error::Error as StdError, fmt};
+
+/// Boxed error used as the source of variants that wrap errors from several
+/// underlying libraries.
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Errors returned by the p2plane API.
+#[derive(Debug, thiserror::Error)]
+pub enum P2PlaneError {
+    /// Building the transport stack or listening on an address failed.
+    #[error("transport error")]
+    Transport(#[source] BoxError),
+    /// Dialing a peer failed.
+    #[error("failed to dial {addr}")]
+    Dial {
+        addr: Multiaddr,
+        #[source]
+        source: DialError,
+    },
+    /// A message or record could not be encoded or decoded.
+    #[error("codec error")]
+    Codec(#[source] BoxError),
+    /// An operation did not complete in time.
+    #[error("timed out: {0}")]
+    Timeout(String),
+    /// Reading or writing peer or record storage failed.
+    #[error("storage error")]
+    Storage(#[source] BoxError),
+    /// Opening a stream or transferring data over it failed.
+    #[error("stream error")]
+    Stream(#[source] BoxError),
+    /// The outbound queue of `peer` is full and the overflow policy is
+    /// [`OverflowPolicy::Error`](crate::p2plane::queue::OverflowPolicy::Error).
+    #[error("outbound queue of {peer} is full")]
+    QueueFull { peer: PeerId },
+    /// The configuration is invalid.
+    #[error("invalid configuration: {message}")]
+    Config {
+        message: String,
+        #[source]
+        source: Option<BoxError>,
+    },
+    /// The node's event loop has stopped, so a [`NodeHandle`] request could
+    /// not be served.
+    ///
+    /// [`NodeHandle`]: crate::p2plane::NodeHandle
+    #[error("node is no longer running")]
+    NodeStopped,
+}
+
+impl P2PlaneError {
+    pub(crate) fn config(message: impl Into<String>) -> Self {
+        P2PlaneError::Config {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn transport(error: impl Into<BoxError>) -> Self {
+        P2PlaneError::Transport(error.into())
+    }
+
+    pub(crate) fn storage(error: impl Into<BoxError>) -> Self {
+        P2PlaneError::Storage(error.into())
+    }
+
+    pub(crate) fn stream(error: impl Into<BoxError>) -> Self {
+        P2PlaneError::Stream(error.into())
+    }
+}
+
+/// Displays an error followed by its chain of sources, separated by `: `,
+/// for logs and messages shown to users.
+pub struct ErrorChain<'a>(pub &'a (dyn StdError + 'static));
+
+impl fmt::Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(error) = source {
+            write!(f, ": {}", error)?;
+            source = error.source();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+use crate::p2plane::{
+    blob::{self, BlobId, BlobStore},
+    error::{ErrorChain, P2PlaneError},
+    identity::{self, KeyRotation},
+    stream::{IncomingStreams, StreamControl},
+    traits::Message,
+    PeerEvent, PeerInfo, QueueDepth, Result, RoutingEntry,
+};
+use libp2p::{
+    futures::{
+        channel::{mpsc, oneshot},
+        future,
+    },
+    identity::Keypair,
+    kad::RecordKey,
+    Multiaddr, PeerId, Stream, StreamProtocol,
+};
+use log::debug;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::sync::{broadcast, mpsc::UnboundedSender};
+
+/// How long announcing a key rotation to a single peer may take.
+const ROTATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events published by a running node to every subscriber.
+#[derive(Debug, Clone)]
+pub enum NodeEvent<M> {
+    /// A message was received from `peer`.
+    Message { peer: PeerId, message: M },
+    /// The first connection to `peer` was established.
+    PeerConnected(PeerId),
+    /// The last connection to `peer` was closed.
+    PeerDisconnected(PeerId),
+    /// `peer` finished pushing blob `id`, which is now in the node's
+    /// [`BlobStore`].
+    BlobReceived { peer: PeerId, id: BlobId },
+}
+
+/// Requests sent from a [`NodeHandle`] to the node's event loop.
+#[derive(Debug)]
+pub(crate) enum Command<M: Message> {
+    Broadcast {
+        message: M,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    SendMessage {
+        peer: PeerId,
+        message: M,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    StartProviding {
+        key: RecordKey,
+        content: Option<Vec<u8>>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    StopProviding {
+        key: RecordKey,
+    },
+    GetProviders {
+        key: RecordKey,
+        sender: mpsc::UnboundedSender<PeerId>,
+    },
+    Fetch {
+        key: RecordKey,
+        reply: oneshot::Sender<Option<Vec<u8>>>,
+    },
+    PeerInfo {
+        peer: PeerId,
+        reply: oneshot::Sender<Option<PeerInfo>>,
+    },
+    Dial {
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    PeersSupporting {
+        protocol: String,
+        reply: oneshot::Sender<Vec<PeerId>>,
+    },
+    DialPeer {
+        peer: PeerId,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    RemovePeer {
+        peer: PeerId,
+        reply: oneshot::Sender<bool>,
+    },
+    BanPeer {
+        peer: PeerId,
+    },
+    UnbanPeer {
+        peer: PeerId,
+    },
+    KeepAlive {
+        peer: PeerId,
+    },
+    ReleaseKeepAlive {
+        peer: PeerId,
+    },
+    ConnectedPeers {
+        reply: oneshot::Sender<Vec<PeerId>>,
+    },
+    Disconnect {
+        peer: PeerId,
+        reply: oneshot::Sender<bool>,
+    },
+    ListenAddrs {
+        reply: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    KnownPeers {
+        reply: oneshot::Sender<Vec<PeerInfo>>,
+    },
+    RoutingTable {
+        reply: oneshot::Sender<Vec<RoutingEntry>>,
+    },
+    BannedPeers {
+        reply: oneshot::Sender<Vec<PeerId>>,
+    },
+    QueueDepths {
+        reply: oneshot::Sender<HashMap<PeerId, QueueDepth>>,
+    },
+    /// Sent by the peer manager task with the known `peers` a
+    /// [`Broadcast`](Self::Broadcast) goes to.
+    BroadcastTo {
+        peers: Vec<PeerId>,
+        message: M,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Sent by the peer manager task with the addresses a
+    /// [`DialPeer`](Self::DialPeer) tries.
+    DialCandidates {
+        peer: PeerId,
+        candidates: Vec<Multiaddr>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Sent by the node itself once a key rotation from `old` was verified.
+    KeyRotated {
+        old: PeerId,
+        new: PeerId,
+    },
+    /// Sent by the peer manager task once `peer` missed `missed` pings in
+    /// a row.
+    MissedPings {
+        peer: PeerId,
+        missed: u32,
+    },
+}
+
+/// Cloneable handle for talking to a [`Node`](crate::p2plane::network::Node)
+/// while its event loop is running in `start()`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use narwhal::p2plane::network::{Node, NodeConfig};
+/// # use narwhal::p2plane::traits::Message;
+/// # use libp2p::kad::RecordKey;
+/// # #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+/// # struct MyMessage(String);
+/// # impl Message for MyMessage {
+/// #     fn protocol_id(&self) -> &'static str { "/my-app/1.0.0" }
+/// # }
+/// # async fn run() -> narwhal::p2plane::Result<()> {
+/// let mut node = Node::<MyMessage>::new(NodeConfig::default()).await?;
+/// let handle = node.handle();
+/// tokio::spawn(async move { node.start().await.ok() });
+///
+/// handle.start_providing(RecordKey::new(&"batch-digest")).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct NodeHandle<M: Message> {
+    keypair: Keypair,
+    local_peer_id: PeerId,
+    commands: UnboundedSender<Command<M>>,
+    events: broadcast::Sender<NodeEvent<M>>,
+    peer_events: broadcast::Sender<PeerEvent>,
+    streams: StreamControl,
+    blobs: BlobStore,
+}
+
+impl<M: Message> Clone for NodeHandle<M> {
+    fn clone(&self) -> Self {
+        Self {
+            keypair: self.keypair.clone(),
+            local_peer_id: self.local_peer_id,
+            commands: self.commands.clone(),
+            events: self.events.clone(),
+            peer_events: self.peer_events.clone(),
+            streams: self.streams.clone(),
+            blobs: self.blobs.clone(),
+        }
+    }
+}
+
+impl<M: Message> NodeHandle<M> {
+    pub(crate) fn new(
+        keypair: Keypair,
+        commands: UnboundedSender<Command<M>>,
+        events: broadcast::Sender<NodeEvent<M>>,
+        peer_events: broadcast::Sender<PeerEvent>,
+        streams: StreamControl,
+        blobs: BlobStore,
+    ) -> Self {
+        Self {
+            local_peer_id: keypair.public().to_peer_id(),
+            keypair,
+            commands,
+            events,
+            peer_events,
+            streams,
+            blobs,
+        }
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Subscribes to the events published by the node from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent<M>> {
+        self.events.subscribe()
+    }
+
+    /// Subscribes to changes to the known peers from now on.
+    pub fn subscribe_peer_events(&self) -> broadcast::Receiver<PeerEvent> {
+        self.peer_events.subscribe()
+    }
+
+    fn send(&self, command: Command<M>) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Queues `message` for every known peer. Under
+    /// [`OverflowPolicy::Block`](crate::p2plane::OverflowPolicy::Block) this
+    /// waits until the message fits in the queue of every peer. Under
+    /// `Error` the message is still queued for the peers with room, and the
+    /// first full queue is reported.
+    pub async fn broadcast_message(&self, message: M) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Broadcast { message, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)?
+    }
+
+    /// Queues `message` for a single peer, which must be connected or have
+    /// a known address. Waits for room like
+    /// [`broadcast_message`](Self::broadcast_message).
+    pub async fn send_message(&self, peer: PeerId, message: M) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::SendMessage {
+            peer,
+            message,
+            reply,
+        })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)?
+    }
+
+    /// Announces the local node as a provider of `key` on the DHT.
+    pub async fn start_providing(&self, key: RecordKey) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::StartProviding {
+            key,
+            content: None,
+            reply,
+        })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)?
+    }
+
+    /// Stores `content` locally so it can be served to `fetch` requests and
+    /// announces the local node as a provider of `key`.
+    pub async fn provide_content(&self, key: RecordKey, content: Vec<u8>) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::StartProviding {
+            key,
+            content: Some(content),
+            reply,
+        })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)?
+    }
+
+    /// Stops announcing `key` and drops any content stored for it.
+    pub fn stop_providing(&self, key: RecordKey) -> Result<()> {
+        self.send(Command::StopProviding { key })
+    }
+
+    /// Looks up the providers of `key`. The returned stream yields each
+    /// provider once and ends when the DHT query completes.
+    pub fn get_providers(&self, key: RecordKey) -> Result<mpsc::UnboundedReceiver<PeerId>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.send(Command::GetProviders { key, sender })?;
+        Ok(receiver)
+    }
+
+    /// Retrieves the content stored under `key` from one of its providers.
+    /// Resolves to `None` if no provider could serve it.
+    pub async fn fetch(&self, key: RecordKey) -> Result<Option<Vec<u8>>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Fetch { key, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Returns the addresses, smoothed RTT, missed-ping count and Identify
+    /// metadata recorded for `peer`.
+    pub async fn peer_info(&self, peer: PeerId) -> Result<Option<PeerInfo>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PeerInfo { peer, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Dials `addr`. Resolves once the dial has been started; connection
+    /// success is reported through [`NodeEvent::PeerConnected`].
+    pub async fn dial(&self, addr: Multiaddr) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Dial { addr, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)?
+    }
+
+    /// Returns the known peers that announced support for `protocol` over
+    /// Identify.
+    pub async fn peers_supporting(&self, protocol: impl Into<String>) -> Result<Vec<PeerId>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PeersSupporting {
+            protocol: protocol.into(),
+            reply,
+        })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Dials `peer` on the addresses recorded for it, trying the ones that
+    /// worked most recently first.
+    pub async fn dial_peer(&self, peer: PeerId) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::DialPeer { peer, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)?
+    }
+
+    /// Forgets `peer` in the peer manager and the Kademlia routing table.
+    /// Returns true if the peer manager knew it.
+    pub async fn remove_peer(&self, peer: PeerId) -> Result<bool> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::RemovePeer { peer, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Closes all connections to `peer` and refuses new ones until
+    /// [`unban_peer`](Self::unban_peer).
+    pub fn ban_peer(&self, peer: PeerId) -> Result<()> {
+        self.send(Command::BanPeer { peer })
+    }
+
+    pub fn unban_peer(&self, peer: PeerId) -> Result<()> {
+        self.send(Command::UnbanPeer { peer })
+    }
+
+    /// Never closes the connections to `peer` for being idle, like the
+    /// peers of `NodeConfig::keep_alive`.
+    pub fn keep_alive(&self, peer: PeerId) -> Result<()> {
+        self.send(Command::KeepAlive { peer })
+    }
+
+    pub fn release_keep_alive(&self, peer: PeerId) -> Result<()> {
+        self.send(Command::ReleaseKeepAlive { peer })
+    }
+
+    /// Returns the peers the node currently has at least one connection to.
+    pub async fn connected_peers(&self) -> Result<Vec<PeerId>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ConnectedPeers { reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Closes all connections to `peer`. Returns false if there were none.
+    pub async fn disconnect(&self, peer: PeerId) -> Result<bool> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Disconnect { peer, reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Returns the addresses the node is listening on.
+    pub async fn listen_addrs(&self) -> Result<Vec<Multiaddr>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ListenAddrs { reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Returns what the peer manager knows about each known peer.
+    pub async fn known_peers(&self) -> Result<Vec<PeerInfo>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::KnownPeers { reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Returns the peers in the Kademlia routing table.
+    pub async fn routing_table(&self) -> Result<Vec<RoutingEntry>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::RoutingTable { reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    pub async fn banned_peers(&self) -> Result<Vec<PeerId>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::BannedPeers { reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Depth of the outbound queue of every peer messages were sent to.
+    pub async fn queue_depths(&self) -> Result<HashMap<PeerId, QueueDepth>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::QueueDepths { reply })?;
+        rx.await.map_err(|_| P2PlaneError::NodeStopped)
+    }
+
+    /// Opens a stream to `peer` speaking `protocol`, dialing the peer if it
+    /// is not connected. The peer must [accept](Self::accept_streams) the
+    /// protocol.
+    pub async fn open_stream(&self, peer: PeerId, protocol: StreamProtocol) -> Result<Stream> {
+        self.streams.open_stream(peer, protocol).await
+    }
+
+    /// Accepts the streams peers open for `protocol` from now on.
+    pub fn accept_streams(&self, protocol: StreamProtocol) -> Result<IncomingStreams> {
+        self.streams.accept(protocol)
+    }
+
+    /// The blobs this node serves to peers.
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    /// Pushes `data` to `peer` in verified chunks. If the transfer is
+    /// interrupted it is retried, sending only the chunks the peer is still
+    /// missing. The blob is also kept locally so others can fetch it. Fails
+    /// if `peer` does not accept the blob; see [`BlobStore::accept_puts`].
+    pub async fn send_blob(&self, peer: PeerId, data: Vec<u8>) -> Result<BlobId> {
+        blob::send_blob(&self.streams, &self.blobs, peer, data).await
+    }
+
+    /// Fetches blob `id` from `peer`, verifying every chunk and resuming
+    /// where an interrupted fetch left off. Resolves to `None` if the peer
+    /// does not have the blob.
+    pub async fn fetch_blob(&self, peer: PeerId, id: BlobId) -> Result<Option<Vec<u8>>> {
+        blob::fetch_blob(&self.streams, &self.blobs, peer, id).await
+    }
+
+    /// Tells every known and connected peer that this node is about to
+    /// switch to the identity `new`, see [`identity`]. Returns the peers
+    /// that accepted the announcement; unreachable peers are skipped.
+    pub async fn announce_key_rotation(&self, new: &Keypair) -> Result<Vec<PeerId>> {
+        let rotation = KeyRotation::new(&self.keypair, new)?;
+        let mut peers: HashSet<PeerId> = self.connected_peers().await?.into_iter().collect();
+        peers.extend(self.known_peers().await?.into_iter().map(|info| info.peer_id));
+        let announcements = peers.into_iter().map(|peer| {
+            let rotation = &rotation;
+            async move {
+                match tokio::time::timeout(
+                    ROTATION_TIMEOUT,
+                    identity::announce(&self.streams, peer, rotation),
+                )
+                .await
+                {
+                    Ok(Ok(())) => Some(peer),
+                    Ok(Err(e)) => {
+                        debug!(
+                            "Announcing the key rotation to {} failed: {}",
+                            peer,
+                            ErrorChain(&e)
+                        );
+                        None
+                    }
+                    Err(_) => {
+                        debug!("Announcing the key rotation to {} timed out", peer);
+                        None
+                    }
+                }
+            }
+        });
+        Ok(future::join_all(announcements)
+            .await
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+}
@@ -0,0 +1,650 @@
+//! Node identities: key types, key files, encrypted keystores and key
+//! rotation.
+//!
+//! [`IdentityConfig`] chooses the key type of the node and where its key is
+//! kept. A key file may hold
+//!
+//! - a protobuf-encoded libp2p keypair, as written by `p2plane key generate`,
+//! - a raw 32-byte secret key of the configured type, either binary or as
+//!   hex text (an optional `0x` prefix is allowed),
+//! - for secp256k1, a DER-encoded private key, or
+//! - a passphrase-encrypted [`Keystore`].
+//!
+//! The passphrase of a keystore is read from the environment variable named
+//! by `passphrase_env`, so it never ends up in config files.
+//!
+//! # Rotating a key
+//!
+//! A node cannot change its identity while it runs, so rotation happens in
+//! two steps. [`IdentityConfig::rotate`] generates a new key, replaces the
+//! key file, keeping the old key next to it with an `.old` suffix, and then
+//! tells all known peers about the new key with a [`KeyRotation`] signed by
+//! both keys. The node is then restarted with the new key. Peers that accepted
+//! the announcement move the addresses of the old peer id to the new one and
+//! report a [`PeerEvent::KeyRotated`](crate::p2plane::PeerEvent::KeyRotated).
+
+use crate::p2plane::{
+    blob::{read_frame, write_frame},
+    handle::NodeHandle,
+    hex,
+    stream::StreamControl,
+    traits::Message,
+    ErrorChain, P2PlaneError, Result,
+};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use libp2p::{
+    futures::{AsyncReadExt, AsyncWriteExt},
+    identity::{ecdsa, ed25519, secp256k1, Keypair, PublicKey},
+    PeerId, Stream, StreamProtocol,
+};
+use log::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Protocol key rotations are announced on.
+pub const KEY_ROTATION_PROTOCOL: &str = "/p2plane/key-rotation/1.0.0";
+
+/// Environment variable keystore passphrases are read from by default.
+pub const DEFAULT_PASSPHRASE_ENV: &str = "P2PLANE_KEY_PASSPHRASE";
+
+/// scrypt cost used for new keystores: 2^15 iterations, 32 MiB of memory.
+pub const DEFAULT_SCRYPT_LOG_N: u8 = 15;
+
+/// Largest scrypt cost accepted from a keystore file.
+const MAX_SCRYPT_LOG_N: u8 = 22;
+
+const KEYSTORE_VERSION: u32 = 1;
+
+/// Upper bound on the size of an encoded [`KeyRotation`].
+const MAX_ROTATION_SIZE: usize = 4096;
+
+/// Prefix of the bytes both keys sign in a [`KeyRotation`].
+const ROTATION_DOMAIN: &[u8] = b"p2plane-key-rotation:";
+
+/// Signature algorithm of a node identity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    /// ECDSA on the NIST P-256 curve.
+    Ecdsa,
+}
+
+impl KeyType {
+    pub fn generate(self) -> Keypair {
+        match self {
+            KeyType::Ed25519 => Keypair::generate_ed25519(),
+            KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+            KeyType::Ecdsa => Keypair::generate_ecdsa(),
+        }
+    }
+
+    /// Type of `keypair`, or `None` for RSA keys.
+    pub fn of(keypair: &Keypair) -> Option<Self> {
+        match keypair.key_type() {
+            libp2p::identity::KeyType::Ed25519 => Some(KeyType::Ed25519),
+            libp2p::identity::KeyType::Secp256k1 => Some(KeyType::Secp256k1),
+            libp2p::identity::KeyType::Ecdsa => Some(KeyType::Ecdsa),
+            _ => None,
+        }
+    }
+
+    /// Builds a keypair from a raw 32-byte secret key.
+    pub fn from_secret(self, mut secret: Vec<u8>) -> Result<Keypair> {
+        let invalid = |e| P2PlaneError::Config {
+            message: format!("invalid {} secret key", self),
+            source: Some(Box::new(e)),
+        };
+        Ok(match self {
+            KeyType::Ed25519 => {
+                let secret = ed25519::SecretKey::try_from_bytes(&mut secret).map_err(invalid)?;
+                ed25519::Keypair::from(secret).into()
+            }
+            KeyType::Secp256k1 => {
+                let secret = secp256k1::SecretKey::try_from_bytes(&mut secret).map_err(invalid)?;
+                secp256k1::Keypair::from(secret).into()
+            }
+            KeyType::Ecdsa => {
+                let secret = ecdsa::SecretKey::try_from_bytes(&secret).map_err(invalid)?;
+                ecdsa::Keypair::from(secret).into()
+            }
+        })
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Secp256k1 => "secp256k1",
+            KeyType::Ecdsa => "ecdsa",
+        })
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = P2PlaneError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            _ => Err(P2PlaneError::config(format!(
+                "unknown key type {:?}, expected ed25519, secp256k1 or ecdsa",
+                s
+            ))),
+        }
+    }
+}
+
+/// Where the identity of a node comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub key_type: KeyType,
+    /// Key file, relative to `data_dir` unless absolute. It is created with
+    /// a new key of `key_type` if it does not exist. Without a key file the
+    /// node gets a new identity on every start.
+    pub key_file: Option<PathBuf>,
+    /// Environment variable holding the keystore passphrase. New key files
+    /// are encrypted when it is set. Other `P2PLANE_*` names than the
+    /// default would be read as config overrides as well.
+    pub passphrase_env: String,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            key_type: KeyType::default(),
+            key_file: None,
+            passphrase_env: DEFAULT_PASSPHRASE_ENV.to_string(),
+        }
+    }
+}
+
+impl IdentityConfig {
+    /// Path of the key file, if any, resolved against `data_dir`.
+    pub fn key_path(&self, data_dir: &Path) -> Option<PathBuf> {
+        self.key_file.as_ref().map(|file| data_dir.join(file))
+    }
+
+    /// Reads the keystore passphrase from the environment.
+    pub fn passphrase(&self) -> Option<String> {
+        std::env::var(&self.passphrase_env)
+            .ok()
+            .filter(|passphrase| !passphrase.is_empty())
+    }
+
+    /// Loads the node's keypair, creating the key file on first use.
+    pub fn load(&self, data_dir: &Path) -> Result<Keypair> {
+        self.load_with_passphrase(data_dir, self.passphrase().as_deref())
+    }
+
+    /// Like [`IdentityConfig::load`], but with the keystore passphrase given
+    /// instead of read from the environment.
+    pub fn load_with_passphrase(
+        &self,
+        data_dir: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<Keypair> {
+        let Some(path) = self.key_path(data_dir) else {
+            return Ok(self.key_type.generate());
+        };
+        if !path.exists() {
+            let keypair = self.key_type.generate();
+            info!(
+                "Generated {} key {} in {}",
+                self.key_type,
+                keypair.public().to_peer_id(),
+                path.display()
+            );
+            save_key(&path, &keypair, passphrase)?;
+            return Ok(keypair);
+        }
+
+        let data = fs::read(&path).map_err(|e| P2PlaneError::Config {
+            message: format!("failed to read key file {}", path.display()),
+            source: Some(Box::new(e)),
+        })?;
+        let keypair = decode_key(&data, self.key_type, passphrase).map_err(|e| match e {
+            P2PlaneError::Config { message, source } => P2PlaneError::Config {
+                message: format!("{}: {}", path.display(), message),
+                source,
+            },
+            e => e,
+        })?;
+        match KeyType::of(&keypair) {
+            Some(key_type) if key_type == self.key_type => Ok(keypair),
+            key_type => Err(P2PlaneError::config(format!(
+                "{} holds a {} key, but identity.key_type is {}",
+                path.display(),
+                key_type.map_or("RSA".to_string(), |t| t.to_string()),
+                self.key_type
+            ))),
+        }
+    }
+
+    /// Replaces the node's key with a new one of `key_type`, see the
+    /// [module documentation](self). The new key is encrypted if a
+    /// passphrase is set, and announced to the known peers of `handle` once
+    /// it is safely on disk. If any step fails the old key file is put back.
+    /// Returns the new keypair to restart the node with.
+    pub async fn rotate<M: Message>(
+        &self,
+        data_dir: &Path,
+        handle: &NodeHandle<M>,
+    ) -> Result<Keypair> {
+        let path = self
+            .key_path(data_dir)
+            .ok_or_else(|| P2PlaneError::config("key rotation requires identity.key_file"))?;
+        let new = self.key_type.generate();
+        let staged = with_suffix(&path, ".new");
+        let old = with_suffix(&path, ".old");
+        if let Err(e) = save_key(&staged, &new, self.passphrase().as_deref())
+            .and_then(|()| replace_file(&path, &staged, &old))
+        {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+
+        let notified = match handle.announce_key_rotation(&new).await {
+            Ok(notified) => notified,
+            Err(e) => {
+                // No peer knows the new key, so the node keeps the old one.
+                let _ = fs::rename(&old, &path);
+                return Err(e);
+            }
+        };
+        info!(
+            "Announced new identity {} to {} peers",
+            new.public().to_peer_id(),
+            notified.len()
+        );
+        Ok(new)
+    }
+}
+
+/// Decodes the contents of a key file, see the [module documentation](self)
+/// for the accepted formats. Raw secret keys are read as `key_type`.
+pub fn decode_key(data: &[u8], key_type: KeyType, passphrase: Option<&str>) -> Result<Keypair> {
+    if let Ok(keystore) = serde_json::from_slice::<Keystore>(data) {
+        let passphrase = passphrase.ok_or_else(|| {
+            P2PlaneError::config("the key file is encrypted, but no passphrase is set")
+        })?;
+        return keystore.decrypt(passphrase);
+    }
+    if let Some(secret) = std::str::from_utf8(data).ok().and_then(|text| {
+        let text = text.trim();
+        hex::decode(text.strip_prefix("0x").unwrap_or(text))
+    }) {
+        return key_type.from_secret(secret);
+    }
+    if data.len() == 32 {
+        return key_type.from_secret(data.to_vec());
+    }
+    if let Ok(keypair) = Keypair::from_protobuf_encoding(data) {
+        return Ok(keypair);
+    }
+    if key_type == KeyType::Secp256k1 {
+        if let Ok(secret) = secp256k1::SecretKey::from_der(data.to_vec()) {
+            return Ok(secp256k1::Keypair::from(secret).into());
+        }
+    }
+    Err(P2PlaneError::config(format!(
+        "not a keystore, protobuf keypair or raw {} secret key",
+        key_type
+    )))
+}
+
+/// Writes `keypair` to `path` so that only the current user can read it,
+/// as a [`Keystore`] if a passphrase is given and protobuf-encoded
+/// otherwise.
+pub fn save_key(path: &Path, keypair: &Keypair, passphrase: Option<&str>) -> Result<()> {
+    let data = match passphrase {
+        Some(passphrase) => Keystore::encrypt(keypair, passphrase)?
+            .to_json()?
+            .into_bytes(),
+        None => keypair
+            .to_protobuf_encoding()
+            .map_err(P2PlaneError::storage)?,
+    };
+    write_private(path, &data)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(suffix);
+    path.into()
+}
+
+/// Moves `staged` to `path`, keeping the file at `path` as `old`. The file
+/// at `path` is put back if any step fails.
+fn replace_file(path: &Path, staged: &Path, old: &Path) -> Result<()> {
+    fs::rename(path, old).map_err(P2PlaneError::storage)?;
+    if let Err(e) = fs::rename(staged, path).and_then(|()| sync_parent(path)) {
+        let _ = fs::rename(old, path);
+        return Err(P2PlaneError::storage(e));
+    }
+    Ok(())
+}
+
+/// Makes renames in the directory of `path` durable.
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes a file only the current user can read, creating its directory,
+/// and waits until it is on disk.
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).map_err(P2PlaneError::storage)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .map_err(P2PlaneError::storage)
+}
+
+/// Key derivation function of a [`Keystore`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase", deny_unknown_fields)]
+enum Kdf {
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+        #[serde(with = "hex_bytes")]
+        salt: Vec<u8>,
+    },
+}
+
+/// Authenticated cipher of a [`Keystore`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "name", deny_unknown_fields)]
+enum Cipher {
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305 {
+        #[serde(with = "hex_bytes")]
+        nonce: Vec<u8>,
+    },
+}
+
+/// Passphrase-encrypted key file.
+///
+/// The protobuf-encoded keypair is encrypted with ChaCha20-Poly1305 under a
+/// key derived from the passphrase with scrypt. The peer id and key type are
+/// stored in the clear, so a keystore can be identified without the
+/// passphrase, and are authenticated along with the key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keystore {
+    version: u32,
+    key_type: KeyType,
+    peer_id: PeerId,
+    kdf: Kdf,
+    cipher: Cipher,
+    #[serde(with = "hex_bytes")]
+    ciphertext: Vec<u8>,
+}
+
+impl Keystore {
+    pub fn encrypt(keypair: &Keypair, passphrase: &str) -> Result<Self> {
+        Self::encrypt_with_cost(keypair, passphrase, DEFAULT_SCRYPT_LOG_N)
+    }
+
+    /// Encrypts with a scrypt cost of 2^`log_n` iterations.
+    pub fn encrypt_with_cost(keypair: &Keypair, passphrase: &str, log_n: u8) -> Result<Self> {
+        let key_type = KeyType::of(keypair)
+            .ok_or_else(|| P2PlaneError::config("RSA keys cannot be stored in a keystore"))?;
+        let peer_id = keypair.public().to_peer_id();
+        let mut salt = vec![0; 32];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = vec![0; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let kdf = Kdf::Scrypt {
+            log_n,
+            r: scrypt::Params::RECOMMENDED_R,
+            p: scrypt::Params::RECOMMENDED_P,
+            salt,
+        };
+
+        let plaintext = keypair
+            .to_protobuf_encoding()
+            .map_err(P2PlaneError::storage)?;
+        let ciphertext = kdf
+            .cipher(passphrase)?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &peer_id.to_bytes(),
+                },
+            )
+            .map_err(|_| P2PlaneError::storage("encrypting the key failed"))?;
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            key_type,
+            peer_id,
+            kdf,
+            cipher: Cipher::ChaCha20Poly1305 { nonce },
+            ciphertext,
+        })
+    }
+
+    /// Decrypts the keypair. Fails on a wrong passphrase or if the file was
+    /// modified.
+    pub fn decrypt(&self, passphrase: &str) -> Result<Keypair> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(P2PlaneError::config(format!(
+                "unsupported keystore version {}",
+                self.version
+            )));
+        }
+        let Cipher::ChaCha20Poly1305 { nonce } = &self.cipher;
+        if nonce.len() != 12 {
+            return Err(P2PlaneError::config("keystore nonce must be 12 bytes"));
+        }
+        let plaintext = self
+            .kdf
+            .cipher(passphrase)?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &self.peer_id.to_bytes(),
+                },
+            )
+            .map_err(|_| P2PlaneError::config("wrong passphrase or corrupted keystore"))?;
+        let keypair = Keypair::from_protobuf_encoding(&plaintext).map_err(P2PlaneError::storage)?;
+        if keypair.public().to_peer_id() != self.peer_id
+            || KeyType::of(&keypair) != Some(self.key_type)
+        {
+            return Err(P2PlaneError::config(
+                "keystore key does not match its peer id",
+            ));
+        }
+        Ok(keypair)
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| P2PlaneError::Codec(Box::new(e)))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| P2PlaneError::Codec(Box::new(e)))
+    }
+}
+
+impl Kdf {
+    fn cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305> {
+        let Kdf::Scrypt { log_n, r, p, salt } = self;
+        if *log_n > MAX_SCRYPT_LOG_N {
+            return Err(P2PlaneError::config(format!(
+                "scrypt cost 2^{} exceeds the limit of 2^{}",
+                log_n, MAX_SCRYPT_LOG_N
+            )));
+        }
+        let params = scrypt::Params::new(*log_n, *r, *p, 32).map_err(|e| P2PlaneError::Config {
+            message: "invalid scrypt parameters".to_string(),
+            source: Some(Box::new(e)),
+        })?;
+        let mut key = [0; 32];
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+            .map_err(|e| P2PlaneError::storage(e.to_string()))?;
+        Ok(ChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+/// Statement that the node with key `old_key` now uses `new_key`, signed
+/// by both keys so that neither can be claimed by someone else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRotation {
+    #[serde(with = "hex_bytes")]
+    old_key: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    new_key: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    old_signature: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    new_signature: Vec<u8>,
+}
+
+impl KeyRotation {
+    pub fn new(old: &Keypair, new: &Keypair) -> Result<Self> {
+        let payload = rotation_payload(&old.public().to_peer_id(), &new.public().to_peer_id());
+        let sign = |key: &Keypair| key.sign(&payload).map_err(P2PlaneError::storage);
+        Ok(Self {
+            old_key: old.public().encode_protobuf(),
+            new_key: new.public().encode_protobuf(),
+            old_signature: sign(old)?,
+            new_signature: sign(new)?,
+        })
+    }
+
+    /// Checks both signatures and returns the old and the new peer id.
+    pub fn verify(&self) -> Result<(PeerId, PeerId)> {
+        let decode = |key: &[u8]| {
+            PublicKey::try_decode_protobuf(key).map_err(|e| P2PlaneError::Codec(Box::new(e)))
+        };
+        let old_key = decode(&self.old_key)?;
+        let new_key = decode(&self.new_key)?;
+        let (old, new) = (old_key.to_peer_id(), new_key.to_peer_id());
+        let payload = rotation_payload(&old, &new);
+        if old == new
+            || !old_key.verify(&payload, &self.old_signature)
+            || !new_key.verify(&payload, &self.new_signature)
+        {
+            return Err(P2PlaneError::Codec("invalid key rotation signature".into()));
+        }
+        Ok((old, new))
+    }
+}
+
+fn rotation_payload(old: &PeerId, new: &PeerId) -> Vec<u8> {
+    let mut payload = ROTATION_DOMAIN.to_vec();
+    payload.extend(old.to_bytes());
+    payload.extend(new.to_bytes());
+    payload
+}
+
+/// Sends `rotation` to `peer`, dialing it if needed.
+pub(crate) async fn announce(
+    streams: &StreamControl,
+    peer: PeerId,
+    rotation: &KeyRotation,
+) -> Result<()> {
+    let mut stream = streams
+        .open_stream(peer, StreamProtocol::new(KEY_ROTATION_PROTOCOL))
+        .await?;
+    let data = serde_json::to_vec(rotation).map_err(|e| P2PlaneError::Codec(Box::new(e)))?;
+    write_frame(&mut stream, &data).await?;
+    stream.flush().await.map_err(P2PlaneError::stream)?;
+    // The receiver closes the stream once it has checked the rotation.
+    let mut ack = [0; 1];
+    stream
+        .read_exact(&mut ack)
+        .await
+        .map_err(P2PlaneError::stream)?;
+    if ack[0] != 1 {
+        return Err(P2PlaneError::stream(format!(
+            "{} rejected the key rotation",
+            peer
+        )));
+    }
+    let _ = stream.close().await;
+    Ok(())
+}
+
+/// Reads a rotation announced by `peer` and acknowledges it if it is valid
+/// and signed by `peer`'s own key. Returns the new peer id.
+pub(crate) async fn receive(peer: PeerId, mut stream: Stream) -> Result<PeerId> {
+    let data = read_frame(&mut stream, MAX_ROTATION_SIZE).await?;
+    let result = serde_json::from_slice::<KeyRotation>(&data)
+        .map_err(|e| P2PlaneError::Codec(Box::new(e)))
+        .and_then(|rotation| rotation.verify())
+        .and_then(|(old, new)| {
+            if old == peer {
+                Ok(new)
+            } else {
+                Err(P2PlaneError::Codec(
+                    format!("{} announced a key rotation for {}", peer, old).into(),
+                ))
+            }
+        });
+    if let Err(e) = &result {
+        warn!("Rejected key rotation from {}: {}", peer, ErrorChain(e));
+    }
+    let _ = stream.write_all(&[result.is_ok() as u8]).await;
+    let _ = stream.close().await;
+    result
+}
+
+/// Serializes byte fields as hex strings.
+mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(&s).ok_or_else(|| serde::de::Error::custom("invalid hex string"))
+    }
+}
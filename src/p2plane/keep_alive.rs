@@ -0,0 +1,233 @@
+use libp2p::{
+    core::{upgrade::DeniedUpgrade, Endpoint},
+    multiaddr::Protocol,
+    swarm::{
+        behaviour::{ConnectionClosed, ConnectionEstablished},
+        handler::ConnectionEvent,
+        ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+        NetworkBehaviour, NotifyHandler, SubstreamProtocol, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+/// Peers whose connections are never closed for being idle.
+///
+/// Connections to everyone else are closed once no streams have been open
+/// on them for `NodeConfig::idle_connection_timeout`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepAlivePolicy {
+    /// Keep the connection to the bootstrap peer open.
+    pub bootstrap: bool,
+    /// Further peers to stay connected to, such as committee members.
+    pub peers: HashSet<PeerId>,
+}
+
+impl Default for KeepAlivePolicy {
+    fn default() -> Self {
+        Self {
+            bootstrap: true,
+            peers: HashSet::new(),
+        }
+    }
+}
+
+/// [`NetworkBehaviour`] that keeps the connections to the peers of a
+/// [`KeepAlivePolicy`] open.
+///
+/// The bootstrap peer is recognised by its peer id if the bootstrap address
+/// ends in `/p2p/<peer-id>`, and otherwise by the address it was dialed on.
+#[derive(Debug, Default)]
+pub struct KeepAlive {
+    peers: HashSet<PeerId>,
+    bootstrap_addr: Option<Multiaddr>,
+    connections: HashMap<PeerId, HashSet<ConnectionId>>,
+    pending: VecDeque<ToSwarm<Infallible, bool>>,
+}
+
+impl KeepAlive {
+    pub fn new(policy: KeepAlivePolicy, bootstrap_addr: Option<&Multiaddr>) -> Self {
+        let mut peers = policy.peers;
+        let mut bootstrap = None;
+        if policy.bootstrap {
+            if let Some(addr) = bootstrap_addr {
+                if let Some(Protocol::P2p(peer)) = addr.iter().last() {
+                    peers.insert(peer);
+                }
+                bootstrap = Some(without_peer_id(addr));
+            }
+        }
+        Self {
+            peers,
+            bootstrap_addr: bootstrap,
+            ..Default::default()
+        }
+    }
+
+    /// Keeps the connections to `peer` open from now on.
+    pub fn keep(&mut self, peer: PeerId) {
+        if self.peers.insert(peer) {
+            self.notify(peer, true);
+        }
+    }
+
+    /// Lets the connections to `peer` close again once they are idle.
+    pub fn release(&mut self, peer: &PeerId) {
+        if self.peers.remove(peer) {
+            self.notify(*peer, false);
+        }
+    }
+
+    pub fn is_kept(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    fn notify(&mut self, peer: PeerId, keep_alive: bool) {
+        for connection in self.connections.get(&peer).into_iter().flatten() {
+            self.pending.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                handler: NotifyHandler::One(*connection),
+                event: keep_alive,
+            });
+        }
+    }
+}
+
+fn without_peer_id(addr: &Multiaddr) -> Multiaddr {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr
+}
+
+impl NetworkBehaviour for KeepAlive {
+    type ConnectionHandler = Handler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler {
+            keep_alive: self.is_kept(&peer),
+        })
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        if self.bootstrap_addr.as_ref() == Some(&without_peer_id(addr)) {
+            self.peers.insert(peer);
+        }
+        Ok(Handler {
+            keep_alive: self.is_kept(&peer),
+        })
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                self.connections
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(connection_id);
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                if let Some(connections) = self.connections.get_mut(&peer_id) {
+                    connections.remove(&connection_id);
+                    if connections.is_empty() {
+                        self.connections.remove(&peer_id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        match self.pending.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Connection handler of [`KeepAlive`]. It opens no streams and only
+/// reports whether its connection should be kept open.
+#[derive(Debug)]
+pub struct Handler {
+    keep_alive: bool,
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = bool;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Infallible;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
+    > {
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    fn on_connection_event(
+        &mut self,
+        _: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+    }
+}
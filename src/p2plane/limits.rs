@@ -0,0 +1,290 @@
+use libp2p::{
+    core::{ConnectedPoint, Endpoint},
+    swarm::{
+        behaviour::{ConnectionEstablished, DialFailure, ListenFailure},
+        dummy, ConnectionClosed, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+        THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    task::{Context, Poll},
+};
+
+/// Limits on the number of connections a node accepts or opens.
+///
+/// `None` means unlimited. The last `reserved_slots` of every established
+/// limit are only handed out to `reserved_peers`, so allowlisted and
+/// bootstrap peers can still connect when the node is otherwise full.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionLimits {
+    pub max_established: Option<u32>,
+    pub max_established_per_peer: Option<u32>,
+    pub max_established_incoming: Option<u32>,
+    pub max_established_outgoing: Option<u32>,
+    pub max_pending_incoming: Option<u32>,
+    pub max_pending_outgoing: Option<u32>,
+    pub reserved_slots: u32,
+    pub reserved_peers: HashSet<PeerId>,
+}
+
+/// Error returned when a connection is refused because a limit was reached.
+#[derive(Debug, Clone, Copy)]
+pub struct LimitExceeded {
+    pub limit: u32,
+    pub kind: LimitKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    PendingIncoming,
+    PendingOutgoing,
+    EstablishedIncoming,
+    EstablishedOutgoing,
+    EstablishedPerPeer,
+    EstablishedTotal,
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKind::PendingIncoming => write!(f, "pending incoming connections"),
+            LimitKind::PendingOutgoing => write!(f, "pending outgoing connections"),
+            LimitKind::EstablishedIncoming => write!(f, "established incoming connections"),
+            LimitKind::EstablishedOutgoing => write!(f, "established outgoing connections"),
+            LimitKind::EstablishedPerPeer => write!(f, "established connections per peer"),
+            LimitKind::EstablishedTotal => write!(f, "established connections"),
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection limit exceeded: at most {} {} are allowed",
+            self.limit, self.kind
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// [`NetworkBehaviour`] that enforces [`ConnectionLimits`] and admits
+/// reserved peers into the slots held back for them.
+#[derive(Debug, Default)]
+pub struct AdmissionControl {
+    limits: ConnectionLimits,
+    pending_inbound: HashSet<ConnectionId>,
+    pending_outbound: HashSet<ConnectionId>,
+    established_inbound: HashSet<ConnectionId>,
+    established_outbound: HashSet<ConnectionId>,
+    established_per_peer: HashMap<PeerId, HashSet<ConnectionId>>,
+}
+
+impl AdmissionControl {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Lets `peer` use the reserved slots from now on.
+    pub fn add_reserved_peer(&mut self, peer: PeerId) {
+        self.limits.reserved_peers.insert(peer);
+    }
+
+    pub fn remove_reserved_peer(&mut self, peer: &PeerId) {
+        self.limits.reserved_peers.remove(peer);
+    }
+
+    pub fn established_connections(&self) -> usize {
+        self.established_inbound.len() + self.established_outbound.len()
+    }
+
+    /// Checks `current` against `limit`, keeping the reserved slots free
+    /// unless the connection belongs to a reserved peer.
+    fn check(
+        &self,
+        limit: Option<u32>,
+        current: usize,
+        kind: LimitKind,
+        reserved: bool,
+    ) -> Result<(), ConnectionDenied> {
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        let effective = if reserved {
+            limit
+        } else {
+            limit.saturating_sub(self.limits.reserved_slots)
+        };
+        if current as u32 >= effective {
+            return Err(ConnectionDenied::new(LimitExceeded { limit: effective, kind }));
+        }
+        Ok(())
+    }
+
+    fn check_established(&self, peer: &PeerId, endpoint: Endpoint) -> Result<(), ConnectionDenied> {
+        let reserved = self.limits.reserved_peers.contains(peer);
+        let (limit, current, kind) = match endpoint {
+            Endpoint::Listener => (
+                self.limits.max_established_incoming,
+                self.established_inbound.len(),
+                LimitKind::EstablishedIncoming,
+            ),
+            Endpoint::Dialer => (
+                self.limits.max_established_outgoing,
+                self.established_outbound.len(),
+                LimitKind::EstablishedOutgoing,
+            ),
+        };
+        self.check(limit, current, kind, reserved)?;
+        self.check(
+            self.limits.max_established,
+            self.established_connections(),
+            LimitKind::EstablishedTotal,
+            reserved,
+        )?;
+        // The per-peer limit is about a single peer, so reservations do not
+        // apply to it.
+        self.check(
+            self.limits.max_established_per_peer,
+            self.established_per_peer.get(peer).map_or(0, |c| c.len()),
+            LimitKind::EstablishedPerPeer,
+            true,
+        )
+    }
+}
+
+impl NetworkBehaviour for AdmissionControl {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        // The remote peer is not known yet, so reservations cannot apply.
+        self.check(
+            self.limits.max_pending_incoming,
+            self.pending_inbound.len(),
+            LimitKind::PendingIncoming,
+            true,
+        )?;
+        self.pending_inbound.insert(connection_id);
+        Ok(())
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.pending_inbound.remove(&connection_id);
+        self.check_established(&peer, Endpoint::Listener)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let reserved = peer.is_some_and(|p| self.limits.reserved_peers.contains(&p));
+        self.check(
+            self.limits.max_pending_outgoing,
+            self.pending_outbound.len(),
+            LimitKind::PendingOutgoing,
+            reserved,
+        )?;
+        self.pending_outbound.insert(connection_id);
+        Ok(vec![])
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.pending_outbound.remove(&connection_id);
+        self.check_established(&peer, Endpoint::Dialer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                endpoint,
+                connection_id,
+                ..
+            }) => {
+                match endpoint {
+                    ConnectedPoint::Listener { .. } => {
+                        self.established_inbound.insert(connection_id);
+                    }
+                    ConnectedPoint::Dialer { .. } => {
+                        self.established_outbound.insert(connection_id);
+                    }
+                }
+                self.established_per_peer
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(connection_id);
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                self.established_inbound.remove(&connection_id);
+                self.established_outbound.remove(&connection_id);
+                if let Some(connections) = self.established_per_peer.get_mut(&peer_id) {
+                    connections.remove(&connection_id);
+                    if connections.is_empty() {
+                        self.established_per_peer.remove(&peer_id);
+                    }
+                }
+            }
+            FromSwarm::DialFailure(DialFailure { connection_id, .. }) => {
+                self.pending_outbound.remove(&connection_id);
+            }
+            FromSwarm::ListenFailure(ListenFailure { connection_id, .. }) => {
+                self.pending_inbound.remove(&connection_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
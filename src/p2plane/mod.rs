@@ -1,21 +1,134 @@
+pub mod address_book;
+pub mod admin;
 pub mod behavior;
+pub mod blob;
+pub mod builder;
+pub mod config;
+pub mod content;
+pub mod error;
+pub mod handle;
+pub(crate) mod hex;
+pub mod identity;
+pub mod keep_alive;
+pub mod limits;
 pub mod network;
 pub mod peer_manager;
+pub mod queue;
+pub mod storage;
+pub mod stream;
+#[cfg(feature = "pnet")]
+pub mod swarm_key;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod traits;
 
 #[cfg(test)]
 pub(crate) mod tests;
 
+pub use address_book::{AddressBook, AddressRecord, AddressSource};
+pub use admin::AdminConfig;
 pub use behavior::{Behavior, Event as BehaviorEvent};
-pub use network::{PeerManager, PeerStorage};
-pub use traits::PeerManagement;
+pub use blob::{BlobConfig, BlobId, BlobStore};
+pub use builder::NodeBuilder;
+pub use config::NodeConfig;
+pub use error::{ErrorChain, P2PlaneError};
+pub use handle::{NodeEvent, NodeHandle};
+pub use identity::{IdentityConfig, KeyRotation, KeyType, Keystore};
+pub use keep_alive::KeepAlivePolicy;
+pub use limits::ConnectionLimits;
+pub use network::{PeerManager, PeerStorage, PeerStorageKind};
+pub use queue::{OutboundQueueConfig, OverflowPolicy, Priority, PriorityWeights, QueueDepth};
+pub use storage::{JsonFileStore, MemoryStore, PeerStore, StoredPeer};
+pub use stream::{IncomingStreams, StreamControl};
+pub use traits::{AsyncPeerManagement, PeerManagement};
 
 // Common types used across the library
-use libp2p::PeerId;
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+use libp2p::{identify, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+pub type Result<T, E = P2PlaneError> = std::result::Result<T, E>;
 
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
     pub peer_id: PeerId,
+    /// Known addresses, best first.
     pub addresses: Vec<libp2p::Multiaddr>,
-}
\ No newline at end of file
+    /// Smoothed round-trip time measured with the ping protocol.
+    pub rtt: Option<Duration>,
+    /// Consecutive pings that failed since the last successful one.
+    pub missed_pings: u32,
+    /// What the peer last reported about itself over Identify.
+    pub metadata: Option<PeerMetadata>,
+}
+
+/// Information a peer reports about itself over the Identify protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerMetadata {
+    pub agent_version: String,
+    pub protocol_version: String,
+    /// Protocols the peer accepts streams for.
+    pub protocols: Vec<String>,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Our own address as seen by the peer.
+    pub observed_addr: Multiaddr,
+}
+
+impl PeerMetadata {
+    pub fn supports(&self, protocol: &str) -> bool {
+        self.protocols.iter().any(|p| p == protocol)
+    }
+}
+
+impl From<identify::Info> for PeerMetadata {
+    fn from(info: identify::Info) -> Self {
+        Self {
+            agent_version: info.agent_version,
+            protocol_version: info.protocol_version,
+            protocols: info.protocols.iter().map(ToString::to_string).collect(),
+            listen_addrs: info.listen_addrs,
+            observed_addr: info.observed_addr,
+        }
+    }
+}
+
+/// Peer in the Kademlia routing table, see
+/// [`Node::routing_table`](network::Node::routing_table).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingEntry {
+    pub peer_id: PeerId,
+    /// Index of the k-bucket, i.e. the base-2 logarithm of the XOR distance
+    /// to the local peer id.
+    pub bucket: u32,
+    pub addresses: Vec<Multiaddr>,
+    pub connected: bool,
+}
+
+/// Change to what is known about a peer, see
+/// [`Node::subscribe_peer_events`](network::Node::subscribe_peer_events).
+///
+/// `Added`, `AddressChanged` and `Removed` come from the peer manager, so
+/// custom managers only report them if they expose an event sender. The
+/// others are always sent by the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// A peer the manager did not know before.
+    Added(PeerId),
+    /// The best address of a known peer changed. `None` once no usable
+    /// address is left.
+    AddressChanged {
+        peer: PeerId,
+        address: Option<Multiaddr>,
+    },
+    /// The first connection to the peer was established.
+    Connected(PeerId),
+    /// The last connection to the peer was closed.
+    Disconnected(PeerId),
+    /// Connections to and from the peer are refused until it is unbanned.
+    Banned(PeerId),
+    Unbanned(PeerId),
+    /// The manager forgot the peer.
+    Removed(PeerId),
+    /// `old` announced that it now uses the identity `new`. Its addresses
+    /// were moved to `new`; see [`identity`] for the rotation procedure.
+    KeyRotated { old: PeerId, new: PeerId },
+}
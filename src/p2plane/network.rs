@@ -1,349 +1,903 @@
+pub use crate::p2plane::config::{NodeConfig, RelayMode};
+pub use crate::p2plane::peer_manager::{PeerManager, PeerStorage, PeerStorageKind};
 use crate::p2plane::{
-    traits::{Message, PeerManagement},
+    address_book::AddressSource,
+    admin,
+    blob::{self, BlobStore, BLOB_PROTOCOL},
+    traits::{AsyncPeerManagement, Message},
     behavior::{Behavior, Event as BehaviorEvent},
+    builder::NodeBuilder,
+    content::{ContentRequest, ContentResponse, ContentRouting},
+    handle::{Command, NodeEvent, NodeHandle},
+    error::{ErrorChain, P2PlaneError},
+    identity::{self, KEY_ROTATION_PROTOCOL},
+    peer_manager::is_relayed,
+    queue::{Lane, OutboundQueues, QueueDepth},
+    stream::{IncomingStreams, StreamControl},
+    PeerEvent, PeerInfo, PeerMetadata, Result, RoutingEntry,
 };
-use std::fs;
 use libp2p::{
-    identity, Multiaddr, PeerId, SwarmBuilder,
-    swarm::{Swarm,  SwarmEvent},
+    Multiaddr, PeerId, Stream, StreamProtocol,
+    identify,
+    identity::Keypair,
+    mdns,
+    ping,
+    relay,
+    swarm::{dial_opts::DialOpts, dummy, DialError, NetworkBehaviour, Swarm, SwarmEvent},
     kad::{
-        store::MemoryStore,
-        Behaviour as Kademlia,
-        Config as KadConfig,
         Event as KadEvent,
-    },
-    identify::{
-        Behaviour as Identify,
-        Config as IdentifyConfig,
+        GetProvidersOk,
+        NodeStatus,
+        QueryId,
+        QueryResult,
+        RecordKey,
     },
     request_response::{
-        cbor::Behaviour as RequestResponse,
-        Config as RequestResponseConfig,
         Event as RequestResponseEvent,
         Message as RequestResponseMessage,
-        ProtocolSupport,
     },
-    tcp::Config as TcpConfig,
-    yamux,
-    noise,
     core::ConnectedPoint,
-    StreamProtocol,
 };
-use libp2p::futures::StreamExt;
+use libp2p::futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
 use log::{debug, error, info};
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
     time::Duration,
 };
-use tokio::sync::Mutex as TokioMutex;
-use serde::{Deserialize, Serialize};
-use std::error::Error as StdError;
-use libp2p::request_response::OutboundFailure;
+use tokio::time::MissedTickBehavior;
+use tokio::sync::{
+    broadcast,
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Mutex as TokioMutex, Semaphore,
+};
+
+pub struct Node<M: Message, X: NetworkBehaviour = dummy::Behaviour> {
+    swarm: Swarm<Behavior<M, X>>,
+    keypair: Keypair,
+    peer_manager: Arc<TokioMutex<Box<dyn AsyncPeerManagement>>>,
+    config: NodeConfig,
+    content: ContentRouting,
+    commands_tx: UnboundedSender<Command<M>>,
+    commands_rx: UnboundedReceiver<Command<M>>,
+    events: broadcast::Sender<NodeEvent<M>>,
+    peer_events: broadcast::Sender<PeerEvent>,
+    banned: HashSet<PeerId>,
+    queues: OutboundQueues<M>,
+    streams: StreamControl,
+    blobs: BlobStore,
+    blob_streams: IncomingStreams,
+    blob_transfers: Arc<Semaphore>,
+    key_rotation_streams: IncomingStreams,
+    peer_updates_tx: UnboundedSender<PeerUpdate<M>>,
+    peer_updates_rx: Option<UnboundedReceiver<PeerUpdate<M>>>,
+    extension_tx: mpsc::UnboundedSender<X::ToSwarm>,
+    extension_rx: Option<mpsc::UnboundedReceiver<X::ToSwarm>>,
+}
 
-// Custom serialization wrapper for PeerId
-#[derive(Debug, Serialize, Deserialize)]
-struct SerializablePeerId(String);
+/// Number of events buffered per subscriber before the slowest one starts
+/// missing events.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
 
-impl From<PeerId> for SerializablePeerId {
-    fn from(peer_id: PeerId) -> Self {
-        SerializablePeerId(peer_id.to_string())
+/// Shortest interval at which pending peer storage changes are checked.
+const MIN_FLUSH_TICK: Duration = Duration::from_millis(100);
+
+impl<M: Message> Node<M> {
+    /// Creates a node with the default network stack. Use [`NodeBuilder`]
+    /// to choose transports, discovery mechanisms or extra behaviours.
+    pub async fn new(config: NodeConfig) -> Result<Self> {
+        NodeBuilder::new(config).build().await
     }
 }
 
-impl TryFrom<SerializablePeerId> for PeerId {
-    type Error = String;
-    fn try_from(spid: SerializablePeerId) -> Result<Self, Self::Error> {
-        spid.0
-            .parse()
-            .map_err(|e| format!("Failed to parse PeerId: {}", e))
+impl<M: Message, X: NetworkBehaviour> Node<M, X> {
+    /// Creates the node around a swarm built with `keypair`. Without a
+    /// `peer_manager` the node uses a [`PeerManager`] backed by the
+    /// configured peer storage.
+    pub(crate) fn from_swarm(
+        swarm: Swarm<Behavior<M, X>>,
+        keypair: Keypair,
+        config: NodeConfig,
+        peer_manager: Option<Box<dyn AsyncPeerManagement>>,
+    ) -> Result<Self> {
+        let local_peer_id = *swarm.local_peer_id();
+        info!("Local peer id: {}", local_peer_id);
+
+        let peer_manager = match peer_manager {
+            Some(peer_manager) => peer_manager,
+            None => {
+                let store = config.peer_storage.open(&config.data_dir, &local_peer_id)?;
+                Box::new(PeerManager::with_store(
+                    local_peer_id,
+                    store,
+                    config.storage_flush_interval,
+                ))
+            }
+        };
+        let peer_events = peer_manager
+            .peer_events()
+            .unwrap_or_else(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0);
+        let peer_manager = Arc::new(TokioMutex::new(peer_manager));
+        info!("Created peer manager for {}", local_peer_id);
+
+        let (commands_tx, commands_rx) = unbounded_channel();
+        let (peer_updates_tx, peer_updates_rx) = unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let (extension_tx, extension_rx) = mpsc::unbounded();
+        let streams = swarm.behaviour().streams.control();
+        let blobs = BlobStore::with_config(config.blobs.clone());
+        let blob_streams = streams.accept(StreamProtocol::new(BLOB_PROTOCOL))?;
+        let blob_transfers = Arc::new(Semaphore::new(config.blobs.max_transfers));
+        let key_rotation_streams = streams.accept(StreamProtocol::new(KEY_ROTATION_PROTOCOL))?;
+        // High-priority messages can only get a lane of their own if the
+        // swarm was built with the protocol for it.
+        let mut queue_config = config.outbound_queue.clone();
+        queue_config.high_priority_protocol = swarm.behaviour().high_priority.is_enabled();
+
+        Ok(Self {
+            swarm,
+            keypair,
+            peer_manager,
+            queues: OutboundQueues::new(queue_config),
+            config,
+            content: ContentRouting::default(),
+            commands_tx,
+            commands_rx,
+            events,
+            peer_events,
+            banned: HashSet::new(),
+            streams,
+            blobs,
+            blob_streams,
+            blob_transfers,
+            key_rotation_streams,
+            peer_updates_tx,
+            peer_updates_rx: Some(peer_updates_rx),
+            extension_tx,
+            extension_rx: Some(extension_rx),
+        })
     }
-}
 
-// Custom serialization wrapper for Multiaddr
-#[derive(Debug, Serialize, Deserialize)]
-struct SerializableMultiaddr(String);
+    pub fn local_peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
 
-impl From<Multiaddr> for SerializableMultiaddr {
-    fn from(addr: Multiaddr) -> Self {
-        SerializableMultiaddr(addr.to_string())
+    /// Returns a handle that can drive this node from other tasks once
+    /// `start()` is running.
+    pub fn handle(&self) -> NodeHandle<M> {
+        NodeHandle::new(
+            self.keypair.clone(),
+            self.commands_tx.clone(),
+            self.events.clone(),
+            self.peer_events.clone(),
+            self.streams.clone(),
+            self.blobs.clone(),
+        )
     }
-}
 
-impl TryFrom<SerializableMultiaddr> for Multiaddr {
-    type Error = String;
-    fn try_from(sma: SerializableMultiaddr) -> Result<Self, Self::Error> {
-        sma.0
-            .parse()
-            .map_err(|e| format!("Failed to parse Multiaddr: {}", e))
+    /// Opens and accepts raw streams. Opening a stream needs `start()` to be
+    /// running, so use the control from another task.
+    pub fn stream_control(&self) -> StreamControl {
+        self.streams.clone()
     }
-}
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct PeerStorage {
-    peers: HashSet<String>,             // Store peer IDs as strings
-    addresses: HashMap<String, String>, // Store addresses as strings
-}
+    /// The blobs this node serves to peers and received from them.
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
 
-impl PeerStorage {
-    pub fn new(peer_id: &PeerId) -> Self {
-        let filename = format!("peers_{}.json", peer_id.to_base58());
-        info!("Loading peer storage from {}", filename);
+    /// Returns the addresses, smoothed RTT, missed-ping count and Identify
+    /// metadata recorded for `peer_id`.
+    pub async fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.peer_manager.lock().await.peer_info(peer_id).await
+    }
 
-        match fs::read_to_string(&filename) {
-            Ok(content) => match serde_json::from_str(&content) {
-                Ok(storage) => {
-                    info!("Successfully loaded peer storage from {}", filename);
-                    storage
-                }
-                Err(e) => {
-                    error!("Failed to parse {}: {}. Creating new storage.", filename, e);
-                    PeerStorage::default()
-                }
-            },
-            Err(e) => {
-                info!("No existing storage found ({}), creating new one at {}", e, filename);
-                PeerStorage::default()
+    /// Returns what the peer manager knows about each known peer.
+    pub async fn known_peers(&self) -> Vec<PeerInfo> {
+        known_peers(&self.peer_manager).await
+    }
+
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.swarm.listeners().cloned().collect()
+    }
+
+    /// Returns the peers in the Kademlia routing table, closest buckets
+    /// first. Empty if Kademlia is disabled.
+    pub fn routing_table(&mut self) -> Vec<RoutingEntry> {
+        let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+            return Vec::new();
+        };
+        let mut entries = Vec::new();
+        for bucket in kad.kbuckets() {
+            let index = bucket.range().0.ilog2().unwrap_or(0);
+            for entry in bucket.iter() {
+                entries.push(RoutingEntry {
+                    peer_id: *entry.node.key.preimage(),
+                    bucket: index,
+                    addresses: entry.node.value.iter().cloned().collect(),
+                    connected: entry.status == NodeStatus::Connected,
+                });
             }
         }
+        entries
     }
 
-    pub fn save_to_disk(&self, peer_id: &PeerId) -> Result<(), std::io::Error> {
-        let filename = format!("peers_{}.json", peer_id.to_base58());
-        info!("Saving peer storage to {}", filename);
+    pub fn banned_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.banned.iter()
+    }
 
-        let content = serde_json::to_string_pretty(self)?;
-        fs::write(&filename, content)?;
-        info!("Successfully saved peer storage to {}", filename);
-        Ok(())
+    /// Closes all connections to `peer`. Returns false if there were none.
+    pub fn disconnect_peer(&mut self, peer: PeerId) -> bool {
+        self.swarm.disconnect_peer_id(peer).is_ok()
     }
-}
 
-#[derive(Debug)]
-pub struct PeerManager {
-    pub peers: HashSet<PeerId>,
-    pub peer_addresses: HashMap<PeerId, Multiaddr>,
-    local_peer_id: PeerId,
-}
+    /// Returns the known peers that announced support for `protocol` over
+    /// Identify, e.g. to check who understands a message before sending it.
+    pub async fn peers_supporting(&self, protocol: &str) -> Vec<PeerId> {
+        self.peer_manager.lock().await.peers_supporting(protocol).await
+    }
 
-impl PeerManager {
-    pub fn new(local_peer_id: PeerId) -> Self {
-        info!(
-            "[PeerManager::new] Creating new instance for {:?}",
-            local_peer_id
-        );
-        let storage = PeerStorage::new(&local_peer_id);
-
-        let peers = storage
-            .peers
-            .iter()
-            .filter_map(|p| match p.parse::<PeerId>() {
-                Ok(peer_id) => {
-                    info!("[PeerManager::new] Loaded peer: {:?}", peer_id);
-                    Some(peer_id)
-                }
-                Err(e) => {
-                    error!("[PeerManager::new] Failed to parse peer ID {}: {}", p, e);
-                    None
+    /// Returns the events of the behaviour added with
+    /// [`NodeBuilder::with_behaviour`]. The receiver can only be taken once.
+    pub fn extension_events(&mut self) -> Option<mpsc::UnboundedReceiver<X::ToSwarm>> {
+        self.extension_rx.take()
+    }
+
+    /// Subscribes to messages and connection changes seen by this node.
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent<M>> {
+        self.events.subscribe()
+    }
+
+    /// Subscribes to peers being added, removed, connected, disconnected or
+    /// banned, and to changes of their best address.
+    pub fn subscribe_peer_events(&self) -> broadcast::Receiver<PeerEvent> {
+        self.peer_events.subscribe()
+    }
+
+    fn publish(&self, event: NodeEvent<M>) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    fn publish_peer_event(&self, event: PeerEvent) {
+        let _ = self.peer_events.send(event);
+    }
+
+    /// Hands `update` to the peer manager task, so a slow manager does not
+    /// hold up the swarm.
+    fn update_peer_manager(&self, update: PeerUpdate<M>) {
+        let _ = self.peer_updates_tx.send(update);
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        // Start listening
+        let listen_addr: Multiaddr =
+            self.config
+                .listen_addr
+                .parse()
+                .map_err(|e| P2PlaneError::Config {
+                    message: format!("invalid listen address {}", self.config.listen_addr),
+                    source: Some(Box::new(e)),
+                })?;
+        if is_relayed(&listen_addr) && self.config.relay_mode != RelayMode::Client {
+            return Err(P2PlaneError::config(format!(
+                "Listening on relayed address {} requires RelayMode::Client",
+                listen_addr
+            )));
+        }
+        self.swarm
+            .listen_on(listen_addr)
+            .map_err(P2PlaneError::transport)?;
+
+        if let Some(addr) = self.config.admin.listen_addr {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(P2PlaneError::transport)?;
+            let handle = self.handle();
+            let token = self.config.admin.token.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin::serve(listener, handle, token).await {
+                    error!("Admin API stopped: {}", ErrorChain(&e));
                 }
-            })
-            .collect();
-
-        let manager = PeerManager {
-            peers,
-            peer_addresses: storage
-                .addresses
-                .iter()
-                .filter_map(|(p, a)| {
-                    let peer_id = p.parse::<PeerId>().ok()?;
-                    let addr = a.parse::<Multiaddr>().ok()?;
-                    info!(
-                        "[PeerManager::new] Loaded peer address: {:?} -> {:?}",
-                        peer_id, addr
-                    );
-                    Some((peer_id, addr))
-                })
-                .collect(),
-            local_peer_id,
-        };
+            });
+        }
 
-        info!(
-            "[PeerManager::new] Created with {} peers",
-            manager.peers.len()
-        );
-        manager
-    }
-
-    pub fn get_peers(&self) -> Vec<PeerId> {
-        let peers = self.peers.iter().cloned().collect::<Vec<_>>();
-        info!(
-            "[PeerManager::get_peers] Returning {} peers: {:?}",
-            peers.len(),
-            peers
-        );
-        peers
-    }
-
-    pub fn add_peer_with_addr(&mut self, peer_id: PeerId, addr: Multiaddr) {
-        debug!(
-            "[PeerManager::add_peer_with_addr] Adding peer {:?} with addr {:?}",
-            peer_id, addr
-        );
-        if peer_id == self.local_peer_id {
-            debug!("[PeerManager::add_peer_with_addr] Skipping self peer");
-            return;
+        // Try to connect to bootstrap node if specified
+        if let Some(addr) = &self.config.bootstrap_addr {
+            self.connect_with_retry(addr.clone()).await?;
         }
-        self.peers.insert(peer_id.clone());
-        self.peer_addresses.insert(peer_id, addr);
-        debug!(
-            "[PeerManager::add_peer_with_addr] Current peers: {:?}",
-            self.peers
-        );
-        self.save_to_disk();
-    }
-
-    fn save_to_disk(&self) {
-        let storage = PeerStorage {
-            peers: self
-                .peers
-                .iter()
-                .map(|p| p.to_base58().to_string())
-                .collect(),
-            addresses: self
-                .peer_addresses
-                .iter()
-                .map(|(p, a)| (p.to_base58().to_string(), a.to_string()))
-                .collect(),
-        };
 
-        if let Err(e) = storage.save_to_disk(&self.local_peer_id) {
-            error!("Failed to save peer storage: {}", e);
-        } else {
-            info!(
-                "Successfully saved peers to disk for {:?}",
-                self.local_peer_id
-            );
+        if let Some(updates) = self.peer_updates_rx.take() {
+            tokio::spawn(run_peer_manager(
+                self.peer_manager.clone(),
+                updates,
+                self.commands_tx.clone(),
+                self.peer_events.clone(),
+                self.config.storage_flush_interval.max(MIN_FLUSH_TICK),
+                self.config.max_missed_pings,
+            ));
+        }
+
+        // Event loop
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => {
+                    self.handle_event(event).await?;
+                }
+                Some(command) = self.commands_rx.recv() => {
+                    self.handle_command(command).await?;
+                }
+                Some((peer, stream)) = self.blob_streams.next() => {
+                    self.serve_blob(peer, stream);
+                }
+                Some((peer, stream)) = self.key_rotation_streams.next() => {
+                    self.receive_key_rotation(peer, stream);
+                }
+            }
         }
     }
 
-    pub fn get_peer_address(&self, peer_id: &PeerId) -> Option<&Multiaddr> {
-        self.peer_addresses.get(peer_id)
+    /// Announces the local node as a provider of `key`. The announcement is
+    /// repeated every `provider_reannounce_interval` until `stop_providing`.
+    pub fn start_providing(
+        &mut self,
+        key: RecordKey,
+    ) -> Result<QueryId> {
+        let query_id = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .as_mut()
+            .ok_or_else(|| P2PlaneError::config("providing content requires Kademlia"))?
+            .start_providing(key.clone())
+            .map_err(|e| P2PlaneError::Storage(Box::new(e)))?;
+        info!("Providing key {:?} (query {:?})", key, query_id);
+        Ok(query_id)
     }
-}
 
-impl PeerManagement for PeerManager {
-    fn add_peer_with_addr(&mut self, peer_id: PeerId, addr: Multiaddr) {
-        PeerManager::add_peer_with_addr(self, peer_id, addr)
+    /// Stores `content` so that it can be served to `fetch` requests from
+    /// other peers, then announces the local node as its provider.
+    pub fn provide_content(
+        &mut self,
+        key: RecordKey,
+        content: Vec<u8>,
+    ) -> Result<QueryId> {
+        self.content.insert_content(key.clone(), content);
+        self.start_providing(key)
     }
 
-    fn get_peers(&self) -> Vec<PeerId> {
-        PeerManager::get_peers(self)
+    pub fn stop_providing(&mut self, key: &RecordKey) {
+        info!("No longer providing key {:?}", key);
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            kad.stop_providing(key);
+        }
+        self.content.remove_content(key);
     }
-}
 
-pub struct Node<M: Message> {
-    swarm: Swarm<Behavior<M>>,
-    peer_manager: Arc<TokioMutex<PeerManager>>,
-    config: NodeConfig,
-}
+    /// Looks up the providers of `key`. Each provider is yielded once and the
+    /// stream ends when the DHT query completes.
+    pub fn get_providers(&mut self, key: RecordKey) -> mpsc::UnboundedReceiver<PeerId> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.lookup_providers(key, sender);
+        receiver
+    }
 
-#[derive(Debug, Clone)]
-pub struct NodeConfig {
-    pub listen_addr: String,
-    pub bootstrap_addr: Option<Multiaddr>,
-}
+    fn lookup_providers(&mut self, key: RecordKey, sender: mpsc::UnboundedSender<PeerId>) {
+        // Without Kademlia the sender is dropped and the lookup ends empty.
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            let query_id = kad.get_providers(key);
+            self.content.track_lookup(query_id, sender);
+        }
+    }
+
+    /// Asks the providers of `key` for its content, one at a time, until one
+    /// of them serves it. Resolves to `None` if none of them can.
+    pub fn fetch(&mut self, key: RecordKey) -> oneshot::Receiver<Option<Vec<u8>>> {
+        let (reply, receiver) = oneshot::channel();
+        self.start_fetch(key, reply);
+        receiver
+    }
+
+    fn start_fetch(&mut self, key: RecordKey, reply: oneshot::Sender<Option<Vec<u8>>>) {
+        if let Some(content) = self.content.get_content(&key) {
+            let _ = reply.send(Some(content));
+            return;
+        }
 
-impl Default for NodeConfig {
-    fn default() -> Self {
-        Self {
-            listen_addr: "/ip4/0.0.0.0/tcp/0".to_string(),
-            bootstrap_addr: None,
+        let local_peer_id = *self.swarm.local_peer_id();
+        let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+            let _ = reply.send(None);
+            return;
+        };
+        let query_id = kad.get_providers(key.clone());
+        self.content.track_fetch(query_id, key, local_peer_id, reply);
+    }
+
+    fn drive_fetch(&mut self, query_id: QueryId) {
+        if let Some((peer, key)) = self.content.next_fetch_target(query_id) {
+            debug!("Fetching {:?} from provider {}", key, peer);
+            let request_id = self
+                .swarm
+                .behaviour_mut()
+                .content
+                .send_request(&peer, ContentRequest { key: key.to_vec() });
+            self.content.fetch_sent(query_id, request_id);
         }
     }
-}
 
-impl<M: Message> Node<M> {
-    pub async fn new(config: NodeConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let local_key = identity::Keypair::generate_ed25519();
-        let local_peer_id = PeerId::from(local_key.public());
-        info!("Local peer id: {}", local_peer_id);
+    /// Serves a blob stream opened by `peer` on its own task, so a slow
+    /// transfer does not hold up the event loop. The stream is dropped if
+    /// `blobs.max_transfers` transfers are already under way.
+    fn serve_blob(&self, peer: PeerId, stream: Stream) {
+        let Ok(permit) = self.blob_transfers.clone().try_acquire_owned() else {
+            debug!("Dropping blob stream from {}: too many transfers under way", peer);
+            return;
+        };
+        let blobs = self.blobs.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            match blob::serve(&blobs, peer, stream).await {
+                Ok(Some(id)) => {
+                    info!("Received blob {} from {}", id, peer);
+                    let _ = events.send(NodeEvent::BlobReceived { peer, id });
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Blob stream from {} failed: {}", peer, ErrorChain(&e)),
+            }
+        });
+    }
 
-        let peer_manager = Arc::new(TokioMutex::new(PeerManager::new(local_peer_id)));
-        info!("Created peer manager for {}", local_peer_id);
-        
-        // Log the storage file name
-        let storage_file = format!("peers_{}.json", local_peer_id.to_base58());
-        info!("Peer storage file will be: {}", storage_file);
+    /// Reads a key rotation announced by `peer` on its own task and applies
+    /// it on the event loop once it has been verified.
+    fn receive_key_rotation(&self, peer: PeerId, stream: Stream) {
+        let commands = self.commands_tx.clone();
+        tokio::spawn(async move {
+            match identity::receive(peer, stream).await {
+                Ok(new) => {
+                    let _ = commands.send(Command::KeyRotated { old: peer, new });
+                }
+                Err(e) => debug!("Key rotation stream from {} failed: {}", peer, ErrorChain(&e)),
+            }
+        });
+    }
 
-        let swarm = Self::build_swarm(local_key, peer_manager.clone()).await?;
+    /// Moves what is known about `old` to `new` after `old` announced a key
+    /// rotation: a ban and the keep-alive policy here, its addresses on the
+    /// peer manager task, which then publishes the rotation.
+    fn rotate_peer(&mut self, old: PeerId, new: PeerId) {
+        info!("Peer {} rotated its key to {}", old, new);
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            kad.remove_peer(&old);
+        }
+        if self.is_banned(&old) {
+            self.ban_peer(new);
+        }
+        let keep_alive = &mut self.swarm.behaviour_mut().keep_alive;
+        if keep_alive.is_kept(&old) {
+            keep_alive.release(&old);
+            keep_alive.keep(new);
+        }
+        self.update_peer_manager(PeerUpdate::KeyRotated(old, new));
+    }
 
-        Ok(Self {
-            swarm,
-            peer_manager,
-            config,
+    async fn handle_command(&mut self, command: Command<M>) -> Result<()> {
+        match command {
+            // Looking up peers can take as long as the peer manager likes,
+            // so it happens on its task, which sends the result back.
+            Command::Broadcast { message, reply } => {
+                self.update_peer_manager(PeerUpdate::Broadcast(message, reply));
+            }
+            Command::BroadcastTo { peers, message, reply } => {
+                let waiter = self.queues.waiter(reply);
+                let result = self.broadcast_to(peers, message, Some(waiter));
+                self.queues.release(waiter, result);
+            }
+            Command::SendMessage { peer, message, reply } => {
+                let waiter = self.queues.waiter(reply);
+                let result = self.queue_message(peer, message, Some(waiter));
+                self.queues.release(waiter, result);
+            }
+            Command::StartProviding { key, content, reply } => {
+                let result = match content {
+                    Some(content) => self.provide_content(key, content),
+                    None => self.start_providing(key),
+                };
+                let _ = reply.send(result.map(|_| ()));
+            }
+            Command::StopProviding { key } => self.stop_providing(&key),
+            Command::GetProviders { key, sender } => self.lookup_providers(key, sender),
+            Command::PeerInfo { peer, reply } => {
+                let peer_manager = self.peer_manager.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(peer_manager.lock().await.peer_info(&peer).await);
+                });
+            }
+            Command::Fetch { key, reply } => self.start_fetch(key, reply),
+            Command::PeersSupporting { protocol, reply } => {
+                let peer_manager = self.peer_manager.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(peer_manager.lock().await.peers_supporting(&protocol).await);
+                });
+            }
+            Command::DialPeer { peer, reply } => {
+                if self.swarm.is_connected(&peer) {
+                    let _ = reply.send(Ok(()));
+                } else {
+                    self.update_peer_manager(PeerUpdate::DialCandidates(peer, reply));
+                }
+            }
+            Command::DialCandidates { peer, candidates, reply } => {
+                let _ = reply.send(self.dial_candidates(peer, candidates));
+            }
+            Command::RemovePeer { peer, reply } => {
+                if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                    kad.remove_peer(&peer);
+                }
+                self.update_peer_manager(PeerUpdate::Remove(peer, reply));
+            }
+            Command::BanPeer { peer } => self.ban_peer(peer),
+            Command::UnbanPeer { peer } => self.unban_peer(peer),
+            Command::KeepAlive { peer } => self.keep_alive(peer),
+            Command::ReleaseKeepAlive { peer } => self.release_keep_alive(peer),
+            Command::Dial { addr, reply } => {
+                let result = self
+                    .swarm
+                    .dial(addr.clone())
+                    .map_err(|source| P2PlaneError::Dial { addr, source });
+                let _ = reply.send(result);
+            }
+            Command::ConnectedPeers { reply } => {
+                let _ = reply.send(self.swarm.connected_peers().copied().collect());
+            }
+            Command::Disconnect { peer, reply } => {
+                let _ = reply.send(self.disconnect_peer(peer));
+            }
+            Command::ListenAddrs { reply } => {
+                let _ = reply.send(self.listen_addrs());
+            }
+            Command::KnownPeers { reply } => {
+                let peer_manager = self.peer_manager.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(known_peers(&peer_manager).await);
+                });
+            }
+            Command::RoutingTable { reply } => {
+                let _ = reply.send(self.routing_table());
+            }
+            Command::BannedPeers { reply } => {
+                let _ = reply.send(self.banned.iter().copied().collect());
+            }
+            Command::QueueDepths { reply } => {
+                let _ = reply.send(self.queue_depths());
+            }
+            Command::KeyRotated { old, new } => self.rotate_peer(old, new),
+            Command::MissedPings { peer, missed } => {
+                info!("Disconnecting {} after {} missed pings", peer, missed);
+                let _ = self.swarm.disconnect_peer_id(peer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Dials `peer` on its known addresses, best ranked first. Does nothing
+    /// if the peer is already connected.
+    pub async fn dial_peer(&mut self, peer: PeerId) -> Result<()> {
+        if self.swarm.is_connected(&peer) {
+            return Ok(());
+        }
+        let candidates = self.peer_manager.lock().await.dial_candidates(&peer).await;
+        self.dial_candidates(peer, candidates)
+    }
+
+    fn dial_candidates(&mut self, peer: PeerId, candidates: Vec<Multiaddr>) -> Result<()> {
+        let opts = DialOpts::peer_id(peer).addresses(candidates.clone()).build();
+        self.swarm.dial(opts).map_err(|source| P2PlaneError::Dial {
+            addr: candidates.into_iter().next().unwrap_or_else(Multiaddr::empty),
+            source,
         })
     }
 
-    pub async fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // Start listening
-        self.swarm.listen_on(self.config.listen_addr.parse()?)?;
+    /// Forgets `peer` in the peer manager and the Kademlia routing table.
+    /// Open connections are left alone. Returns true if the manager knew it.
+    pub async fn remove_peer(&mut self, peer: &PeerId) -> bool {
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            kad.remove_peer(peer);
+        }
+        self.peer_manager.lock().await.remove_peer(peer).await
+    }
 
-        // Try to connect to bootstrap node if specified
-        if let Some(addr) = &self.config.bootstrap_addr {
-            self.connect_with_retry(addr.clone()).await?;
+    /// Closes all connections to `peer` and refuses new ones until
+    /// [`unban_peer`](Self::unban_peer). The peer is also dropped from the
+    /// Kademlia routing table and skipped by broadcasts.
+    pub fn ban_peer(&mut self, peer: PeerId) {
+        if !self.banned.insert(peer) {
+            return;
+        }
+        info!("Banning peer {}", peer);
+        self.queues.remove(&peer);
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.blocked.block_peer(peer);
+        if let Some(kad) = behaviour.kad.as_mut() {
+            kad.remove_peer(&peer);
         }
+        self.publish_peer_event(PeerEvent::Banned(peer));
+    }
 
-        // Event loop
-        while let Some(event) = self.swarm.next().await {
-            self.handle_event(event).await?;
+    pub fn unban_peer(&mut self, peer: PeerId) {
+        if self.banned.remove(&peer) {
+            info!("Unbanning peer {}", peer);
+            self.swarm.behaviour_mut().blocked.unblock_peer(peer);
+            self.publish_peer_event(PeerEvent::Unbanned(peer));
         }
+    }
 
-        Ok(())
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains(peer)
     }
 
-    pub async fn broadcast_message(&mut self, message: M) -> Result<(), Box<dyn std::error::Error>> {
-        let peers = {
-            let pm = self.peer_manager.lock().await;
-            pm.get_peers()
-        };
+    /// Never closes the connections to `peer` for being idle, like the
+    /// peers of `NodeConfig::keep_alive`.
+    pub fn keep_alive(&mut self, peer: PeerId) {
+        self.swarm.behaviour_mut().keep_alive.keep(peer);
+    }
+
+    /// Lets the connections to `peer` close again once they are idle.
+    pub fn release_keep_alive(&mut self, peer: PeerId) {
+        self.swarm.behaviour_mut().keep_alive.release(&peer);
+    }
 
+    /// Queues `message` for every known peer. When a queue is full the
+    /// configured overflow policy applies, except that
+    /// [`OverflowPolicy::Block`](crate::p2plane::queue::OverflowPolicy::Block)
+    /// rejects the message with [`P2PlaneError::QueueFull`]; see
+    /// [`NodeHandle::broadcast_message`] for callers that should wait.
+    pub async fn broadcast_message(&mut self, message: M) -> Result<()> {
+        let peers = self.peer_manager.lock().await.get_peers().await;
+        self.broadcast_to(peers, message, None)
+    }
+
+    fn broadcast_to(&mut self, mut peers: Vec<PeerId>, message: M, waiter: Option<u64>) -> Result<()> {
+        peers.retain(|peer| !self.banned.contains(peer));
+
+        let mut result = Ok(());
         for peer in peers {
-            match self.swarm.behaviour_mut().request_response.send_request(&peer, message.clone()) {
-                id => {
-                    debug!("Sent message to peer {}, request id: {:?}", peer, id);
+            if let Err(e) = self.queue_message(peer, message.clone(), waiter) {
+                debug!("Not broadcasting to {}: {}", peer, e);
+                if result.is_ok() {
+                    result = Err(e);
                 }
             }
         }
+        result
+    }
 
+    /// Queues `message` for `peer`, applying the overflow policy if its
+    /// queue is full. Like [`broadcast_message`](Self::broadcast_message)
+    /// this never waits for room.
+    pub fn send_message(&mut self, peer: &PeerId, message: M) -> Result<()> {
+        self.queue_message(*peer, message, None)
+    }
+
+    fn queue_message(&mut self, peer: PeerId, message: M, waiter: Option<u64>) -> Result<()> {
+        self.queues.push(peer, message.priority(), message, waiter)?;
+        self.send_queued(peer);
         Ok(())
     }
 
-    async fn handle_event(&mut self, event: SwarmEvent<BehaviorEvent<M>>) -> Result<(), Box<dyn StdError>> {
+    /// Hands queued messages for `peer` to the swarm, most urgent first,
+    /// while fewer than `max_in_flight` are unanswered.
+    fn send_queued(&mut self, peer: PeerId) {
+        while let Some((message, lane)) = self.queues.next(&peer) {
+            let behaviour = self.swarm.behaviour_mut();
+            let id = match lane {
+                Lane::Shared => behaviour.request_response.send_request(&peer, message),
+                Lane::HighPriority => behaviour
+                    .high_priority
+                    .as_mut()
+                    .expect("high-priority lane is only used when its protocol is enabled")
+                    .send_request(&peer, message),
+            };
+            debug!("Sent message to peer {}, request id: {:?}", peer, id);
+            self.queues.sent(peer, lane, id);
+        }
+    }
+
+    /// Depth of the outbound queue of `peer`, if messages were sent to it.
+    pub fn queue_depth(&self, peer: &PeerId) -> Option<QueueDepth> {
+        self.queues.depth(peer)
+    }
+
+    /// Depth of the outbound queue of every peer messages were sent to.
+    pub fn queue_depths(&self) -> HashMap<PeerId, QueueDepth> {
+        self.queues.depths()
+    }
+
+    async fn handle_event(&mut self, event: SwarmEvent<BehaviorEvent<M, X::ToSwarm>>) -> Result<()> {
         match event {
-            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {}", address);
+                // Relay clients can only reserve a slot on a server that
+                // advertises at least one external address.
+                if self.config.relay_mode == RelayMode::Server && !is_relayed(&address) {
+                    self.swarm.add_external_address(address);
+                }
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
                 info!("Connection established with peer: {:?}", peer_id);
-                let mut pm = self.peer_manager.lock().await;
-                if let ConnectedPoint::Dialer { address, .. } = endpoint {
-                    pm.add_peer_with_addr(peer_id, address);
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                        kad.add_address(&peer_id, address.clone());
+                    }
+                    self.update_peer_manager(PeerUpdate::DialSuccess(peer_id, address.clone()));
+                }
+                if num_established.get() == 1 {
+                    self.update_peer_manager(PeerUpdate::Connected(peer_id, endpoint));
+                    self.publish(NodeEvent::PeerConnected(peer_id));
+                    self.publish_peer_event(PeerEvent::Connected(peer_id));
                 }
             }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error: DialError::Transport(errors),
+                ..
+            } => {
+                for (address, error) in errors {
+                    debug!("Dialing {} at {} failed: {}", peer_id, address, error);
+                    self.update_peer_manager(PeerUpdate::DialFailure(peer_id, address));
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.update_peer_manager(PeerUpdate::Disconnected(peer_id));
+                self.publish(NodeEvent::PeerDisconnected(peer_id));
+                self.publish_peer_event(PeerEvent::Disconnected(peer_id));
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Ping(ping::Event { peer, result, .. })) => {
+                match result {
+                    Ok(rtt) => self.update_peer_manager(PeerUpdate::Ping(peer, rtt)),
+                    // Peers that do not speak ping are not penalised.
+                    Err(ping::Failure::Unsupported) => {}
+                    Err(e) => {
+                        debug!("Ping to {} failed: {}", peer, e);
+                        self.update_peer_manager(PeerUpdate::PingFailure(peer));
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                for (peer, address) in peers {
+                    debug!("Discovered {} at {} via mDNS", peer, address);
+                    if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                        kad.add_address(&peer, address.clone());
+                    }
+                    self.update_peer_manager(PeerUpdate::Address(
+                        peer,
+                        address,
+                        AddressSource::Mdns,
+                    ));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                    for address in &info.listen_addrs {
+                        kad.add_address(&peer_id, address.clone());
+                    }
+                }
+                self.update_peer_manager(PeerUpdate::Identify(peer_id, info.into()));
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Kad(KadEvent::RoutingUpdated {
+                peer,
+                addresses,
+                ..
+            })) => {
+                for address in addresses.iter() {
+                    self.update_peer_manager(PeerUpdate::Address(
+                        peer,
+                        address.clone(),
+                        AddressSource::Kademlia,
+                    ));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Extension(event)) => {
+                // Dropped when the application did not take the receiver.
+                let _ = self.extension_tx.unbounded_send(event);
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::RelayClient(relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                ..
+            })) => {
+                info!("Reservation accepted by relay {}", relay_peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Kad(KadEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetProviders(result),
+                step,
+                ..
+            })) => {
+                match result {
+                    Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                        self.content.providers_found(id, providers);
+                    }
+                    Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    Err(e) => debug!("Provider lookup {:?} failed: {:?}", id, e),
+                }
+                if step.last {
+                    self.content.lookup_finished(id);
+                }
+                self.drive_fetch(id);
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::Content(event)) => match event {
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { request, channel, .. },
+                    ..
+                } => {
+                    let content = self.content.get_content(&RecordKey::new(&request.key));
+                    let response = ContentResponse { content };
+                    if self.swarm.behaviour_mut().content.send_response(channel, response).is_err() {
+                        debug!("Content requester went away before the response was sent");
+                    }
+                }
+                RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { request_id, response },
+                    ..
+                } => {
+                    if let Some(query_id) = self.content.fetch_response(request_id, response.content) {
+                        self.drive_fetch(query_id);
+                    }
+                }
+                RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                    debug!("Content request to {} failed: {:?}", peer, error);
+                    if let Some(query_id) = self.content.fetch_response(request_id, None) {
+                        self.drive_fetch(query_id);
+                    }
+                }
+                _ => {}
+            },
             SwarmEvent::Behaviour(BehaviorEvent::RequestResponse(event)) => {
-                match event {
-                    RequestResponseEvent::Message { peer, message } => {
-                        info!("Received message from peer {:?}: {:?}", peer, message);
-                        match message {
-                            RequestResponseMessage::Request { request, channel, .. } => {
-                                if let Err(e) = self.swarm.behaviour_mut().request_response.send_response(channel, request.clone()) {
-                                    error!("Failed to send response to peer {}: {:?}", peer, e);
-                                    return Err(format!("Failed to send response: {:?}", e).into());
-                                }
-                            }
-                            RequestResponseMessage::Response { .. } => {
-                                // Handle response
-                            }
+                return self.handle_message_event(Lane::Shared, event);
+            }
+            SwarmEvent::Behaviour(BehaviorEvent::HighPriority(event)) => {
+                return self.handle_message_event(Lane::HighPriority, event);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handles an event of the message protocol behind `lane`.
+    fn handle_message_event(&mut self, lane: Lane, event: RequestResponseEvent<M, M>) -> Result<()> {
+        match event {
+            RequestResponseEvent::Message { peer, message } => {
+                info!("Received message from peer {:?}: {:?}", peer, message);
+                match message {
+                    RequestResponseMessage::Request { request, channel, .. } => {
+                        self.publish(NodeEvent::Message { peer, message: request.clone() });
+                        let behaviour = self.swarm.behaviour_mut();
+                        let sent = match lane {
+                            Lane::Shared => behaviour.request_response.send_response(channel, request),
+                            Lane::HighPriority => match behaviour.high_priority.as_mut() {
+                                Some(high_priority) => high_priority.send_response(channel, request),
+                                None => Ok(()),
+                            },
+                        };
+                        if let Err(e) = sent {
+                            error!("Failed to send response to peer {}: {:?}", peer, e);
+                            return Err(P2PlaneError::transport(format!("Failed to send response: {:?}", e)));
+                        }
+                    }
+                    RequestResponseMessage::Response { request_id, .. } => {
+                        if let Some(peer) = self.queues.completed(lane, request_id) {
+                            self.send_queued(peer);
                         }
                     }
-                    _ => {}
+                }
+            }
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                debug!("Message to {} failed: {}", peer, error);
+                if let Some(peer) = self.queues.completed(lane, request_id) {
+                    self.send_queued(peer);
                 }
             }
             _ => {}
@@ -351,9 +905,9 @@ impl<M: Message> Node<M> {
         Ok(())
     }
 
-    async fn connect_with_retry(&mut self, addr: Multiaddr) -> Result<(), Box<dyn std::error::Error>> {
+    async fn connect_with_retry(&mut self, addr: Multiaddr) -> Result<()> {
         let mut retry_count = 0;
-        while retry_count < 3 {
+        loop {
             match self.swarm.dial(addr.clone()) {
                 Ok(_) => {
                     info!("Connected to bootstrap node: {}", addr);
@@ -362,58 +916,108 @@ impl<M: Message> Node<M> {
                 Err(e) => {
                     error!("Failed to connect to {}: {}", addr, e);
                     retry_count += 1;
+                    if retry_count == 3 {
+                        return Err(P2PlaneError::Dial { addr, source: e });
+                    }
                     tokio::time::sleep(std::time::Duration::from_secs(2)).await;
                 }
             }
         }
-        Err("Failed to connect after 3 retries".into())
-    }
-
-    async fn build_swarm(
-        local_key: identity::Keypair,
-        _peer_manager: Arc<TokioMutex<PeerManager>>,
-    ) -> Result<Swarm<Behavior<M>>, Box<dyn std::error::Error>> {
-        let local_peer_id = PeerId::from(local_key.public());
-        info!("LocalPeerID: {local_peer_id}");
-
-        let swarm = SwarmBuilder::with_existing_identity(local_key.clone())
-            .with_tokio()
-            .with_tcp(
-                TcpConfig::default(),
-                noise::Config::new,
-                || yamux::Config::default(),
-            )?
-            .with_behaviour(|key| {
-                let local_peer_id = PeerId::from(key.public());
-                
-                // Setup Kademlia
-                let kad_store = MemoryStore::new(local_peer_id);
-                let kad = Kademlia::with_config(
-                    local_peer_id,
-                    kad_store,
-                    KadConfig::default(),
-                );
-
-                // Setup Identify
-                let identify = Identify::new(
-                    IdentifyConfig::new("/p2plane/1.0.0".to_string(), key.public())
-                        .with_push_listen_addr_updates(true)
-                        .with_interval(Duration::from_secs(30)),
-                );
-
-                // Setup Request/Response
-                let rr_protocol = StreamProtocol::new("/p2plane/message/1.0.0");
-                let request_response = RequestResponse::new(
-                    [(rr_protocol, ProtocolSupport::Full)],
-                    RequestResponseConfig::default(),
-                );
-
-                // Create behavior
-                Ok(Behavior::new(kad, identify, request_response))
-            })?
-            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(30)))
-            .build();
-
-        Ok(swarm)
     }
 }
+
+/// What happened to a peer, for the [`AsyncPeerManagement`] hook of the
+/// same name, or the part of a command that needs the peer manager.
+#[derive(Debug)]
+enum PeerUpdate<M: Message> {
+    Connected(PeerId, ConnectedPoint),
+    Disconnected(PeerId),
+    DialSuccess(PeerId, Multiaddr),
+    DialFailure(PeerId, Multiaddr),
+    Identify(PeerId, PeerMetadata),
+    Ping(PeerId, Duration),
+    PingFailure(PeerId),
+    Address(PeerId, Multiaddr, AddressSource),
+    Broadcast(M, oneshot::Sender<Result<()>>),
+    DialCandidates(PeerId, oneshot::Sender<Result<()>>),
+    Remove(PeerId, oneshot::Sender<bool>),
+    KeyRotated(PeerId, PeerId),
+}
+
+/// Calls the peer manager hooks for `updates` in order, and `on_tick` every
+/// `tick`, until the node is dropped. Peers that missed `max_missed_pings`
+/// pings in a row are handed back to the node to be disconnected, as are the
+/// results of lookups the node needs to go on with a command.
+async fn run_peer_manager<M: Message>(
+    peer_manager: Arc<TokioMutex<Box<dyn AsyncPeerManagement>>>,
+    mut updates: UnboundedReceiver<PeerUpdate<M>>,
+    commands: UnboundedSender<Command<M>>,
+    peer_events: broadcast::Sender<PeerEvent>,
+    tick: Duration,
+    max_missed_pings: u32,
+) {
+    // Batched peer storage changes are written out on every tick.
+    let mut flush = tokio::time::interval(tick);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let update = tokio::select! {
+            update = updates.recv() => match update {
+                Some(update) => update,
+                None => return,
+            },
+            _ = flush.tick() => {
+                peer_manager.lock().await.on_tick().await;
+                continue;
+            }
+        };
+        let mut pm = peer_manager.lock().await;
+        match update {
+            PeerUpdate::Connected(peer, endpoint) => pm.on_connected(peer, &endpoint).await,
+            PeerUpdate::Disconnected(peer) => pm.on_disconnected(peer).await,
+            PeerUpdate::DialSuccess(peer, addr) => pm.on_dial_success(peer, &addr).await,
+            PeerUpdate::DialFailure(peer, addr) => pm.on_dial_failure(&peer, &addr).await,
+            PeerUpdate::Identify(peer, metadata) => pm.on_identify(peer, metadata).await,
+            PeerUpdate::Ping(peer, rtt) => pm.on_ping(peer, rtt).await,
+            PeerUpdate::PingFailure(peer) => {
+                let missed = pm.on_ping_failure(peer).await;
+                if missed >= max_missed_pings {
+                    let _ = commands.send(Command::MissedPings { peer, missed });
+                }
+            }
+            PeerUpdate::Address(peer, addr, source) => pm.add_address(peer, addr, source).await,
+            PeerUpdate::Broadcast(message, reply) => {
+                let peers = pm.get_peers().await;
+                let _ = commands.send(Command::BroadcastTo { peers, message, reply });
+            }
+            PeerUpdate::DialCandidates(peer, reply) => {
+                let candidates = pm.dial_candidates(&peer).await;
+                let _ = commands.send(Command::DialCandidates { peer, candidates, reply });
+            }
+            PeerUpdate::Remove(peer, reply) => {
+                let _ = reply.send(pm.remove_peer(&peer).await);
+            }
+            PeerUpdate::KeyRotated(old, new) => {
+                // Kept like dialed addresses, which is long enough for the
+                // peer to come back with its new key.
+                for addr in pm.dial_candidates(&old).await {
+                    pm.add_address(new, addr, AddressSource::Dialed).await;
+                }
+                pm.remove_peer(&old).await;
+                let _ = peer_events.send(PeerEvent::KeyRotated { old, new });
+            }
+        }
+    }
+}
+
+// Takes the peer manager rather than the node, whose event loop future
+// would otherwise have to be `Sync`.
+async fn known_peers(peer_manager: &TokioMutex<Box<dyn AsyncPeerManagement>>) -> Vec<PeerInfo> {
+    let pm = peer_manager.lock().await;
+    let mut peers = Vec::new();
+    for peer in pm.get_peers().await {
+        if let Some(info) = pm.peer_info(&peer).await {
+            peers.push(info);
+        }
+    }
+    peers
+}
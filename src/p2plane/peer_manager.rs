@@ -1,9 +1,26 @@
 use std::collections::{HashMap, HashSet};
-use libp2p::{Multiaddr, PeerId};
+use libp2p::{core::ConnectedPoint, multiaddr::Protocol, Multiaddr, PeerId};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use crate::p2plane::traits::PeerManagement;
+pub use crate::p2plane::storage::{PeerStorage, PeerStorageKind};
+use crate::p2plane::{
+    address_book::{AddressBook, AddressSource},
+    storage::{JsonFileStore, MemoryStore, PeerStore, StoredPeer},
+    traits::PeerManagement,
+    ErrorChain, PeerEvent, PeerInfo, PeerMetadata, Result,
+};
+use std::{
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::broadcast;
+
+/// How long changes are batched before they are written to the peer store.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of peer events buffered per subscriber before the slowest one
+/// starts missing events.
+const PEER_EVENT_CAPACITY: usize = 1024;
 
 // Custom serialization wrapper for PeerId
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,92 +60,104 @@ impl TryFrom<SerializableMultiaddr> for Multiaddr {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct PeerStorage {
-    peers: HashSet<String>,             // Store peer IDs as strings
-    addresses: HashMap<String, String>, // Store addresses as strings
-}
-
-impl PeerStorage {
-    pub fn new(peer_id: &PeerId) -> Self {
-        let filename = format!("peers_{}.json", peer_id.to_base58());
-        info!("Loading peer storage from {}", filename);
-
-        match fs::read_to_string(&filename) {
-            Ok(content) => match serde_json::from_str(&content) {
-                Ok(storage) => {
-                    info!("Successfully loaded peer storage from {}", filename);
-                    storage
-                }
-                Err(e) => {
-                    error!("Failed to parse {}: {}. Creating new storage.", filename, e);
-                    PeerStorage::default()
-                }
-            },
-            Err(e) => {
-                info!("No existing storage found ({}), creating new one at {}", e, filename);
-                PeerStorage::default()
-            }
-        }
-    }
-
-    pub fn save_to_disk(&self, peer_id: &PeerId) -> Result<(), std::io::Error> {
-        let filename = format!("peers_{}.json", peer_id.to_base58());
-        info!("Saving peer storage to {}", filename);
+/// Weight given to a new RTT sample when updating the smoothed RTT
+/// (the 1/8 gain used for TCP's SRTT in RFC 6298).
+const RTT_SMOOTHING: f64 = 0.125;
 
-        let content = serde_json::to_string_pretty(self)?;
-        fs::write(&filename, content)?;
-        info!("Successfully saved peer storage to {}", filename);
-        Ok(())
-    }
+/// Latency measurements for a single peer.
+#[derive(Debug, Default, Clone)]
+struct PingStats {
+    rtt: Option<Duration>,
+    missed_pings: u32,
 }
 
 #[derive(Debug)]
 pub struct PeerManager {
-    peers: HashSet<PeerId>,
-    peer_addresses: HashMap<PeerId, Multiaddr>,
+    pub peers: HashSet<PeerId>,
+    address_book: AddressBook,
+    metadata: HashMap<PeerId, PeerMetadata>,
+    ping_stats: HashMap<PeerId, PingStats>,
     local_peer_id: PeerId,
+    store: Box<dyn PeerStore>,
+    flush_interval: Duration,
+    /// Peers changed since the last flush.
+    dirty: HashSet<PeerId>,
+    last_flush: Option<Instant>,
+    events: broadcast::Sender<PeerEvent>,
 }
 
 impl PeerManager {
     pub fn new(local_peer_id: PeerId) -> Self {
+        Self::with_storage(local_peer_id, PeerStorageKind::File)
+    }
+
+    /// Creates a manager whose store of kind `storage_kind` lives in the
+    /// working directory.
+    pub fn with_storage(local_peer_id: PeerId, storage_kind: PeerStorageKind) -> Self {
+        let store = storage_kind
+            .open(Path::new("."), &local_peer_id)
+            .unwrap_or_else(|e| {
+                error!(
+                    "Failed to open peer storage: {}. Keeping peers in memory.",
+                    ErrorChain(&e)
+                );
+                Box::new(MemoryStore::new())
+            });
+        Self::with_store(local_peer_id, store, DEFAULT_FLUSH_INTERVAL)
+    }
+
+    /// Creates a manager that persists its peers in a JSON file in
+    /// `data_dir`, rewriting the file at most once per `flush_interval`.
+    pub fn open(local_peer_id: PeerId, data_dir: impl AsRef<Path>, flush_interval: Duration) -> Self {
+        let path = PeerStorage::path(data_dir.as_ref(), &local_peer_id);
+        Self::with_store(local_peer_id, Box::new(JsonFileStore::open(path)), flush_interval)
+    }
+
+    /// Creates a manager backed by `store`. Changed peers are written to it
+    /// at most once per `flush_interval`.
+    pub fn with_store(
+        local_peer_id: PeerId,
+        mut store: Box<dyn PeerStore>,
+        flush_interval: Duration,
+    ) -> Self {
         info!(
             "[PeerManager::new] Creating new instance for {:?}",
             local_peer_id
         );
-        let storage = PeerStorage::new(&local_peer_id);
 
-        let peers = storage
-            .peers
-            .iter()
-            .filter_map(|p| match p.parse::<PeerId>() {
-                Ok(peer_id) => {
-                    info!("[PeerManager::new] Loaded peer: {:?}", peer_id);
-                    Some(peer_id)
-                }
-                Err(e) => {
-                    error!("[PeerManager::new] Failed to parse peer ID {}: {}", p, e);
-                    None
-                }
-            })
-            .collect();
+        let stored = store.load().unwrap_or_else(|e| {
+            error!("[PeerManager::new] Failed to load peers: {}", ErrorChain(&e));
+            Vec::new()
+        });
+        let mut peers = HashSet::new();
+        let mut address_book = AddressBook::new();
+        let mut metadata = HashMap::new();
+        for peer in stored {
+            debug!("[PeerManager::new] Loaded peer: {:?}", peer.peer_id);
+            peers.insert(peer.peer_id);
+            address_book.insert_records(peer.peer_id, peer.addresses);
+            if let Some(m) = peer.metadata {
+                metadata.insert(peer.peer_id, m);
+            }
+        }
+        let expired = address_book.prune_expired(SystemTime::now());
+        info!(
+            "[PeerManager::new] Loaded addresses for {} peers, dropped {} expired",
+            address_book.peers().count(),
+            expired
+        );
 
         let manager = PeerManager {
             peers,
-            peer_addresses: storage
-                .addresses
-                .iter()
-                .filter_map(|(p, a)| {
-                    let peer_id = p.parse::<PeerId>().ok()?;
-                    let addr = a.parse::<Multiaddr>().ok()?;
-                    info!(
-                        "[PeerManager::new] Loaded peer address: {:?} -> {:?}",
-                        peer_id, addr
-                    );
-                    Some((peer_id, addr))
-                })
-                .collect(),
+            address_book,
+            metadata,
+            ping_stats: HashMap::new(),
             local_peer_id,
+            store,
+            flush_interval,
+            dirty: HashSet::new(),
+            last_flush: None,
+            events: broadcast::channel(PEER_EVENT_CAPACITY).0,
         };
 
         info!(
@@ -148,50 +177,259 @@ impl PeerManager {
         peers
     }
 
+    /// Adds `peer_id` with an address supplied by the application, which
+    /// never expires.
     pub fn add_peer_with_addr(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        self.add_address(peer_id, addr, AddressSource::Manual);
+    }
+
+    /// Adds `peer_id` and records `addr` as one of its addresses.
+    pub fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr, source: AddressSource) {
         debug!(
-            "[PeerManager::add_peer_with_addr] Adding peer {:?} with addr {:?}",
-            peer_id, addr
+            "[PeerManager::add_address] Adding peer {:?} with addr {:?} from {:?}",
+            peer_id, addr, source
         );
         if peer_id == self.local_peer_id {
-            debug!("[PeerManager::add_peer_with_addr] Skipping self peer");
+            debug!("[PeerManager::add_address] Skipping self peer");
+            return;
+        }
+        let best = self.address_book.best(&peer_id).cloned();
+        let new_peer = self.peers.insert(peer_id);
+        let new_addr = self.address_book.add(peer_id, addr, source);
+        if new_peer || new_addr {
+            self.changed(peer_id, new_peer, best);
+        }
+    }
+
+    /// Forgets `peer_id` along with its addresses, metadata and ping
+    /// statistics. Returns true if it was known.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) -> bool {
+        let known = self.peers.remove(peer_id);
+        let addresses = !self.address_book.remove_peer(peer_id).is_empty();
+        let metadata = self.metadata.remove(peer_id).is_some();
+        self.ping_stats.remove(peer_id);
+        let removed = known || addresses || metadata;
+        if removed {
+            debug!("[PeerManager::remove_peer] Removed peer {:?}", peer_id);
+            self.mark_dirty(*peer_id);
+            self.notify(PeerEvent::Removed(*peer_id));
+        }
+        removed
+    }
+
+    /// Records a connection established by dialing `addr`.
+    pub fn record_dial_success(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        if peer_id == self.local_peer_id {
+            return;
+        }
+        let best = self.address_book.best(&peer_id).cloned();
+        let new_peer = self.peers.insert(peer_id);
+        self.address_book.add(peer_id, addr.clone(), AddressSource::Dialed);
+        self.address_book.record_success(&peer_id, &addr);
+        self.changed(peer_id, new_peer, best);
+    }
+
+    /// Records a connection `peer_id` opened to us. Its remote address is
+    /// usually an ephemeral port that cannot be dialed, so no address is
+    /// stored; the peer's listen addresses arrive over Identify.
+    pub fn record_inbound(&mut self, peer_id: PeerId) {
+        if peer_id == self.local_peer_id {
+            return;
+        }
+        if self.peers.insert(peer_id) {
+            debug!("[PeerManager::record_inbound] Added inbound peer {:?}", peer_id);
+            let best = self.address_book.best(&peer_id).cloned();
+            self.changed(peer_id, true, best);
+        }
+    }
+
+    /// Records a failed dial of `addr`. Addresses that keep failing are
+    /// forgotten.
+    pub fn record_dial_failure(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        let best = self.address_book.best(peer_id).cloned();
+        self.address_book.record_failure(peer_id, addr);
+        self.changed(*peer_id, false, best);
+    }
+
+    /// Stores what `peer_id` reported over Identify and records its listen
+    /// addresses.
+    pub fn record_identify(&mut self, peer_id: PeerId, metadata: PeerMetadata) {
+        if peer_id == self.local_peer_id {
             return;
         }
-        self.peers.insert(peer_id.clone());
-        self.peer_addresses.insert(peer_id, addr);
         debug!(
-            "[PeerManager::add_peer_with_addr] Current peers: {:?}",
-            self.peers
+            "[PeerManager::record_identify] {:?} runs {} with {} protocols",
+            peer_id,
+            metadata.agent_version,
+            metadata.protocols.len()
         );
-        self.save_to_disk();
-    }
-
-    fn save_to_disk(&self) {
-        let storage = PeerStorage {
-            peers: self
-                .peers
-                .iter()
-                .map(|p| p.to_base58().to_string())
-                .collect(),
-            addresses: self
-                .peer_addresses
-                .iter()
-                .map(|(p, a)| (p.to_base58().to_string(), a.to_string()))
-                .collect(),
-        };
+        let best = self.address_book.best(&peer_id).cloned();
+        let new_peer = self.peers.insert(peer_id);
+        for addr in &metadata.listen_addrs {
+            self.address_book.add(peer_id, addr.clone(), AddressSource::Identify);
+        }
+        self.metadata.insert(peer_id, metadata);
+        self.changed(peer_id, new_peer, best);
+    }
+
+    /// The Identify information last received from `peer_id`.
+    pub fn metadata(&self, peer_id: &PeerId) -> Option<&PeerMetadata> {
+        self.metadata.get(peer_id)
+    }
+
+    /// Known peers that announced support for `protocol` over Identify.
+    pub fn peers_supporting(&self, protocol: &str) -> Vec<PeerId> {
+        self.metadata
+            .iter()
+            .filter(|(_, metadata)| metadata.supports(protocol))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+    /// Addresses of `peer_id` in the order they should be dialed.
+    pub fn dial_candidates(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.address_book.dial_candidates(peer_id)
+    }
+
+    /// Subscribes to peers being added, removed or getting a new best
+    /// address.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, event: PeerEvent) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    /// Persists a change to `peer_id` and reports it, given whether the peer
+    /// is new and what its best address was before.
+    fn changed(&mut self, peer_id: PeerId, new_peer: bool, best_before: Option<Multiaddr>) {
+        self.mark_dirty(peer_id);
+        if new_peer {
+            self.notify(PeerEvent::Added(peer_id));
+        }
+        let best = self.address_book.best(&peer_id);
+        if best != best_before.as_ref() {
+            let address = best.cloned();
+            self.notify(PeerEvent::AddressChanged {
+                peer: peer_id,
+                address,
+            });
+        }
+    }
+
+    /// Schedules `peer_id` to be written to the store. The first change after
+    /// a quiet period is written immediately, later ones are batched until
+    /// `flush_interval` has passed.
+    fn mark_dirty(&mut self, peer_id: PeerId) {
+        self.dirty.insert(peer_id);
+        self.flush_if_due();
+    }
+
+    /// Writes pending changes if `flush_interval` has passed since the last
+    /// write. The node calls this periodically.
+    pub fn flush_if_due(&mut self) {
+        let due = self
+            .last_flush
+            .is_none_or(|last| last.elapsed() >= self.flush_interval);
+        if !self.dirty.is_empty() && due {
+            if let Err(e) = self.flush() {
+                error!("Failed to save peer storage: {}", ErrorChain(&e));
+            }
+        }
+    }
 
-        if let Err(e) = storage.save_to_disk(&self.local_peer_id) {
-            error!("Failed to save peer storage: {}", e);
-        } else {
-            info!(
-                "Successfully saved peers to disk for {:?}",
-                self.local_peer_id
-            );
+    /// Writes the peers changed since the last flush to the store now.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        for peer_id in &self.dirty {
+            if self.peers.contains(peer_id) {
+                self.store.upsert(&StoredPeer {
+                    peer_id: *peer_id,
+                    addresses: self.address_book.records(peer_id).to_vec(),
+                    metadata: self.metadata.get(peer_id).cloned(),
+                })?;
+            } else {
+                self.store.delete(peer_id)?;
+            }
         }
+        self.store.flush()?;
+        debug!(
+            "Saved {} changed peers of {:?}",
+            self.dirty.len(),
+            self.local_peer_id
+        );
+        self.dirty.clear();
+        self.last_flush = Some(Instant::now());
+        Ok(())
     }
 
+    /// The best known address of `peer_id`.
     pub fn get_peer_address(&self, peer_id: &PeerId) -> Option<&Multiaddr> {
-        self.peer_addresses.get(peer_id)
+        self.address_book.best(peer_id)
+    }
+
+    /// Folds a successful ping into the peer's smoothed RTT and clears its
+    /// missed-ping count.
+    pub fn record_ping(&mut self, peer_id: PeerId, rtt: Duration) {
+        let stats = self.ping_stats.entry(peer_id).or_default();
+        stats.rtt = Some(match stats.rtt {
+            Some(srtt) => srtt.mul_f64(1.0 - RTT_SMOOTHING) + rtt.mul_f64(RTT_SMOOTHING),
+            None => rtt,
+        });
+        stats.missed_pings = 0;
+        debug!(
+            "[PeerManager::record_ping] {:?} rtt {:?}, smoothed {:?}",
+            peer_id, rtt, stats.rtt
+        );
+    }
+
+    /// Records a failed ping and returns the number of consecutive misses.
+    pub fn record_ping_failure(&mut self, peer_id: PeerId) -> u32 {
+        let stats = self.ping_stats.entry(peer_id).or_default();
+        stats.missed_pings += 1;
+        debug!(
+            "[PeerManager::record_ping_failure] {:?} missed {} pings",
+            peer_id, stats.missed_pings
+        );
+        stats.missed_pings
+    }
+
+    /// Returns what is known about `peer_id`, or `None` for an unknown peer.
+    pub fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        let stats = self.ping_stats.get(peer_id);
+        if !self.peers.contains(peer_id) && stats.is_none() {
+            return None;
+        }
+        let stats = stats.cloned().unwrap_or_default();
+        Some(PeerInfo {
+            peer_id: *peer_id,
+            addresses: self.address_book.dial_candidates(peer_id),
+            rtt: stats.rtt,
+            missed_pings: stats.missed_pings,
+            metadata: self.metadata.get(peer_id).cloned(),
+        })
+    }
+}
+
+/// Returns true if `addr` reaches the peer through a circuit relay
+/// (i.e. it contains a `/p2p-circuit` component).
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::P2pCircuit))
+}
+
+impl Drop for PeerManager {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to save peer storage: {}", ErrorChain(&e));
+        }
     }
 }
 
@@ -203,4 +441,68 @@ impl PeerManagement for PeerManager {
     fn get_peers(&self) -> Vec<PeerId> {
         PeerManager::get_peers(self)
     }
+
+    fn remove_peer(&mut self, peer_id: &PeerId) -> bool {
+        PeerManager::remove_peer(self, peer_id)
+    }
+
+    fn get_address(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+        self.get_peer_address(peer_id).cloned()
+    }
+
+    fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr, source: AddressSource) {
+        PeerManager::add_address(self, peer_id, addr, source)
+    }
+
+    fn dial_candidates(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        PeerManager::dial_candidates(self, peer_id)
+    }
+
+    fn on_connected(&mut self, peer_id: PeerId, endpoint: &ConnectedPoint) {
+        // Dialed connections are recorded by `on_dial_success`.
+        if endpoint.is_listener() {
+            self.record_inbound(peer_id);
+        }
+    }
+
+    fn on_disconnected(&mut self, peer_id: PeerId) {
+        // RTT measured on a closed connection says little about the next one.
+        self.ping_stats.remove(&peer_id);
+    }
+
+    fn on_dial_success(&mut self, peer_id: PeerId, addr: &Multiaddr) {
+        self.record_dial_success(peer_id, addr.clone())
+    }
+
+    fn on_dial_failure(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        self.record_dial_failure(peer_id, addr)
+    }
+
+    fn on_identify(&mut self, peer_id: PeerId, metadata: PeerMetadata) {
+        self.record_identify(peer_id, metadata)
+    }
+
+    fn on_ping(&mut self, peer_id: PeerId, rtt: Duration) {
+        self.record_ping(peer_id, rtt)
+    }
+
+    fn on_ping_failure(&mut self, peer_id: PeerId) -> u32 {
+        self.record_ping_failure(peer_id)
+    }
+
+    fn on_tick(&mut self) {
+        self.flush_if_due()
+    }
+
+    fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        PeerManager::peer_info(self, peer_id)
+    }
+
+    fn peers_supporting(&self, protocol: &str) -> Vec<PeerId> {
+        PeerManager::peers_supporting(self, protocol)
+    }
+
+    fn peer_events(&self) -> Option<broadcast::Sender<PeerEvent>> {
+        Some(self.events.clone())
+    }
 }
\ No newline at end of file
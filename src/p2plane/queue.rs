@@ -0,0 +1,388 @@
+//! Bounded per-peer queues for outgoing messages.
+//!
+//! Every peer has a queue per [`Priority`] class, each holding up to
+//! `capacity` messages. What happens to a message that arrives while its
+//! queue is full is decided by the [`OverflowPolicy`].
+//!
+//! At most `max_in_flight` messages per peer are handed to the
+//! request-response behaviour at a time. When a slot frees up the next
+//! message is picked by weighted round robin: while all classes have
+//! messages waiting, each round sends up to `weights.high` high-priority,
+//! `weights.normal` normal and `weights.low` low-priority messages, so
+//! urgent traffic goes first without starving bulk traffic. With
+//! `high_priority_protocol` enabled, high-priority messages are sent over a
+//! protocol of their own with separate in-flight slots, so a backlog of bulk
+//! messages cannot hold them up at all.
+
+use crate::p2plane::{P2PlaneError, Result};
+use libp2p::{futures::channel::oneshot, request_response::OutboundRequestId, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Priority class of a message, see
+/// [`Message::priority`](crate::p2plane::traits::Message::priority).
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Latency-critical messages such as consensus votes and certificates.
+    High = 0,
+    #[default]
+    Normal = 1,
+    /// Bulk traffic such as transaction gossip.
+    Low = 2,
+}
+
+impl Priority {
+    /// Every class, most urgent first.
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+}
+
+/// What happens to a message sent to a peer whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Hold the message back and make the sender wait until the queue has
+    /// room. Only callers going through a
+    /// [`NodeHandle`](crate::p2plane::NodeHandle) can wait; messages sent
+    /// directly on the node are rejected with [`P2PlaneError::QueueFull`].
+    #[default]
+    Block,
+    /// Drop the oldest queued message of the same class to make room.
+    DropOldest,
+    /// Reject the message with [`P2PlaneError::QueueFull`].
+    Error,
+}
+
+/// Messages of each class sent per round of weighted round robin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriorityWeights {
+    pub high: u32,
+    pub normal: u32,
+    pub low: u32,
+}
+
+impl PriorityWeights {
+    pub fn get(&self, priority: Priority) -> u32 {
+        match priority {
+            Priority::High => self.high,
+            Priority::Normal => self.normal,
+            Priority::Low => self.low,
+        }
+    }
+}
+
+impl Default for PriorityWeights {
+    fn default() -> Self {
+        Self {
+            high: 16,
+            normal: 4,
+            low: 1,
+        }
+    }
+}
+
+/// Limits of the outbound message queues.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundQueueConfig {
+    /// Messages of each priority class that may wait per peer on top of
+    /// those in flight.
+    pub capacity: usize,
+    /// Messages per peer sent and not yet answered. High-priority messages
+    /// have slots of their own when `high_priority_protocol` is enabled.
+    pub max_in_flight: usize,
+    pub overflow: OverflowPolicy,
+    pub weights: PriorityWeights,
+    /// Send high-priority messages over a separate protocol. Peers that do
+    /// not support it cannot receive them.
+    pub high_priority_protocol: bool,
+}
+
+impl Default for OutboundQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            max_in_flight: 16,
+            overflow: OverflowPolicy::Block,
+            weights: PriorityWeights::default(),
+            high_priority_protocol: false,
+        }
+    }
+}
+
+/// Current state of the outbound queue of one peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    /// Messages waiting to be sent.
+    pub queued: usize,
+    /// Messages waiting to be sent, by class in the order of
+    /// [`Priority::ALL`].
+    pub queued_by_priority: [usize; 3],
+    /// Messages sent and not yet answered.
+    pub in_flight: usize,
+    /// Messages waiting for room in the queue under
+    /// [`OverflowPolicy::Block`].
+    pub blocked: usize,
+    /// Messages dropped under [`OverflowPolicy::DropOldest`].
+    pub dropped: u64,
+}
+
+/// Behaviour a message is sent with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Lane {
+    /// The message protocol shared by all classes.
+    Shared = 0,
+    /// The protocol reserved for high-priority messages.
+    HighPriority = 1,
+}
+
+/// Caller of a send that is held back until all of its messages are queued.
+#[derive(Debug)]
+struct Waiter {
+    blocked: usize,
+    /// Set by [`OutboundQueues::release`] once every message was pushed.
+    result: Option<Result<()>>,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Debug)]
+struct ClassQueue<M> {
+    queued: VecDeque<M>,
+    blocked: VecDeque<(M, u64)>,
+}
+
+impl<M> Default for ClassQueue<M> {
+    fn default() -> Self {
+        Self {
+            queued: VecDeque::new(),
+            blocked: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerQueue<M> {
+    classes: [ClassQueue<M>; 3],
+    /// Messages in flight per [`Lane`].
+    in_flight: [usize; 2],
+    /// Messages each class may still send in the current round.
+    credits: [u32; 3],
+    dropped: u64,
+}
+
+impl<M> Default for PeerQueue<M> {
+    fn default() -> Self {
+        Self {
+            classes: Default::default(),
+            in_flight: [0; 2],
+            credits: [0; 3],
+            dropped: 0,
+        }
+    }
+}
+
+/// Outbound queues of every peer.
+#[derive(Debug)]
+pub(crate) struct OutboundQueues<M> {
+    config: OutboundQueueConfig,
+    peers: HashMap<PeerId, PeerQueue<M>>,
+    requests: HashMap<(Lane, OutboundRequestId), PeerId>,
+    waiters: HashMap<u64, Waiter>,
+    next_waiter: u64,
+}
+
+impl<M> OutboundQueues<M> {
+    pub fn new(config: OutboundQueueConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+            requests: HashMap::new(),
+            waiters: HashMap::new(),
+            next_waiter: 0,
+        }
+    }
+
+    /// Registers the caller of a send. Pass the returned id to
+    /// [`push`](Self::push) and hand it to [`release`](Self::release) once
+    /// every message has been pushed.
+    pub fn waiter(&mut self, reply: oneshot::Sender<Result<()>>) -> u64 {
+        let id = self.next_waiter;
+        self.next_waiter += 1;
+        self.waiters.insert(
+            id,
+            Waiter {
+                blocked: 0,
+                result: None,
+                reply,
+            },
+        );
+        id
+    }
+
+    /// Replies to the caller `waiter` with `result` unless some of its
+    /// messages are still blocked; it is answered once they are queued.
+    pub fn release(&mut self, waiter: u64, result: Result<()>) {
+        if let Some(w) = self.waiters.get_mut(&waiter) {
+            w.result = Some(result);
+        }
+        self.answer_if_done(waiter);
+    }
+
+    fn unblock(&mut self, waiter: u64) {
+        if let Some(w) = self.waiters.get_mut(&waiter) {
+            w.blocked -= 1;
+        }
+        self.answer_if_done(waiter);
+    }
+
+    fn answer_if_done(&mut self, waiter: u64) {
+        let done = self
+            .waiters
+            .get(&waiter)
+            .is_some_and(|w| w.blocked == 0 && w.result.is_some());
+        if done {
+            let w = self
+                .waiters
+                .remove(&waiter)
+                .expect("waiter was just looked up");
+            let _ = w.reply.send(w.result.expect("waiter is done"));
+        }
+    }
+
+    /// Queues `message` for `peer`, applying the overflow policy if the
+    /// queue of its class is full. Under [`OverflowPolicy::Block`] only a
+    /// message with a `waiter` can be held back; others are rejected.
+    pub fn push(
+        &mut self,
+        peer: PeerId,
+        priority: Priority,
+        message: M,
+        waiter: Option<u64>,
+    ) -> Result<()> {
+        let queue = self.peers.entry(peer).or_default();
+        let class = &mut queue.classes[priority as usize];
+        if class.queued.len() < self.config.capacity && class.blocked.is_empty() {
+            class.queued.push_back(message);
+            return Ok(());
+        }
+        match self.config.overflow {
+            OverflowPolicy::Block => {
+                let Some(waiter) = waiter else {
+                    return Err(P2PlaneError::QueueFull { peer });
+                };
+                class.blocked.push_back((message, waiter));
+                if let Some(waiter) = self.waiters.get_mut(&waiter) {
+                    waiter.blocked += 1;
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                if class.queued.pop_front().is_some() {
+                    class.queued.push_back(message);
+                }
+                queue.dropped += 1;
+            }
+            OverflowPolicy::Error => return Err(P2PlaneError::QueueFull { peer }),
+        }
+        Ok(())
+    }
+
+    /// Takes the next message for `peer` and the lane to send it on, if a
+    /// lane has fewer than `max_in_flight` messages in flight. Report the
+    /// request it was sent as with [`sent`](Self::sent).
+    pub fn next(&mut self, peer: &PeerId) -> Option<(M, Lane)> {
+        let max_in_flight = self.config.max_in_flight;
+        let dedicated = self.config.high_priority_protocol;
+        let weights = self.config.weights;
+        let queue = self.peers.get_mut(peer)?;
+
+        let high_waiting = !queue.classes[Priority::High as usize].queued.is_empty();
+        let (priority, lane) = if dedicated
+            && high_waiting
+            && queue.in_flight[Lane::HighPriority as usize] < max_in_flight
+        {
+            (Priority::High, Lane::HighPriority)
+        } else if queue.in_flight[Lane::Shared as usize] < max_in_flight {
+            let waiting: Vec<Priority> = Priority::ALL
+                .into_iter()
+                .filter(|&p| !(dedicated && p == Priority::High))
+                .filter(|&p| !queue.classes[p as usize].queued.is_empty())
+                .collect();
+            if waiting.iter().all(|&p| queue.credits[p as usize] == 0) {
+                for p in Priority::ALL {
+                    queue.credits[p as usize] = weights.get(p);
+                }
+            }
+            let priority = waiting
+                .into_iter()
+                .find(|&p| queue.credits[p as usize] > 0)?;
+            queue.credits[priority as usize] -= 1;
+            (priority, Lane::Shared)
+        } else {
+            return None;
+        };
+
+        let class = &mut queue.classes[priority as usize];
+        let message = class.queued.pop_front()?;
+        queue.in_flight[lane as usize] += 1;
+        if let Some((blocked, waiter)) = class.blocked.pop_front() {
+            class.queued.push_back(blocked);
+            self.unblock(waiter);
+        }
+        Some((message, lane))
+    }
+
+    pub fn sent(&mut self, peer: PeerId, lane: Lane, request_id: OutboundRequestId) {
+        self.requests.insert((lane, request_id), peer);
+    }
+
+    /// Marks `request_id` on `lane` as answered or failed. Returns its peer,
+    /// which may have room for the next message now.
+    pub fn completed(&mut self, lane: Lane, request_id: OutboundRequestId) -> Option<PeerId> {
+        let peer = self.requests.remove(&(lane, request_id))?;
+        if let Some(queue) = self.peers.get_mut(&peer) {
+            let in_flight = &mut queue.in_flight[lane as usize];
+            *in_flight = in_flight.saturating_sub(1);
+        }
+        Some(peer)
+    }
+
+    /// Drops everything queued for `peer`. Callers held back by its blocked
+    /// messages are released.
+    pub fn remove(&mut self, peer: &PeerId) {
+        let Some(queue) = self.peers.remove(peer) else {
+            return;
+        };
+        let waiters: Vec<u64> = queue
+            .classes
+            .into_iter()
+            .flat_map(|class| class.blocked)
+            .map(|(_, waiter)| waiter)
+            .collect();
+        for waiter in waiters {
+            self.unblock(waiter);
+        }
+    }
+
+    pub fn depth(&self, peer: &PeerId) -> Option<QueueDepth> {
+        self.peers.get(peer).map(|queue| {
+            let queued_by_priority = Priority::ALL.map(|p| queue.classes[p as usize].queued.len());
+            QueueDepth {
+                queued: queued_by_priority.iter().sum(),
+                queued_by_priority,
+                in_flight: queue.in_flight.iter().sum(),
+                blocked: queue.classes.iter().map(|class| class.blocked.len()).sum(),
+                dropped: queue.dropped,
+            }
+        })
+    }
+
+    pub fn depths(&self) -> HashMap<PeerId, QueueDepth> {
+        self.peers
+            .keys()
+            .filter_map(|peer| Some((*peer, self.depth(peer)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,328 @@
+//! JSON file backend of [`PeerStore`].
+//!
+//! Peers are stored as JSON in `<data_dir>/peers_<peer-id>.json`. Writes go
+//! to a temporary file that is synced and then renamed over the old one, so
+//! a crash leaves either the old or the new contents behind. Every file
+//! carries a schema `version`: older files are migrated when loaded, and
+//! files that cannot be read are moved aside to
+//! `<file>.corrupt-<unix-time>` rather than being overwritten.
+//!
+//! The whole file is rewritten on every flush, which is fine for a few
+//! hundred peers. Larger deployments should use the SQLite store.
+
+use super::{PeerStore, StoredPeer};
+use crate::p2plane::{
+    address_book::{AddressRecord, AddressSource},
+    P2PlaneError, PeerMetadata, Result,
+};
+use libp2p::{Multiaddr, PeerId};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the storage format written by this release.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Contents of a peer storage file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerStorage {
+    pub(crate) version: u32,
+    pub(crate) peers: HashSet<String>, // Store peer IDs as strings
+    #[serde(default)]
+    pub(crate) address_book: HashMap<String, Vec<AddressRecord>>,
+    /// Identify information, keyed by peer ID.
+    #[serde(default)]
+    pub(crate) metadata: HashMap<String, PeerMetadata>,
+}
+
+impl Default for PeerStorage {
+    fn default() -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            peers: HashSet::new(),
+            address_book: HashMap::new(),
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+impl PeerStorage {
+    /// Path of the storage file of `peer_id` in `data_dir`.
+    pub fn path(data_dir: &Path, peer_id: &PeerId) -> PathBuf {
+        data_dir.join(format!("peers_{}.json", peer_id.to_base58()))
+    }
+
+    /// Loads the storage of `peer_id` from the working directory.
+    pub fn new(peer_id: &PeerId) -> Self {
+        Self::load(&Self::path(Path::new("."), peer_id))
+    }
+
+    /// Loads `path`, migrating files written by older versions. A missing
+    /// file gives empty storage. A file that cannot be parsed is moved aside
+    /// and also gives empty storage.
+    pub fn load(path: &Path) -> Self {
+        info!("Loading peer storage from {}", path.display());
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!(
+                    "No existing storage found, creating new one at {}",
+                    path.display()
+                );
+                return PeerStorage::default();
+            }
+            Err(e) => {
+                error!(
+                    "Failed to read {}: {}. Creating new storage.",
+                    path.display(),
+                    e
+                );
+                return PeerStorage::default();
+            }
+        };
+
+        match Self::parse(&content) {
+            Ok(storage) => {
+                info!("Successfully loaded peer storage from {}", path.display());
+                storage
+            }
+            Err(e) => {
+                match back_up_corrupt(path) {
+                    Ok(backup) => error!(
+                        "Failed to parse {}: {}. Moved it to {} and created new storage.",
+                        path.display(),
+                        e,
+                        backup.display()
+                    ),
+                    Err(backup_error) => error!(
+                        "Failed to parse {}: {}. Moving it aside failed too: {}",
+                        path.display(),
+                        e,
+                        backup_error
+                    ),
+                }
+                PeerStorage::default()
+            }
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut value: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        let version = match value.get("version") {
+            // Files written before the version field was introduced.
+            None => 1,
+            Some(version) => version
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| format!("invalid schema version {}", version))?,
+        };
+        if version == 0 || version > SCHEMA_VERSION {
+            return Err(format!(
+                "schema version {} is not supported (expected at most {})",
+                version, SCHEMA_VERSION
+            ));
+        }
+        for from in version..SCHEMA_VERSION {
+            info!(
+                "Migrating peer storage from version {} to {}",
+                from,
+                from + 1
+            );
+            MIGRATIONS[(from - 1) as usize](&mut value)?;
+        }
+        value["version"] = json!(SCHEMA_VERSION);
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    /// Saves the storage of `peer_id` to the working directory.
+    pub fn save_to_disk(&self, peer_id: &PeerId) -> Result<(), io::Error> {
+        self.save(&Self::path(Path::new("."), peer_id))
+    }
+
+    /// Atomically replaces `path` with the current contents, creating its
+    /// directory if needed.
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let tmp = path.with_extension("json.tmp");
+        let content = serde_json::to_vec_pretty(self)?;
+        let mut file = File::create(&tmp)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, path)?;
+        // Make the rename itself durable.
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// The stored peers. Entries whose peer ID cannot be parsed are skipped.
+    pub fn to_peers(&self) -> Vec<StoredPeer> {
+        let ids: BTreeSet<&String> = self
+            .peers
+            .iter()
+            .chain(self.address_book.keys())
+            .chain(self.metadata.keys())
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| match id.parse::<PeerId>() {
+                Ok(peer_id) => Some(StoredPeer {
+                    peer_id,
+                    addresses: self.address_book.get(id).cloned().unwrap_or_default(),
+                    metadata: self.metadata.get(id).cloned(),
+                }),
+                Err(e) => {
+                    error!("Skipping stored peer with invalid ID {}: {}", id, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn upsert(&mut self, peer: &StoredPeer) {
+        let id = peer.peer_id.to_base58();
+        self.remove(&peer.peer_id);
+        if !peer.addresses.is_empty() {
+            self.address_book.insert(id.clone(), peer.addresses.clone());
+        }
+        if let Some(metadata) = &peer.metadata {
+            self.metadata.insert(id.clone(), metadata.clone());
+        }
+        self.peers.insert(id);
+    }
+
+    fn remove(&mut self, peer_id: &PeerId) -> bool {
+        let id = peer_id.to_base58();
+        let known = self.peers.remove(&id);
+        let addresses = self.address_book.remove(&id).is_some();
+        let metadata = self.metadata.remove(&id).is_some();
+        known || addresses || metadata
+    }
+}
+
+/// [`PeerStore`] that keeps all peers in one JSON file. Changes are kept in
+/// memory until [`flush`](PeerStore::flush), which atomically rewrites the
+/// file.
+#[derive(Debug)]
+pub struct JsonFileStore {
+    path: PathBuf,
+    storage: PeerStorage,
+    changed: bool,
+}
+
+impl JsonFileStore {
+    /// Opens the store at `path`. See [`PeerStorage::load`] for how missing
+    /// and unreadable files are handled.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let storage = PeerStorage::load(&path);
+        Self {
+            path,
+            storage,
+            changed: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl PeerStore for JsonFileStore {
+    fn load(&mut self) -> Result<Vec<StoredPeer>> {
+        Ok(self.storage.to_peers())
+    }
+
+    fn save(&mut self, peers: &[StoredPeer]) -> Result<()> {
+        self.storage = PeerStorage::default();
+        for peer in peers {
+            self.storage.upsert(peer);
+        }
+        self.changed = true;
+        self.flush()
+    }
+
+    fn upsert(&mut self, peer: &StoredPeer) -> Result<()> {
+        self.storage.upsert(peer);
+        self.changed = true;
+        Ok(())
+    }
+
+    fn delete(&mut self, peer_id: &PeerId) -> Result<()> {
+        self.changed |= self.storage.remove(peer_id);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        self.storage
+            .save(&self.path)
+            .map_err(P2PlaneError::storage)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Moves an unreadable storage file out of the way so it is not overwritten.
+fn back_up_corrupt(path: &Path) -> io::Result<PathBuf> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".corrupt-{}", secs));
+    let backup = PathBuf::from(backup);
+    fs::rename(path, &backup)?;
+    Ok(backup)
+}
+
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` upgrades a file from version `n + 1` to `n + 2`.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize - 1] = [v1_to_v2];
+
+/// Version 1 kept a single address per peer in `addresses`. Version 2 keeps
+/// an address book per peer; the old addresses become dialed addresses.
+fn v1_to_v2(value: &mut Value) -> Result<(), String> {
+    let object = value
+        .as_object_mut()
+        .ok_or("peer storage is not a JSON object")?;
+    let addresses = object.remove("addresses");
+    let book = object
+        .entry("address_book")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or("address_book is not a JSON object")?;
+
+    let Some(Value::Object(addresses)) = addresses else {
+        return Ok(());
+    };
+    let now = SystemTime::now();
+    for (peer, addr) in addresses {
+        let Some(addr) = addr.as_str().and_then(|a| a.parse::<Multiaddr>().ok()) else {
+            error!("Dropping invalid address {} of peer {}", addr, peer);
+            continue;
+        };
+        let record = AddressRecord::new(addr, AddressSource::Dialed, now);
+        let record = serde_json::to_value(record).map_err(|e| e.to_string())?;
+        match book.entry(peer).or_insert_with(|| json!([])) {
+            Value::Array(records) => records.push(record),
+            _ => return Err("address_book entries must be arrays".to_string()),
+        }
+    }
+    Ok(())
+}
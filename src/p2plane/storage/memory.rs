@@ -0,0 +1,64 @@
+//! In-memory backend of [`PeerStore`].
+
+use super::{PeerStore, StoredPeer};
+use crate::p2plane::Result;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// [`PeerStore`] that keeps peers in memory only.
+///
+/// Clones share their contents, so a test can keep a clone to inspect what
+/// a manager stored or to hand it to the next manager as if it had been
+/// restarted.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    peers: Arc<Mutex<HashMap<PeerId, StoredPeer>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What is stored about `peer_id`.
+    pub fn get(&self, peer_id: &PeerId) -> Option<StoredPeer> {
+        self.peers().get(peer_id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers().is_empty()
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, StoredPeer>> {
+        // A panic while holding the lock cannot leave the map half-updated.
+        self.peers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl PeerStore for MemoryStore {
+    fn load(&mut self) -> Result<Vec<StoredPeer>> {
+        Ok(self.peers().values().cloned().collect())
+    }
+
+    fn save(&mut self, peers: &[StoredPeer]) -> Result<()> {
+        *self.peers() = peers.iter().map(|p| (p.peer_id, p.clone())).collect();
+        Ok(())
+    }
+
+    fn upsert(&mut self, peer: &StoredPeer) -> Result<()> {
+        self.peers().insert(peer.peer_id, peer.clone());
+        Ok(())
+    }
+
+    fn delete(&mut self, peer_id: &PeerId) -> Result<()> {
+        self.peers().remove(peer_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Persistence of the peers a [`PeerManager`] has learned about.
+//!
+//! The manager talks to a [`PeerStore`], so the backend can be chosen per
+//! deployment: a [`JsonFileStore`] for small nodes, a [`SqliteStore`]
+//! (`sqlite` feature) that only rewrites the rows of peers that changed, or a
+//! [`MemoryStore`] for tests.
+//!
+//! [`PeerManager`]: crate::p2plane::PeerManager
+
+pub mod json;
+pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+pub use json::{JsonFileStore, PeerStorage, SCHEMA_VERSION};
+pub use memory::MemoryStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+#[cfg(not(feature = "sqlite"))]
+use crate::p2plane::P2PlaneError;
+use crate::p2plane::{address_book::AddressRecord, PeerMetadata, Result};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::Path};
+
+/// Where a [`PeerManager`](crate::p2plane::PeerManager) keeps the peers it
+/// learns about.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerStorageKind {
+    /// Persist to `peers_<peer-id>.json` in `NodeConfig::data_dir`.
+    #[default]
+    File,
+    /// Keep peers in memory only. Nothing is read from or written to disk,
+    /// which keeps tests that run many nodes independent of each other.
+    Memory,
+    /// Persist to the SQLite database `peers_<peer-id>.sqlite` in
+    /// `NodeConfig::data_dir`. Requires the `sqlite` feature.
+    Sqlite,
+}
+
+impl PeerStorageKind {
+    /// Opens the store of `local_peer_id` in `data_dir`.
+    pub fn open(self, data_dir: &Path, local_peer_id: &PeerId) -> Result<Box<dyn PeerStore>> {
+        Ok(match self {
+            PeerStorageKind::File => Box::new(JsonFileStore::open(PeerStorage::path(
+                data_dir,
+                local_peer_id,
+            ))),
+            PeerStorageKind::Memory => Box::new(MemoryStore::new()),
+            #[cfg(feature = "sqlite")]
+            PeerStorageKind::Sqlite => Box::new(SqliteStore::open(SqliteStore::path(
+                data_dir,
+                local_peer_id,
+            ))?),
+            #[cfg(not(feature = "sqlite"))]
+            PeerStorageKind::Sqlite => {
+                return Err(P2PlaneError::config(
+                    "peer_storage: sqlite requires the `sqlite` feature",
+                ))
+            }
+        })
+    }
+}
+
+/// Opens the store file at `path`: a SQLite database if it ends in
+/// `.sqlite` or `.db`, a JSON file otherwise.
+pub fn open_path(path: &Path) -> Result<Box<dyn PeerStore>> {
+    let sqlite = path
+        .extension()
+        .is_some_and(|ext| ext == "sqlite" || ext == "db");
+    if !sqlite {
+        return Ok(Box::new(JsonFileStore::open(path)));
+    }
+    #[cfg(feature = "sqlite")]
+    return Ok(Box::new(SqliteStore::open(path)?));
+    #[cfg(not(feature = "sqlite"))]
+    Err(P2PlaneError::config(format!(
+        "{}: SQLite peer stores require the `sqlite` feature",
+        path.display()
+    )))
+}
+
+/// Everything persisted about one peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPeer {
+    pub peer_id: PeerId,
+    /// Known addresses, best first.
+    #[serde(default)]
+    pub addresses: Vec<AddressRecord>,
+    /// What the peer last reported over Identify.
+    #[serde(default)]
+    pub metadata: Option<PeerMetadata>,
+}
+
+impl StoredPeer {
+    pub fn new(peer_id: PeerId) -> Self {
+        Self {
+            peer_id,
+            addresses: Vec::new(),
+            metadata: None,
+        }
+    }
+}
+
+/// Backend that persists what a [`PeerManager`] learns about peers.
+///
+/// The manager loads every peer when it starts. Afterwards it only writes
+/// the peers that changed, calling [`upsert`](PeerStore::upsert) or
+/// [`delete`](PeerStore::delete) for each of them followed by
+/// [`flush`](PeerStore::flush). Stores may buffer changes until `flush`.
+///
+/// [`PeerManager`]: crate::p2plane::PeerManager
+pub trait PeerStore: Send + Sync + fmt::Debug {
+    /// Every stored peer.
+    fn load(&mut self) -> Result<Vec<StoredPeer>>;
+
+    /// Replaces the contents of the store with `peers`.
+    fn save(&mut self, peers: &[StoredPeer]) -> Result<()>;
+
+    /// Inserts `peer`, or replaces what is stored about it.
+    fn upsert(&mut self, peer: &StoredPeer) -> Result<()>;
+
+    /// Removes `peer_id`. Removing an unknown peer is not an error.
+    fn delete(&mut self, peer_id: &PeerId) -> Result<()>;
+
+    /// Makes buffered changes durable.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+//! SQLite backend of [`PeerStore`]. Enabled by the `sqlite` feature.
+//!
+//! Each peer is one row, so a change only rewrites the rows of the peers
+//! involved. Changes are collected in a transaction that is committed on
+//! [`flush`](PeerStore::flush), and the database runs in WAL mode so a crash
+//! never leaves a partially written batch behind.
+
+use super::{PeerStore, StoredPeer};
+use crate::p2plane::{P2PlaneError, Result};
+use libp2p::PeerId;
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+/// Version of the database schema, kept in `PRAGMA user_version`.
+const SCHEMA_VERSION: i32 = 1;
+
+/// [`PeerStore`] backed by an embedded SQLite database.
+#[derive(Debug)]
+pub struct SqliteStore {
+    /// Only locked by [`SqliteStore::get`]; the [`PeerStore`] methods take
+    /// `&mut self` and reach the connection directly.
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Path of the database of `peer_id` in `data_dir`.
+    pub fn path(data_dir: &Path, peer_id: &PeerId) -> PathBuf {
+        data_dir.join(format!("peers_{}.sqlite", peer_id.to_base58()))
+    }
+
+    /// Opens or creates the database at `path`, creating its directory if
+    /// needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(P2PlaneError::storage)?;
+        }
+        info!("Opening peer database {}", path.display());
+        let conn = Connection::open(path).map_err(P2PlaneError::storage)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(P2PlaneError::storage)?;
+        conn.pragma_update(None, "synchronous", "NORMAL")
+            .map_err(P2PlaneError::storage)?;
+        Self::init(conn)
+    }
+
+    /// Creates a database that lives in memory only.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory().map_err(P2PlaneError::storage)?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        let version: i32 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(P2PlaneError::storage)?;
+        match version {
+            0 => conn
+                .execute_batch(&format!(
+                    "CREATE TABLE IF NOT EXISTS peers (
+                         peer_id TEXT PRIMARY KEY,
+                         addresses TEXT NOT NULL,
+                         metadata TEXT
+                     );
+                     PRAGMA user_version = {};",
+                    SCHEMA_VERSION
+                ))
+                .map_err(P2PlaneError::storage)?,
+            SCHEMA_VERSION => {}
+            _ => {
+                return Err(P2PlaneError::storage(format!(
+                    "peer database schema version {} is not supported (expected {})",
+                    version, SCHEMA_VERSION
+                )))
+            }
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&mut self) -> &mut Connection {
+        self.conn.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts the transaction that collects changes until the next flush.
+    fn begin(&mut self) -> Result<()> {
+        if self.conn().is_autocommit() {
+            self.conn()
+                .execute_batch("BEGIN")
+                .map_err(P2PlaneError::storage)?;
+        }
+        Ok(())
+    }
+
+    /// What is stored about `peer_id`, including uncommitted changes.
+    pub fn get(&self, peer_id: &PeerId) -> Result<Option<StoredPeer>> {
+        self.conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .query_row(
+                "SELECT addresses, metadata FROM peers WHERE peer_id = ?1",
+                params![peer_id.to_base58()],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+            )
+            .optional()
+            .map_err(P2PlaneError::storage)?
+            .map(|(addresses, metadata)| decode(*peer_id, &addresses, metadata.as_deref()))
+            .transpose()
+    }
+}
+
+fn decode(peer_id: PeerId, addresses: &str, metadata: Option<&str>) -> Result<StoredPeer> {
+    Ok(StoredPeer {
+        peer_id,
+        addresses: serde_json::from_str(addresses).map_err(P2PlaneError::storage)?,
+        metadata: metadata
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(P2PlaneError::storage)?,
+    })
+}
+
+impl PeerStore for SqliteStore {
+    fn load(&mut self) -> Result<Vec<StoredPeer>> {
+        let mut statement = self
+            .conn()
+            .prepare("SELECT peer_id, addresses, metadata FROM peers")
+            .map_err(P2PlaneError::storage)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })
+            .map_err(P2PlaneError::storage)?;
+
+        let mut peers = Vec::new();
+        for row in rows {
+            let (peer_id, addresses, metadata) = row.map_err(P2PlaneError::storage)?;
+            let peer_id = peer_id.parse::<PeerId>().map_err(P2PlaneError::storage)?;
+            peers.push(decode(peer_id, &addresses, metadata.as_deref())?);
+        }
+        Ok(peers)
+    }
+
+    fn save(&mut self, peers: &[StoredPeer]) -> Result<()> {
+        self.begin()?;
+        self.conn()
+            .execute("DELETE FROM peers", [])
+            .map_err(P2PlaneError::storage)?;
+        for peer in peers {
+            self.upsert(peer)?;
+        }
+        self.flush()
+    }
+
+    fn upsert(&mut self, peer: &StoredPeer) -> Result<()> {
+        let addresses = serde_json::to_string(&peer.addresses).map_err(P2PlaneError::storage)?;
+        let metadata = peer
+            .metadata
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(P2PlaneError::storage)?;
+        self.begin()?;
+        self.conn()
+            .execute(
+                "INSERT INTO peers (peer_id, addresses, metadata) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (peer_id) DO UPDATE
+                 SET addresses = excluded.addresses, metadata = excluded.metadata",
+                params![peer.peer_id.to_base58(), addresses, metadata],
+            )
+            .map_err(P2PlaneError::storage)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, peer_id: &PeerId) -> Result<()> {
+        self.begin()?;
+        self.conn()
+            .execute(
+                "DELETE FROM peers WHERE peer_id = ?1",
+                params![peer_id.to_base58()],
+            )
+            .map_err(P2PlaneError::storage)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.conn().is_autocommit() {
+            self.conn()
+                .execute_batch("COMMIT")
+                .map_err(P2PlaneError::storage)?;
+        }
+        Ok(())
+    }
+}
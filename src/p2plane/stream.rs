@@ -0,0 +1,399 @@
+//! Raw libp2p streams for payloads that do not fit in a single
+//! request-response message.
+//!
+//! [`Streams`] is part of the node's behaviour. Applications use a
+//! [`StreamControl`] to open streams to peers and to accept the streams
+//! peers open for a protocol. Streams implement `AsyncRead` and
+//! `AsyncWrite`; flow control is provided by the muxer (yamux or QUIC), so a
+//! writer is slowed down to the pace of the remote reader.
+
+use crate::p2plane::{P2PlaneError, Result};
+use libp2p::{
+    core::{
+        upgrade::{InboundUpgrade, ReadyUpgrade, UpgradeInfo},
+        Endpoint,
+    },
+    futures::{
+        channel::{mpsc, oneshot},
+        future, Stream as FuturesStream,
+    },
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        handler::{
+            ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        },
+        ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, DialError,
+        FromSwarm, NetworkBehaviour, NotifyHandler, SubstreamProtocol, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId, Stream, StreamProtocol,
+};
+use log::debug;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+/// Inbound streams queued per accepted protocol. Streams arriving while the
+/// queue is full are dropped, which resets them on the remote side.
+pub const ACCEPT_QUEUE_CAPACITY: usize = 32;
+
+/// Request to open a stream, passed from a [`StreamControl`] to the
+/// behaviour and from there to a connection handler.
+#[derive(Debug)]
+pub struct OpenStream {
+    peer: PeerId,
+    protocol: StreamProtocol,
+    reply: oneshot::Sender<Result<Stream>>,
+}
+
+/// State shared by the behaviour, its connection handlers and every
+/// [`StreamControl`].
+#[derive(Debug, Default)]
+struct Shared {
+    accept: HashMap<StreamProtocol, mpsc::Sender<(PeerId, Stream)>>,
+    requests: VecDeque<OpenStream>,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn lock(shared: &Mutex<Shared>) -> std::sync::MutexGuard<'_, Shared> {
+        shared.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Protocols streams are accepted for. Protocols whose receiver was
+    /// dropped are forgotten here, so they are no longer announced.
+    fn protocols(&mut self) -> Vec<StreamProtocol> {
+        self.accept.retain(|_, sender| !sender.is_closed());
+        self.accept.keys().cloned().collect()
+    }
+
+    fn deliver(&mut self, peer: PeerId, protocol: StreamProtocol, stream: Stream) {
+        let Some(sender) = self.accept.get_mut(&protocol) else {
+            return;
+        };
+        if let Err(e) = sender.try_send((peer, stream)) {
+            if e.is_disconnected() {
+                self.accept.remove(&protocol);
+            } else {
+                debug!(
+                    "Dropping {} stream from {}: accept queue is full",
+                    protocol, peer
+                );
+            }
+        }
+    }
+}
+
+/// Opens and accepts streams. Cheap to clone; all clones talk to the same
+/// node.
+#[derive(Debug, Clone)]
+pub struct StreamControl {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl StreamControl {
+    /// Opens a stream to `peer` speaking `protocol`, dialing the peer first
+    /// if it is not connected.
+    pub async fn open_stream(&self, peer: PeerId, protocol: StreamProtocol) -> Result<Stream> {
+        let (reply, rx) = oneshot::channel();
+        {
+            let mut shared = Shared::lock(&self.shared);
+            shared.requests.push_back(OpenStream {
+                peer,
+                protocol: protocol.clone(),
+                reply,
+            });
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+        rx.await.map_err(|_| {
+            P2PlaneError::stream(format!(
+                "connection to {} closed while opening {}",
+                peer, protocol
+            ))
+        })?
+    }
+
+    /// Accepts the streams peers open for `protocol` from now on. The
+    /// protocol is announced over Identify until the returned receiver is
+    /// dropped. Fails if the protocol is already being accepted.
+    pub fn accept(&self, protocol: StreamProtocol) -> Result<IncomingStreams> {
+        let mut shared = Shared::lock(&self.shared);
+        if shared
+            .accept
+            .get(&protocol)
+            .is_some_and(|sender| !sender.is_closed())
+        {
+            return Err(P2PlaneError::stream(format!(
+                "{} is already accepted",
+                protocol
+            )));
+        }
+        let (sender, receiver) = mpsc::channel(ACCEPT_QUEUE_CAPACITY);
+        shared.accept.insert(protocol, sender);
+        Ok(IncomingStreams { receiver })
+    }
+}
+
+/// Streams opened by peers for an accepted protocol, with the peer that
+/// opened each of them.
+#[derive(Debug)]
+pub struct IncomingStreams {
+    receiver: mpsc::Receiver<(PeerId, Stream)>,
+}
+
+impl FuturesStream for IncomingStreams {
+    type Item = (PeerId, Stream);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Behaviour handing out raw streams, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct Streams {
+    shared: Arc<Mutex<Shared>>,
+    connected: HashMap<PeerId, HashSet<ConnectionId>>,
+    /// Requests waiting for a connection to their peer.
+    dialing: HashMap<PeerId, Vec<OpenStream>>,
+    events: VecDeque<ToSwarm<Infallible, OpenStream>>,
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn control(&self) -> StreamControl {
+        StreamControl {
+            shared: self.shared.clone(),
+        }
+    }
+
+    fn handler(&self, peer: PeerId) -> Handler {
+        Handler {
+            peer,
+            shared: self.shared.clone(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn route(&mut self, request: OpenStream) {
+        if self.connected.contains_key(&request.peer) {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: request.peer,
+                handler: NotifyHandler::Any,
+                event: request,
+            });
+            return;
+        }
+        let waiting = self.dialing.entry(request.peer).or_default();
+        if waiting.is_empty() {
+            self.events.push_back(ToSwarm::Dial {
+                opts: DialOpts::peer_id(request.peer)
+                    .condition(PeerCondition::DisconnectedAndNotDialing)
+                    .build(),
+            });
+        }
+        waiting.push(request);
+    }
+}
+
+impl NetworkBehaviour for Streams {
+    type ConnectionHandler = Handler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.handler(peer))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.handler(peer))
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(established) => {
+                let peer = established.peer_id;
+                self.connected
+                    .entry(peer)
+                    .or_default()
+                    .insert(established.connection_id);
+                for request in self.dialing.remove(&peer).unwrap_or_default() {
+                    self.route(request);
+                }
+            }
+            FromSwarm::ConnectionClosed(closed) => {
+                if let Some(connections) = self.connected.get_mut(&closed.peer_id) {
+                    connections.remove(&closed.connection_id);
+                    if connections.is_empty() {
+                        self.connected.remove(&closed.peer_id);
+                    }
+                }
+            }
+            FromSwarm::DialFailure(failure) => {
+                // A dial that was not started because one is already under
+                // way leaves the requests waiting for that one.
+                if matches!(failure.error, DialError::DialPeerConditionFalse(_)) {
+                    return;
+                }
+                let Some(peer) = failure.peer_id else {
+                    return;
+                };
+                for request in self.dialing.remove(&peer).unwrap_or_default() {
+                    let _ = request.reply.send(Err(P2PlaneError::stream(format!(
+                        "failed to dial {}: {}",
+                        peer, failure.error
+                    ))));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        let requests: Vec<_> = {
+            let mut shared = Shared::lock(&self.shared);
+            shared.waker = Some(cx.waker().clone());
+            shared.requests.drain(..).collect()
+        };
+        for request in requests {
+            self.route(request);
+        }
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Inbound upgrade accepting every protocol registered with
+/// [`StreamControl::accept`] at the time the stream arrives.
+#[derive(Debug)]
+pub struct AcceptUpgrade(Vec<StreamProtocol>);
+
+impl UpgradeInfo for AcceptUpgrade {
+    type Info = StreamProtocol;
+    type InfoIter = Vec<StreamProtocol>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.0.clone()
+    }
+}
+
+impl InboundUpgrade<Stream> for AcceptUpgrade {
+    type Output = (Stream, StreamProtocol);
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, stream: Stream, protocol: Self::Info) -> Self::Future {
+        future::ready(Ok((stream, protocol)))
+    }
+}
+
+/// Connection handler of [`Streams`].
+#[derive(Debug)]
+pub struct Handler {
+    peer: PeerId,
+    shared: Arc<Mutex<Shared>>,
+    pending: VecDeque<OpenStream>,
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = OpenStream;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = AcceptUpgrade;
+    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = oneshot::Sender<Result<Stream>>;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        let protocols = Shared::lock(&self.shared).protocols();
+        SubstreamProtocol::new(AcceptUpgrade(protocols), ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
+    > {
+        match self.pending.pop_front() {
+            Some(request) => Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(
+                    ReadyUpgrade::new(request.protocol),
+                    request.reply,
+                ),
+            }),
+            None => Poll::Pending,
+        }
+    }
+
+    fn on_behaviour_event(&mut self, request: Self::FromBehaviour) {
+        self.pending.push_back(request);
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: (stream, protocol),
+                ..
+            }) => Shared::lock(&self.shared).deliver(self.peer, protocol, stream),
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: stream,
+                info: reply,
+            }) => {
+                let _ = reply.send(Ok(stream));
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { info: reply, error }) => {
+                let _ = reply.send(Err(P2PlaneError::stream(format!(
+                    "failed to open stream to {}: {}",
+                    self.peer, error
+                ))));
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Pre-shared keys for private networks.
+//!
+//! When `NodeConfig::swarm_key_file` is set, every TCP, WebSocket, memory and
+//! relayed connection is encrypted with the key before the security
+//! handshake. Nodes without the same key cannot complete the handshake, so
+//! networks that share hosts stay apart. The key file uses the go-libp2p
+//! `swarm.key` format:
+//!
+//! ```text
+//! /key/swarm/psk/1.0.0/
+//! /base16/
+//! <64 hex digits>
+//! ```
+//!
+//! QUIC has no room for the extra handshake and cannot be used together with
+//! a pre-shared key.
+
+use crate::p2plane::{identity::write_private, P2PlaneError, Result};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+pub use libp2p::pnet::PreSharedKey;
+use std::{fs, path::Path};
+
+/// Creates a new random key.
+pub fn generate() -> PreSharedKey {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    PreSharedKey::new(key)
+}
+
+/// Reads a key file.
+pub fn load(path: &Path) -> Result<PreSharedKey> {
+    let contents = fs::read_to_string(path).map_err(|e| P2PlaneError::Config {
+        message: format!("failed to read swarm key file {}", path.display()),
+        source: Some(Box::new(e)),
+    })?;
+    contents.parse().map_err(|e| P2PlaneError::Config {
+        message: format!("{}: invalid swarm key", path.display()),
+        source: Some(Box::new(e)),
+    })
+}
+
+/// Writes `key` to a file only the current user can read.
+pub fn save(path: &Path, key: &PreSharedKey) -> Result<()> {
+    write_private(path, key.to_string().as_bytes())
+}
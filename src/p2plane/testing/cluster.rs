@@ -0,0 +1,370 @@
+//! Multi-node clusters for integration tests.
+//!
+//! [`TestCluster`] starts a number of nodes on top of a [`Simulator`],
+//! connects them in a [`Topology`] and waits for the connections to come up
+//! by watching node events, so tests do not need fixed sleeps.
+
+use crate::p2plane::{
+    handle::{NodeEvent, NodeHandle},
+    network::{Node, NodeConfig},
+    testing::Simulator,
+    traits::Message,
+    ErrorChain, P2PlaneError, Result,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use log::debug;
+use rand::{rngs::StdRng, seq::IteratorRandom, Rng, SeedableRng};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    marker::PhantomData,
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{sleep, timeout_at, Instant},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How the nodes of a [`TestCluster`] are connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Node 0 is connected to every other node.
+    Star,
+    /// Every node is connected to the next one, and the last to the first.
+    Ring,
+    /// Every node is connected to every other node.
+    FullMesh,
+    /// A random spanning tree plus `extra_edges` random connections, chosen
+    /// deterministically from `seed`.
+    Random { extra_edges: usize, seed: u64 },
+}
+
+impl Topology {
+    /// Returns the connections between `size` nodes as `(dialer, listener)`
+    /// pairs with `listener < dialer`.
+    pub fn edges(&self, size: usize) -> BTreeSet<(usize, usize)> {
+        let mut edges = BTreeSet::new();
+        match *self {
+            Topology::Star => edges.extend((1..size).map(|i| (i, 0))),
+            Topology::Ring => {
+                edges.extend((1..size).map(|i| (i, i - 1)));
+                if size > 2 {
+                    edges.insert((size - 1, 0));
+                }
+            }
+            Topology::FullMesh => {
+                edges.extend((1..size).flat_map(|i| (0..i).map(move |j| (i, j))));
+            }
+            Topology::Random { extra_edges, seed } => {
+                let mut rng = StdRng::seed_from_u64(seed);
+                edges.extend((1..size).map(|i| (i, rng.gen_range(0..i))));
+                let max_edges = size * size.saturating_sub(1) / 2;
+                let target = (edges.len() + extra_edges).min(max_edges);
+                while edges.len() < target {
+                    let pair = (0..size).choose_multiple(&mut rng, 2);
+                    edges.insert((pair[0].max(pair[1]), pair[0].min(pair[1])));
+                }
+            }
+        }
+        edges
+    }
+}
+
+type ConfigureFn = Box<dyn Fn(usize, &mut NodeConfig)>;
+
+/// Builder for a [`TestCluster`].
+pub struct TestClusterBuilder<M> {
+    size: usize,
+    topology: Topology,
+    simulator: Option<Simulator>,
+    configure: Option<ConfigureFn>,
+    timeout: Duration,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M> fmt::Debug for TestClusterBuilder<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestClusterBuilder")
+            .field("size", &self.size)
+            .field("topology", &self.topology)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M: Message> TestClusterBuilder<M> {
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Runs the cluster on `simulator`, e.g. one with lossy links. By default
+    /// the nodes are connected by perfect links.
+    pub fn simulator(mut self, simulator: Simulator) -> Self {
+        self.simulator = Some(simulator);
+        self
+    }
+
+    /// Adjusts the configuration of node `index` before it is created.
+    pub fn configure(mut self, configure: impl Fn(usize, &mut NodeConfig) + 'static) -> Self {
+        self.configure = Some(Box::new(configure));
+        self
+    }
+
+    /// How long `build` waits for the topology to be connected.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Starts the nodes, connects them and waits until every connection of
+    /// the topology is established.
+    pub async fn build(self) -> Result<TestCluster<M>> {
+        let simulator = self.simulator.unwrap_or_else(|| Simulator::new(0));
+        let edges = self.topology.edges(self.size);
+
+        let mut nodes = Vec::with_capacity(self.size);
+        for index in 0..self.size {
+            let mut config = simulator.node_config(index, None);
+            if let Some(configure) = &self.configure {
+                configure(index, &mut config);
+            }
+            let mut node = Node::<M>::new(config).await?;
+            let peer_id = node.local_peer_id();
+            let handle = node.handle();
+            let events = handle.subscribe();
+            tokio::spawn(async move {
+                if let Err(e) = node.start().await {
+                    debug!("Cluster node {} stopped: {}", index, ErrorChain(&e));
+                }
+            });
+            nodes.push(ClusterNode {
+                peer_id,
+                address: simulator.address(index).with(Protocol::P2p(peer_id)),
+                handle,
+                events,
+            });
+        }
+
+        let mut cluster = TestCluster {
+            nodes,
+            edges,
+            simulator,
+        };
+
+        let deadline = Instant::now() + self.timeout;
+        for &(dialer, listener) in &cluster.edges {
+            let addr = cluster.nodes[listener].address.clone();
+            cluster.nodes[dialer].handle.dial(addr).await?;
+        }
+        for index in 0..cluster.nodes.len() {
+            let mut pending: HashSet<PeerId> = cluster
+                .neighbours(index)
+                .into_iter()
+                .map(|n| cluster.nodes[n].peer_id)
+                .collect();
+            let events = &mut cluster.nodes[index].events;
+            let connected = timeout_at(deadline, async {
+                while !pending.is_empty() {
+                    match events.recv().await {
+                        Ok(NodeEvent::PeerConnected(peer)) => {
+                            pending.remove(&peer);
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return false,
+                    }
+                }
+                true
+            })
+            .await;
+            if connected != Ok(true) {
+                return Err(P2PlaneError::Timeout(format!(
+                    "cluster node {} did not connect to {} of its neighbours",
+                    index,
+                    pending.len()
+                )));
+            }
+        }
+        Ok(cluster)
+    }
+}
+
+/// One node of a [`TestCluster`].
+#[derive(Debug)]
+pub struct ClusterNode<M: Message> {
+    pub peer_id: PeerId,
+    /// Dialable address including the `/p2p/<peer-id>` suffix.
+    pub address: Multiaddr,
+    pub handle: NodeHandle<M>,
+    events: broadcast::Receiver<NodeEvent<M>>,
+}
+
+/// A set of connected nodes running in the current process.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use narwhal::p2plane::testing::{TestCluster, Topology};
+/// # use narwhal::p2plane::traits::Message;
+/// # use std::time::Duration;
+/// # #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+/// # struct MyMessage(String);
+/// # impl Message for MyMessage {
+/// #     fn protocol_id(&self) -> &'static str { "/my-app/1.0.0" }
+/// # }
+/// # async fn run() -> narwhal::p2plane::Result<()> {
+/// let mut cluster = TestCluster::<MyMessage>::builder(5)
+///     .topology(Topology::FullMesh)
+///     .build()
+///     .await?;
+///
+/// cluster.send_to_all(0, MyMessage("hello".to_string())).await?;
+/// cluster
+///     .assert_all_received(1.., Duration::from_secs(5), |m| m.0 == "hello")
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TestCluster<M: Message> {
+    nodes: Vec<ClusterNode<M>>,
+    edges: BTreeSet<(usize, usize)>,
+    simulator: Simulator,
+}
+
+impl<M: Message> TestCluster<M> {
+    pub fn builder(size: usize) -> TestClusterBuilder<M> {
+        TestClusterBuilder {
+            size,
+            topology: Topology::FullMesh,
+            simulator: None,
+            configure: None,
+            timeout: DEFAULT_TIMEOUT,
+            _message: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, index: usize) -> &ClusterNode<M> {
+        &self.nodes[index]
+    }
+
+    pub fn handle(&self, index: usize) -> &NodeHandle<M> {
+        &self.nodes[index].handle
+    }
+
+    /// The simulator the cluster runs on, for partitions and link changes.
+    pub fn simulator(&self) -> &Simulator {
+        &self.simulator
+    }
+
+    /// Indices of the nodes connected to node `index` in the topology.
+    pub fn neighbours(&self, index: usize) -> Vec<usize> {
+        self.edges
+            .iter()
+            .filter_map(|&(a, b)| {
+                if a == index {
+                    Some(b)
+                } else if b == index {
+                    Some(a)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Sends `message` from node `from` directly to every other node.
+    pub async fn send_to_all(&self, from: usize, message: M) -> Result<()> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if index != from {
+                self.nodes[from]
+                    .handle
+                    .send_message(node.peer_id, message.clone())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until every node in `indices` has received a message matching
+    /// `matches`, panicking if one has not within `within`. Iteration stops
+    /// at the first index past the last node, so open ranges such as `1..`
+    /// can be used.
+    pub async fn assert_all_received(
+        &mut self,
+        indices: impl IntoIterator<Item = usize>,
+        within: Duration,
+        matches: impl Fn(&M) -> bool,
+    ) {
+        let deadline = Instant::now() + within;
+        let len = self.nodes.len();
+        for index in indices.into_iter().take_while(|&i| i < len) {
+            let events = &mut self.nodes[index].events;
+            let received = timeout_at(deadline, async {
+                loop {
+                    match events.recv().await {
+                        Ok(NodeEvent::Message { message, .. }) if matches(&message) => {
+                            return true
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return false,
+                    }
+                }
+            })
+            .await;
+            assert_eq!(
+                received,
+                Ok(true),
+                "node {} did not receive the expected message within {:?}",
+                index,
+                within
+            );
+        }
+    }
+
+    /// Waits until every node is connected to exactly `expected(index)`
+    /// peers, panicking if the counts have not converged within `within`.
+    pub async fn assert_peer_counts_converge(
+        &self,
+        within: Duration,
+        expected: impl Fn(usize) -> usize,
+    ) {
+        let deadline = Instant::now() + within;
+        let mut counts = Vec::new();
+        loop {
+            counts.clear();
+            for node in &self.nodes {
+                let peers = node.handle.connected_peers().await.unwrap_or_default();
+                counts.push(peers.len());
+            }
+            if counts.iter().enumerate().all(|(i, &c)| c == expected(i)) {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "peer counts {:?} did not converge within {:?}",
+                counts,
+                within
+            );
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Waits until every node is connected to as many peers as it has
+    /// neighbours in the topology.
+    pub async fn assert_topology_connected(&self, within: Duration) {
+        let expected: Vec<usize> = (0..self.len()).map(|i| self.neighbours(i).len()).collect();
+        self.assert_peer_counts_converge(within, |i| expected[i]).await;
+    }
+}
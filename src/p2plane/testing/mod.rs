@@ -0,0 +1,8 @@
+//! Helpers for exercising many nodes in one process. Enabled by the
+//! `testing` feature.
+
+pub mod cluster;
+pub mod simulator;
+
+pub use cluster::{ClusterNode, TestCluster, TestClusterBuilder, Topology};
+pub use simulator::{LinkConfig, ScriptedEvent, SimEndpoint, SimStream, Simulator};
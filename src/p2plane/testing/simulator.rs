@@ -0,0 +1,540 @@
+//! Deterministic network simulation for multi-node tests.
+//!
+//! Every simulated node listens on a `/memory/<n>` address and connects
+//! through [`SimEndpoint::wrap`], which shapes the bytes flowing over each
+//! connection according to the [`LinkConfig`] of the link it crosses. All
+//! delays are tokio timers, so under `#[tokio::test(start_paused = true)]`
+//! a run takes no wall-clock time, and with a current-thread runtime the
+//! same seed produces the same schedule.
+
+use crate::p2plane::{
+    handle::NodeHandle,
+    network::{Node, NodeConfig, PeerStorageKind},
+    traits::Message,
+    ErrorChain, Result,
+};
+use libp2p::{
+    core::{
+        transport::{memory::Channel, Boxed, MemoryTransport, Transport},
+        ConnectedPoint,
+    },
+    futures::{future, AsyncRead, AsyncWrite},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use log::debug;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Memory ports are process-wide, so every simulator takes its own block.
+const PORTS_PER_SIMULATOR: u64 = 10_000;
+static NEXT_BASE_PORT: AtomicU64 = AtomicU64::new(1_000_000);
+
+/// How often a stream blocked by a partition checks whether it has healed.
+const PARTITION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bytes a stream buffers for sending before `poll_write` pushes back.
+const MAX_BUFFERED_BYTES: usize = 1024 * 1024;
+
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Characteristics of the link between two simulated nodes, applied
+/// independently to each direction.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    /// Fixed one-way delay.
+    pub latency: Duration,
+    /// Upper bound of a uniformly distributed extra delay per write.
+    pub jitter: Duration,
+    /// Probability in `[0, 1)` that a write is lost and has to be resent.
+    pub loss: f64,
+    /// Delay added for every resend of a lost write.
+    pub retransmit_delay: Duration,
+    /// Link capacity in bytes per second, `None` for unlimited.
+    pub bandwidth: Option<u64>,
+}
+
+/// A change to the simulated network applied at a scripted point in time.
+#[derive(Debug, Clone)]
+pub enum ScriptedEvent {
+    /// Split the nodes into groups that cannot reach each other. Nodes not
+    /// listed form one more group.
+    Partition(Vec<Vec<usize>>),
+    /// Remove all partitions.
+    Heal,
+    /// Change the link between two nodes in both directions.
+    SetLink(usize, usize, LinkConfig),
+}
+
+#[derive(Debug)]
+struct SimState {
+    rng: StdRng,
+    default_link: LinkConfig,
+    links: HashMap<(usize, usize), LinkConfig>,
+    groups: HashMap<usize, usize>,
+    busy_until: HashMap<(usize, usize), Instant>,
+    base_port: u64,
+}
+
+impl SimState {
+    fn link(&self, from: usize, to: usize) -> &LinkConfig {
+        self.links.get(&(from, to)).unwrap_or(&self.default_link)
+    }
+
+    fn partitioned(&self, a: usize, b: usize) -> bool {
+        self.groups.get(&a).copied().unwrap_or(0) != self.groups.get(&b).copied().unwrap_or(0)
+    }
+
+    fn node_index(&self, addr: &Multiaddr) -> Option<usize> {
+        addr.iter().find_map(|p| match p {
+            Protocol::Memory(port) if port >= self.base_port => {
+                Some((port - self.base_port) as usize)
+            }
+            _ => None,
+        })
+    }
+
+    /// Returns when `len` bytes sent now from `from` reach `to`.
+    fn schedule(&mut self, from: usize, to: usize, len: usize, now: Instant) -> Instant {
+        let link = self.link(from, to).clone();
+
+        let start = self
+            .busy_until
+            .get(&(from, to))
+            .copied()
+            .map_or(now, |busy| busy.max(now));
+        let transmit = link
+            .bandwidth
+            .map_or(Duration::ZERO, |bw| Duration::from_secs_f64(len as f64 / bw.max(1) as f64));
+        self.busy_until.insert((from, to), start + transmit);
+
+        let jitter = if link.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            Duration::from_nanos(self.rng.gen_range(0..=link.jitter.as_nanos() as u64))
+        };
+
+        // Every loss costs one more round of retransmission.
+        let loss = link.loss.clamp(0.0, 0.99);
+        let mut retransmits = 0;
+        while loss > 0.0 && self.rng.gen_bool(loss) {
+            retransmits += 1;
+        }
+
+        start + transmit + link.latency + jitter + link.retransmit_delay * retransmits
+    }
+}
+
+/// A simulated network of nodes connected over shaped in-memory links.
+#[derive(Clone)]
+pub struct Simulator {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl fmt::Debug for Simulator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Simulator").finish_non_exhaustive()
+    }
+}
+
+impl Simulator {
+    /// Creates an empty network whose random choices (jitter and loss) are
+    /// drawn from `seed`.
+    pub fn new(seed: u64) -> Self {
+        let base_port = NEXT_BASE_PORT.fetch_add(PORTS_PER_SIMULATOR, Ordering::Relaxed);
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                groups: HashMap::new(),
+                busy_until: HashMap::new(),
+                base_port,
+            })),
+        }
+    }
+
+    /// Link used between any two nodes without a link of their own.
+    pub fn with_default_link(self, link: LinkConfig) -> Self {
+        self.state.lock().unwrap().default_link = link;
+        self
+    }
+
+    /// Sets the link between nodes `a` and `b` in both directions.
+    pub fn set_link(&self, a: usize, b: usize, link: LinkConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.links.insert((a, b), link.clone());
+        state.links.insert((b, a), link);
+    }
+
+    /// Splits the network into `groups`. Nodes in different groups cannot
+    /// dial each other, and data on existing connections between them is
+    /// held back until [`heal`](Self::heal).
+    pub fn partition(&self, groups: &[Vec<usize>]) {
+        let mut state = self.state.lock().unwrap();
+        state.groups.clear();
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in nodes {
+                state.groups.insert(*node, group + 1);
+            }
+        }
+        debug!("Simulator partitioned into {:?}", groups);
+    }
+
+    pub fn heal(&self) {
+        self.state.lock().unwrap().groups.clear();
+        debug!("Simulator partitions healed");
+    }
+
+    pub fn apply(&self, event: ScriptedEvent) {
+        match event {
+            ScriptedEvent::Partition(groups) => self.partition(&groups),
+            ScriptedEvent::Heal => self.heal(),
+            ScriptedEvent::SetLink(a, b, link) => self.set_link(a, b, link),
+        }
+    }
+
+    /// Applies each event once its offset from now has elapsed.
+    pub fn run_script(&self, script: Vec<(Duration, ScriptedEvent)>) -> tokio::task::JoinHandle<()> {
+        let simulator = self.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            for (at, event) in script {
+                sleep_until(start + at).await;
+                simulator.apply(event);
+            }
+        })
+    }
+
+    /// Delay a write of `len` bytes from `from` to `to` would see if sent
+    /// now. Draws from the same random sequence as real traffic.
+    pub fn sample_delay(&self, from: usize, to: usize, len: usize) -> Duration {
+        let now = Instant::now();
+        self.state.lock().unwrap().schedule(from, to, len, now) - now
+    }
+
+    /// Listen address of node `index`.
+    pub fn address(&self, index: usize) -> Multiaddr {
+        let base_port = self.state.lock().unwrap().base_port;
+        Protocol::Memory(base_port + index as u64).into()
+    }
+
+    /// Transport hook for node `index`.
+    pub fn endpoint(&self, index: usize) -> SimEndpoint {
+        SimEndpoint {
+            index,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Configuration for node `index`, optionally bootstrapping from another
+    /// simulated node. Peers are kept in memory.
+    pub fn node_config(&self, index: usize, bootstrap: Option<usize>) -> NodeConfig {
+        NodeConfig {
+            listen_addr: self.address(index).to_string(),
+            bootstrap_addr: bootstrap.map(|b| self.address(b)),
+            peer_storage: PeerStorageKind::Memory,
+            simulation: Some(self.endpoint(index)),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a node from `config`, usually obtained from
+    /// [`node_config`](Self::node_config), and runs it in the background.
+    pub async fn spawn_node<M: Message>(&self, config: NodeConfig) -> Result<NodeHandle<M>> {
+        let mut node = Node::<M>::new(config).await?;
+        let handle = node.handle();
+        tokio::spawn(async move {
+            if let Err(e) = node.start().await {
+                debug!("Simulated node stopped: {}", ErrorChain(&e));
+            }
+        });
+        Ok(handle)
+    }
+}
+
+/// A node's attachment point to a [`Simulator`].
+#[derive(Clone)]
+pub struct SimEndpoint {
+    index: usize,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl fmt::Debug for SimEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimEndpoint").field("index", &self.index).finish()
+    }
+}
+
+impl SimEndpoint {
+    /// In-memory transport for this node. Outgoing connections to other
+    /// simulated nodes are shaped in both directions on the dialing side,
+    /// which keeps the listening side a plain pass-through.
+    pub fn memory_transport(&self) -> Boxed<SimStream<Channel<Vec<u8>>>> {
+        let endpoint = self.clone();
+        MemoryTransport::default()
+            .and_then(move |stream, connected_point| {
+                future::ready(endpoint.shape(stream, &connected_point))
+            })
+            .boxed()
+    }
+
+    fn shape<S>(&self, stream: S, connected_point: &ConnectedPoint) -> io::Result<SimStream<S>> {
+        let ConnectedPoint::Dialer { address, .. } = connected_point else {
+            return Ok(SimStream::new(stream, None));
+        };
+        let state = self.state.lock().unwrap();
+        let Some(remote) = state.node_index(address) else {
+            return Ok(SimStream::new(stream, None));
+        };
+        if state.partitioned(self.index, remote) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("node {} is partitioned from node {}", self.index, remote),
+            ));
+        }
+        let link = SimLink {
+            local: self.index,
+            remote,
+            state: self.state.clone(),
+        };
+        Ok(SimStream::new(stream, Some(link)))
+    }
+}
+
+struct SimLink {
+    local: usize,
+    remote: usize,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimLink {
+    fn schedule(&self, from: usize, to: usize, len: usize) -> Instant {
+        self.state.lock().unwrap().schedule(from, to, len, Instant::now())
+    }
+
+    fn partitioned(&self) -> bool {
+        self.state.lock().unwrap().partitioned(self.local, self.remote)
+    }
+}
+
+/// Chunks waiting for their delivery time, in order.
+#[derive(Default)]
+struct DelayQueue {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    offset: usize,
+    bytes: usize,
+    last_ready: Option<Instant>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl DelayQueue {
+    fn push(&mut self, ready_at: Instant, chunk: Vec<u8>) {
+        // Data on a stream is never reordered.
+        let ready_at = self.last_ready.map_or(ready_at, |last| last.max(ready_at));
+        self.last_ready = Some(ready_at);
+        self.bytes += chunk.len();
+        self.chunks.push_back((ready_at, chunk));
+    }
+
+    /// Returns the bytes at the front of the queue once they are due and
+    /// the link is not partitioned, registering a wake-up otherwise.
+    fn poll_front(&mut self, cx: &mut Context<'_>, link: &SimLink) -> Poll<Option<&[u8]>> {
+        let Some((ready_at, _)) = self.chunks.front() else {
+            return Poll::Ready(None);
+        };
+        let now = Instant::now();
+        let wake_at = if link.partitioned() {
+            Some(now + PARTITION_POLL_INTERVAL)
+        } else if *ready_at > now {
+            Some(*ready_at)
+        } else {
+            None
+        };
+        if let Some(wake_at) = wake_at {
+            let timer = self
+                .timer
+                .get_or_insert_with(|| Box::pin(sleep_until(wake_at)));
+            timer.as_mut().reset(wake_at);
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(self.chunks.front().map(|(_, chunk)| &chunk[self.offset..]))
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.offset += n;
+        self.bytes -= n;
+        if self
+            .chunks
+            .front()
+            .is_some_and(|(_, chunk)| self.offset == chunk.len())
+        {
+            self.chunks.pop_front();
+            self.offset = 0;
+        }
+    }
+}
+
+/// Delivery state of a shaped connection.
+struct Shaping {
+    link: SimLink,
+    outbound: DelayQueue,
+    inbound: DelayQueue,
+    read_eof: bool,
+}
+
+impl Shaping {
+    /// Writes every due outbound chunk to `inner`.
+    fn poll_send<S: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut S,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let n = match self.outbound.poll_front(cx, &self.link) {
+                Poll::Ready(Some(chunk)) => match Pin::new(&mut *inner).poll_write(cx, chunk) {
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.outbound.consume(n);
+        }
+    }
+
+    /// Moves everything `inner` has available into the inbound queue,
+    /// stamped with its delivery time.
+    fn receive<S: AsyncRead + Unpin>(&mut self, inner: &mut S, cx: &mut Context<'_>) -> io::Result<()> {
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        while !self.read_eof {
+            match Pin::new(&mut *inner).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(0)) => self.read_eof = true,
+                Poll::Ready(Ok(n)) => {
+                    let ready_at = self.link.schedule(self.link.remote, self.link.local, n);
+                    self.inbound.push(ready_at, buf[..n].to_vec());
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A connection whose traffic is delayed, throttled and held back according
+/// to the simulated link it crosses. Connections accepted by a listener and
+/// connections to addresses outside the simulator are passed through.
+pub struct SimStream<S> {
+    inner: S,
+    shaping: Option<Shaping>,
+}
+
+impl<S> SimStream<S> {
+    fn new(inner: S, link: Option<SimLink>) -> Self {
+        Self {
+            inner,
+            shaping: link.map(|link| Shaping {
+                link,
+                outbound: DelayQueue::default(),
+                inbound: DelayQueue::default(),
+                read_eof: false,
+            }),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SimStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(shaping) = &mut this.shaping else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        if let Err(e) = shaping.receive(&mut this.inner, cx) {
+            return Poll::Ready(Err(e));
+        }
+        match shaping.inbound.poll_front(cx, &shaping.link) {
+            Poll::Ready(Some(chunk)) => {
+                let n = chunk.len().min(buf.len());
+                buf[..n].copy_from_slice(&chunk[..n]);
+                shaping.inbound.consume(n);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(None) if shaping.read_eof => Poll::Ready(Ok(0)),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SimStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(shaping) = &mut this.shaping else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        if shaping.outbound.bytes >= MAX_BUFFERED_BYTES {
+            if let Poll::Ready(Err(e)) = shaping.poll_send(&mut this.inner, cx) {
+                return Poll::Ready(Err(e));
+            }
+            if shaping.outbound.bytes >= MAX_BUFFERED_BYTES {
+                return Poll::Pending;
+            }
+        }
+        let ready_at = shaping
+            .link
+            .schedule(shaping.link.local, shaping.link.remote, buf.len());
+        shaping.outbound.push(ready_at, buf.to_vec());
+        // Registers the wake-up for the new chunk, or sends it right away if
+        // it is already due.
+        if let Poll::Ready(Err(e)) = shaping.poll_send(&mut this.inner, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(shaping) = &mut this.shaping {
+            match shaping.poll_send(&mut this.inner, cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(shaping) = &mut this.shaping {
+            match shaping.poll_send(&mut this.inner, cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+        }
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,110 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::address_book::{
+        AddressBook, AddressSource, MAX_ADDRESSES_PER_PEER, MAX_ADDRESS_FAILURES,
+    };
+    use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+    use std::time::{Duration, SystemTime};
+
+    fn tcp(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_keeps_every_address_with_its_source() {
+        let mut book = AddressBook::new();
+        let peer = PeerId::random();
+        let quic: Multiaddr = "/ip4/10.0.0.1/udp/4001/quic-v1".parse().unwrap();
+
+        assert!(book.add(peer, tcp(4001), AddressSource::Identify));
+        assert!(book.add(peer, quic.clone(), AddressSource::Kademlia));
+        assert!(!book.add(
+            peer,
+            tcp(4001).with(Protocol::P2p(peer)),
+            AddressSource::Mdns
+        ));
+
+        let records = book.records(&peer);
+        assert_eq!(records.len(), 2);
+        let tcp_record = records.iter().find(|r| r.addr == tcp(4001)).unwrap();
+        assert_eq!(tcp_record.source, AddressSource::Identify);
+        assert!(records
+            .iter()
+            .any(|r| r.addr == quic && r.source == AddressSource::Kademlia));
+    }
+
+    #[test]
+    fn test_candidates_ranked_by_recent_success() {
+        let mut book = AddressBook::new();
+        let peer = PeerId::random();
+        let relayed: Multiaddr = format!(
+            "/ip4/10.0.0.9/tcp/4001/p2p/{}/p2p-circuit",
+            PeerId::random()
+        )
+        .parse()
+        .unwrap();
+        book.add(peer, relayed.clone(), AddressSource::Manual);
+        for port in [4001, 4002, 4003] {
+            book.add(peer, tcp(port), AddressSource::Kademlia);
+        }
+
+        book.record_failure(&peer, &tcp(4001));
+        book.record_success(&peer, &tcp(4002));
+        std::thread::sleep(Duration::from_millis(2));
+        book.record_success(&peer, &tcp(4003));
+
+        assert_eq!(
+            book.dial_candidates(&peer),
+            vec![tcp(4003), tcp(4002), tcp(4001), relayed]
+        );
+        assert_eq!(book.best(&peer), Some(&tcp(4003)));
+        assert_eq!(book.records(&peer)[2].failures, 1);
+    }
+
+    #[test]
+    fn test_addresses_expire_unless_manual() {
+        let mut book = AddressBook::new();
+        let peer = PeerId::random();
+        book.add(peer, tcp(1), AddressSource::Manual);
+        book.add(peer, tcp(2), AddressSource::Mdns);
+        book.add(peer, tcp(3), AddressSource::Identify);
+        book.add(peer, tcp(4), AddressSource::Identify);
+        book.record_success(&peer, &tcp(4));
+
+        let later = SystemTime::now() + Duration::from_secs(2 * 60 * 60);
+        assert_eq!(book.prune_expired(later), 2);
+        let mut left = book.dial_candidates(&peer);
+        left.sort();
+        assert_eq!(left, vec![tcp(1), tcp(4)]);
+    }
+
+    #[test]
+    fn test_failing_addresses_are_forgotten() {
+        let mut book = AddressBook::new();
+        let peer = PeerId::random();
+        book.add(peer, tcp(1), AddressSource::Manual);
+        book.add(peer, tcp(2), AddressSource::Dialed);
+        for _ in 0..MAX_ADDRESS_FAILURES {
+            book.record_failure(&peer, &tcp(1));
+            book.record_failure(&peer, &tcp(2));
+        }
+        assert_eq!(book.dial_candidates(&peer), vec![tcp(1)]);
+    }
+
+    #[test]
+    fn test_full_book_evicts_worst_learned_address() {
+        let mut book = AddressBook::new();
+        let peer = PeerId::random();
+        book.add(peer, tcp(0), AddressSource::Manual);
+        for port in 1..MAX_ADDRESSES_PER_PEER as u16 {
+            book.add(peer, tcp(port), AddressSource::Kademlia);
+            book.record_success(&peer, &tcp(port));
+        }
+        assert!(book.add(peer, tcp(100), AddressSource::Identify));
+
+        let candidates = book.dial_candidates(&peer);
+        assert_eq!(candidates.len(), MAX_ADDRESSES_PER_PEER);
+        assert!(candidates.contains(&tcp(0)));
+        assert!(candidates.contains(&tcp(100)));
+    }
+}
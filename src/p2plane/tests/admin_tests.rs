@@ -0,0 +1,126 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        admin::{self, AdminClient, AdminPeer, DialRequest},
+        network::Node,
+        tests::{memory_config, wait_until_connected, TestMessage},
+        RoutingEntry,
+    };
+    use libp2p::{Multiaddr, PeerId};
+    use std::{error::Error, net::SocketAddr};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const TOKEN: &str = "secret";
+
+    /// Sends a bare HTTP/1.1 request and returns the status and body.
+    async fn request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let auth = token
+            .map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
+            method,
+            path,
+            body.len(),
+            auth,
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let (status, body) = request(addr, "GET", path, Some(TOKEN), "").await;
+        assert_eq!(status, 200, "GET {}: {}", path, body);
+        body
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7580, None)).await?;
+        let first_id = first.local_peer_id();
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7581, Some(7580))).await?;
+        let second_id = second.local_peer_id();
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+        wait_until_connected(&handle, first_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(admin::serve(listener, handle, Some(TOKEN.to_string())));
+
+        assert_eq!(request(addr, "GET", "/peer-id", None, "").await.0, 401);
+        assert_eq!(
+            request(addr, "GET", "/peer-id", Some("wrong"), "").await.0,
+            401
+        );
+        assert!(get(addr, "/peer-id").await.contains(&second_id.to_string()));
+
+        let listen: Vec<Multiaddr> = serde_json::from_str(&get(addr, "/listen-addrs").await)?;
+        assert_eq!(listen, vec!["/memory/7581".parse::<Multiaddr>()?]);
+        let connected: Vec<PeerId> = serde_json::from_str(&get(addr, "/peers/connected").await)?;
+        assert_eq!(connected, vec![first_id]);
+        let peers: Vec<AdminPeer> = serde_json::from_str(&get(addr, "/peers").await)?;
+        let peer = peers.iter().find(|peer| peer.peer_id == first_id).unwrap();
+        assert!(peer.connected);
+        let _: Vec<RoutingEntry> = serde_json::from_str(&get(addr, "/routing-table").await)?;
+
+        let body = format!("{{\"peer_id\": \"{}\"}}", first_id);
+        assert_eq!(
+            request(addr, "POST", "/ban", Some(TOKEN), &body).await.0,
+            204
+        );
+        let bans: Vec<PeerId> = serde_json::from_str(&get(addr, "/bans").await)?;
+        assert_eq!(bans, vec![first_id]);
+        assert_eq!(
+            request(addr, "POST", "/unban", Some(TOKEN), &body).await.0,
+            204
+        );
+        assert_eq!(get(addr, "/bans").await, "[]");
+
+        assert_eq!(
+            request(addr, "POST", "/dial", Some(TOKEN), "{}").await.0,
+            400
+        );
+        let dial = "{\"addr\": \"/memory/7580\"}";
+        assert_eq!(
+            request(addr, "POST", "/dial", Some(TOKEN), dial).await.0,
+            202
+        );
+        let (status, body) = request(addr, "POST", "/disconnect", Some(TOKEN), &body).await;
+        assert_eq!((status, body.as_str()), (200, "{\"disconnected\":true}"));
+
+        let client = AdminClient::new(&format!("http://{}", addr), Some(TOKEN.to_string()))?;
+        assert_eq!(client.peer_id().await?, second_id);
+        assert!(client.peers().await?.iter().any(|peer| peer.peer_id == first_id));
+        client
+            .dial(&DialRequest {
+                peer_id: Some(first_id),
+                ..Default::default()
+            })
+            .await?;
+        let unauthorized = AdminClient::new(&addr.to_string(), None)?;
+        let error = unauthorized.peers().await.unwrap_err();
+        assert!(error.source().unwrap().to_string().contains("401"));
+        assert!(AdminClient::new("https://localhost:1", None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        builder::{Discovery, NodeBuilder, Security, Transports},
+        handle::NodeEvent,
+        tests::{memory_config, TestMessage},
+        P2PlaneError,
+    };
+    use libp2p::{futures::StreamExt, kad::RecordKey, ping};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_extension_events_are_forwarded() {
+        let ping_config = || ping::Config::new().with_interval(Duration::from_millis(50));
+        let mut first = NodeBuilder::<TestMessage>::new(memory_config(7100, None))
+            .with_behaviour(ping::Behaviour::new(ping_config()))
+            .build()
+            .await
+            .unwrap();
+        let mut pings = first.extension_events().unwrap();
+        assert!(first.extension_events().is_none());
+        tokio::spawn(async move { first.start().await });
+
+        let mut second = NodeBuilder::<TestMessage>::new(memory_config(7101, Some(7100)))
+            .with_behaviour(ping::Behaviour::new(ping_config()))
+            .build()
+            .await
+            .unwrap();
+        tokio::spawn(async move { second.start().await });
+
+        let event = timeout(Duration::from_secs(5), pings.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(event.result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tls_nodes_connect() {
+        let mut first = NodeBuilder::<TestMessage>::new(memory_config(7200, None))
+            .security(Security::Tls)
+            .build()
+            .await
+            .unwrap();
+        let first_id = first.local_peer_id();
+        tokio::spawn(async move { first.start().await });
+
+        let mut second = NodeBuilder::<TestMessage>::new(memory_config(7201, Some(7200)))
+            .security(Security::Tls)
+            .build()
+            .await
+            .unwrap();
+        let mut events = second.subscribe();
+        tokio::spawn(async move { second.start().await });
+
+        let connected = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(NodeEvent::PeerConnected(peer)) = events.recv().await {
+                    return peer;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, first_id);
+    }
+
+    #[tokio::test]
+    async fn test_content_routing_requires_kademlia() {
+        let mut node = NodeBuilder::<TestMessage>::new(memory_config(7300, None))
+            .discovery(Discovery {
+                kademlia: false,
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        let key = RecordKey::new(&"digest");
+        assert!(matches!(
+            node.start_providing(key.clone()),
+            Err(P2PlaneError::Config { .. })
+        ));
+        let providers: Vec<_> = node.get_providers(key).collect().await;
+        assert!(providers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_transports_is_a_config_error() {
+        let result = NodeBuilder::<TestMessage>::new(memory_config(7400, None))
+            .transports(Transports {
+                tcp: false,
+                quic: false,
+                websocket: false,
+                memory: false,
+                dns: false,
+            })
+            .build()
+            .await;
+        assert!(matches!(result, Err(P2PlaneError::Config { .. })));
+    }
+    #[tokio::test]
+    async fn test_invalid_config_is_rejected_by_build() {
+        let mut config = memory_config(7401, None);
+        config.outbound_queue.weights.low = 0;
+        let result = NodeBuilder::<TestMessage>::new(config).build().await;
+        match result {
+            Err(P2PlaneError::Config { message, .. }) => {
+                assert!(message.contains("outbound_queue.weights.low"), "{}", message)
+            }
+            _ => panic!("expected a config error"),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        testing::{TestCluster, Topology},
+        tests::TestMessage,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_topology_edges() {
+        assert_eq!(Topology::Star.edges(4).len(), 3);
+        assert!(Topology::Star.edges(4).iter().all(|&(_, listener)| listener == 0));
+        assert_eq!(Topology::Ring.edges(2).len(), 1);
+        assert_eq!(Topology::Ring.edges(5).len(), 5);
+        assert_eq!(Topology::FullMesh.edges(5).len(), 10);
+
+        let random = Topology::Random {
+            extra_edges: 3,
+            seed: 9,
+        };
+        let edges = random.edges(8);
+        assert_eq!(edges.len(), 7 + 3);
+        assert_eq!(edges, random.edges(8));
+        // The spanning tree connects every node to a lower one.
+        assert!((1..8).all(|i| edges.iter().any(|&(a, b)| a == i && b < i)));
+    }
+
+    #[tokio::test]
+    async fn test_full_mesh_delivers_to_all() {
+        let mut cluster = TestCluster::<TestMessage>::builder(5)
+            .topology(Topology::FullMesh)
+            .build()
+            .await
+            .unwrap();
+        cluster.assert_topology_connected(Duration::from_secs(5)).await;
+
+        cluster
+            .send_to_all(0, TestMessage("hello".to_string()))
+            .await
+            .unwrap();
+        cluster
+            .assert_all_received(1.., Duration::from_secs(5), |m| m.0 == "hello")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_star_and_ring_peer_counts() {
+        let star = TestCluster::<TestMessage>::builder(6)
+            .topology(Topology::Star)
+            .build()
+            .await
+            .unwrap();
+        star.assert_peer_counts_converge(Duration::from_secs(5), |i| if i == 0 { 5 } else { 1 })
+            .await;
+
+        let ring = TestCluster::<TestMessage>::builder(6)
+            .topology(Topology::Ring)
+            .build()
+            .await
+            .unwrap();
+        ring.assert_peer_counts_converge(Duration::from_secs(5), |_| 2)
+            .await;
+    }
+}
@@ -0,0 +1,153 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        builder::Security,
+        network::{NodeConfig, PeerStorageKind, RelayMode},
+        P2PlaneError,
+    };
+    use libp2p::PeerId;
+    use std::time::Duration;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn config_message(result: crate::p2plane::Result<NodeConfig>) -> String {
+        match result {
+            Err(P2PlaneError::Config { message, .. }) => message,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_default_config_round_trips_through_toml() {
+        let printed = NodeConfig::default().to_toml().unwrap();
+        assert!(printed.contains("ping_interval = \"15s\""));
+        assert!(printed.contains("[transports]"));
+
+        let parsed = NodeConfig::from_toml_str(&printed).unwrap();
+        assert_eq!(parsed.to_toml().unwrap(), printed);
+        parsed.validate().unwrap();
+    }
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let peer = PeerId::random();
+        let config = NodeConfig::from_toml_str(&format!(
+            r#"
+            listen_addr = "/ip4/127.0.0.1/tcp/9000"
+            relay_mode = "server"
+            security = "tls"
+            provider_reannounce_interval = "1h 30m"
+
+            [transports]
+            quic = true
+
+            [limits]
+            max_established = 10
+            reserved_peers = ["{peer}"]
+            "#
+        ))
+        .unwrap();
+
+        assert_eq!(config.listen_addr, "/ip4/127.0.0.1/tcp/9000");
+        assert_eq!(config.relay_mode, RelayMode::Server);
+        assert_eq!(config.security, Security::Tls);
+        assert_eq!(
+            config.provider_reannounce_interval,
+            Duration::from_secs(90 * 60)
+        );
+        assert!(config.transports.quic && config.transports.tcp);
+        assert_eq!(config.limits.max_established, Some(10));
+        assert!(config.limits.reserved_peers.contains(&peer));
+        assert_eq!(config.ping_interval, Duration::from_secs(15));
+        assert_eq!(config.peer_storage, PeerStorageKind::File);
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let path = std::env::temp_dir().join(format!("p2plane-{}.toml", PeerId::random()));
+        std::fs::write(
+            &path,
+            "max_missed_pings = 5\n[limits]\nmax_established = 10\n",
+        )
+        .unwrap();
+
+        let config = NodeConfig::load_with_env(
+            Some(&path),
+            env(&[
+                ("P2PLANE_LISTEN_ADDR", "/memory/42"),
+                ("P2PLANE_PING_INTERVAL", "2s"),
+                ("P2PLANE_PEER_STORAGE", "memory"),
+                ("P2PLANE_LIMITS__MAX_ESTABLISHED", "20"),
+                ("P2PLANE_DISCOVERY__MDNS", "true"),
+                ("P2PLANE_KEY_PASSPHRASE", "secret"),
+                ("P2PLANE_ADMIN_TOKEN", "secret"),
+                ("HOME", "/root"),
+            ]),
+        );
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.listen_addr, "/memory/42");
+        assert_eq!(config.ping_interval, Duration::from_secs(2));
+        assert_eq!(config.peer_storage, PeerStorageKind::Memory);
+        assert_eq!(config.max_missed_pings, 5);
+        assert_eq!(config.limits.max_established, Some(20));
+        assert!(config.discovery.mdns);
+    }
+
+    #[test]
+    fn test_errors_report_field_paths() {
+        let message = config_message(NodeConfig::from_toml_str(
+            "[limits]\nmax_established = \"many\"\n",
+        ));
+        assert!(
+            message.starts_with("limits.max_established:"),
+            "{}",
+            message
+        );
+
+        let message = config_message(NodeConfig::from_toml_str("[transports]\nudp = true\n"));
+        assert!(message.starts_with("transports."), "{}", message);
+        assert!(message.contains("udp"), "{}", message);
+
+        let message = config_message(NodeConfig::load_with_env(
+            None,
+            env(&[("P2PLANE_PING_INTERVAL", "soon")]),
+        ));
+        assert!(message.starts_with("ping_interval:"), "{}", message);
+    }
+
+    #[test]
+    fn test_validation_lists_every_problem() {
+        let message = config_message(NodeConfig::load_with_env(
+            None,
+            env(&[
+                ("P2PLANE_LISTEN_ADDR", "/ip4/127.0.0.1/udp/9000/quic-v1"),
+                ("P2PLANE_MAX_MISSED_PINGS", "0"),
+                ("P2PLANE_LIMITS__MAX_ESTABLISHED", "2"),
+                ("P2PLANE_LIMITS__RESERVED_SLOTS", "3"),
+                ("P2PLANE_DATA_DIR", "Cargo.toml"),
+            ]),
+        ));
+        assert!(message.contains("listen_addr:") && message.contains("transports.quic"));
+        assert!(message.contains("max_missed_pings: must be at least 1"));
+        assert!(message.contains("limits.reserved_slots: 3 exceeds limits.max_established (2)"));
+        assert!(message.contains("data_dir: Cargo.toml is not a directory"));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_yaml_config() {
+        let config = NodeConfig::from_yaml_str(
+            "listen_addr: /memory/7\ntransports:\n  tcp: false\nlimits:\n  reserved_slots: 1\n",
+        )
+        .unwrap();
+        assert_eq!(config.listen_addr, "/memory/7");
+        assert!(!config.transports.tcp);
+        assert_eq!(config.limits.reserved_slots, 1);
+    }
+}
@@ -0,0 +1,212 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        identity::{decode_key, IdentityConfig, KeyRotation, KeyType, Keystore},
+        network::{Node, NodeConfig},
+        tests::{memory_config, wait_until_connected, TempDir, TestMessage},
+        PeerEvent,
+    };
+    use libp2p::identity::Keypair;
+    use std::{error::Error, fs, path::PathBuf, time::Duration};
+
+    fn identity(key_type: KeyType) -> IdentityConfig {
+        IdentityConfig {
+            key_type,
+            key_file: Some("node.key".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_key_file_is_created_and_reused() {
+        for key_type in [KeyType::Ed25519, KeyType::Secp256k1, KeyType::Ecdsa] {
+            let dir = TempDir::new();
+            let config = identity(key_type);
+            let created = config.load_with_passphrase(&dir.0, None).unwrap();
+            assert_eq!(KeyType::of(&created), Some(key_type));
+            let loaded = config.load_with_passphrase(&dir.0, None).unwrap();
+            assert_eq!(loaded.public(), created.public());
+        }
+
+        let dir = TempDir::new();
+        identity(KeyType::Secp256k1)
+            .load_with_passphrase(&dir.0, None)
+            .unwrap();
+        let message = identity(KeyType::Ed25519)
+            .load_with_passphrase(&dir.0, None)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("holds a secp256k1 key"), "{}", message);
+    }
+
+    #[test]
+    fn test_raw_secret_keys_are_imported() {
+        let keypair = Keypair::generate_secp256k1();
+        let secret = keypair
+            .clone()
+            .try_into_secp256k1()
+            .unwrap()
+            .secret()
+            .to_bytes();
+        let hex: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let from_hex =
+            decode_key(format!("0x{}\n", hex).as_bytes(), KeyType::Secp256k1, None).unwrap();
+        assert_eq!(from_hex.public(), keypair.public());
+        let from_bytes = decode_key(&secret, KeyType::Secp256k1, None).unwrap();
+        assert_eq!(from_bytes.public(), keypair.public());
+
+        // The same secret read as another key type is a different identity.
+        let ecdsa = decode_key(&secret, KeyType::Ecdsa, None).unwrap();
+        assert_ne!(ecdsa.public().to_peer_id(), keypair.public().to_peer_id());
+        assert!(decode_key(b"not a key", KeyType::Ed25519, None).is_err());
+    }
+
+    #[test]
+    fn test_keystore_requires_the_passphrase() {
+        let keypair = Keypair::generate_ecdsa();
+        let keystore = Keystore::encrypt_with_cost(&keypair, "correct horse", 4).unwrap();
+        assert_eq!(keystore.peer_id(), keypair.public().to_peer_id());
+        assert_eq!(keystore.key_type(), KeyType::Ecdsa);
+
+        let json = keystore.to_json().unwrap();
+        let decrypted = Keystore::from_json(&json)
+            .unwrap()
+            .decrypt("correct horse")
+            .unwrap();
+        assert_eq!(decrypted.public(), keypair.public());
+        assert!(keystore.decrypt("battery staple").is_err());
+
+        // The peer id is authenticated along with the key.
+        let other = Keypair::generate_ecdsa().public().to_peer_id().to_string();
+        let tampered = json.replace(&keypair.public().to_peer_id().to_string(), &other);
+        assert!(Keystore::from_json(&tampered)
+            .unwrap()
+            .decrypt("correct horse")
+            .is_err());
+
+        let dir = TempDir::new();
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join("node.key"), json).unwrap();
+        let config = identity(KeyType::Ecdsa);
+        let message = config
+            .load_with_passphrase(&dir.0, None)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("encrypted"), "{}", message);
+        let loaded = config
+            .load_with_passphrase(&dir.0, Some("correct horse"))
+            .unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+    }
+
+    #[test]
+    fn test_identity_section_is_configurable() {
+        let config = NodeConfig::from_toml_str(
+            "[identity]\nkey_type = \"secp256k1\"\nkey_file = \"keys/validator.key\"\npassphrase_env = \"VALIDATOR_PASSPHRASE\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.identity.key_type, KeyType::Secp256k1);
+        assert_eq!(
+            config.identity.key_path(&config.data_dir),
+            Some(PathBuf::from("./keys/validator.key"))
+        );
+        assert_eq!(config.identity.passphrase_env, "VALIDATOR_PASSPHRASE");
+        assert!(NodeConfig::from_toml_str("[identity]\nkey_type = \"rsa\"\n").is_err());
+    }
+
+    #[test]
+    fn test_key_rotation_needs_both_signatures() {
+        let old = Keypair::generate_ed25519();
+        let new = Keypair::generate_secp256k1();
+        let rotation = KeyRotation::new(&old, &new).unwrap();
+        assert_eq!(
+            rotation.verify().unwrap(),
+            (old.public().to_peer_id(), new.public().to_peer_id())
+        );
+
+        // Claiming someone else's key as the new identity fails.
+        let victim = Keypair::generate_ed25519();
+        let mut forged = serde_json::to_value(&rotation).unwrap();
+        let other = serde_json::to_value(KeyRotation::new(&old, &victim).unwrap()).unwrap();
+        forged["new_key"] = other["new_key"].clone();
+        let forged: KeyRotation = serde_json::from_value(forged).unwrap();
+        assert!(forged.verify().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_is_announced_to_known_peers() -> Result<(), Box<dyn Error>> {
+        let mut observer = Node::<TestMessage>::new(memory_config(7590, None)).await?;
+        let observer_id = observer.local_peer_id();
+        let observer_handle = observer.handle();
+        let mut peer_events = observer.subscribe_peer_events();
+        tokio::spawn(async move { observer.start().await });
+
+        let mut rotating = Node::<TestMessage>::new(memory_config(7591, Some(7590))).await?;
+        let old_id = rotating.local_peer_id();
+        let handle = rotating.handle();
+        tokio::spawn(async move { rotating.start().await });
+
+        wait_until_connected(&handle, observer_id).await;
+
+        let new = Keypair::generate_secp256k1();
+        let new_id = new.public().to_peer_id();
+        assert_eq!(handle.announce_key_rotation(&new).await?, vec![observer_id]);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Ok(event) = peer_events.recv().await {
+                if event
+                    == (PeerEvent::KeyRotated {
+                        old: old_id,
+                        new: new_id,
+                    })
+                {
+                    return;
+                }
+            }
+            panic!("peer event channel closed");
+        })
+        .await?;
+        let known = observer_handle
+            .peer_info(new_id)
+            .await?
+            .expect("new peer id is known");
+        assert!(!known.addresses.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotation_replaces_the_key_file() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new();
+        let mut config = memory_config(7608, None);
+        config.data_dir = dir.0.clone();
+        config.identity = identity(KeyType::Ed25519);
+        let node = Node::<TestMessage>::new(config.clone()).await?;
+        let old_id = node.local_peer_id();
+        let handle = node.handle();
+        tokio::spawn(async move {
+            let mut node = node;
+            node.start().await
+        });
+        let path = dir.0.join("node.key");
+        let key_id = |path: &PathBuf| {
+            decode_key(&fs::read(path).unwrap(), KeyType::Ed25519, None)
+                .unwrap()
+                .public()
+                .to_peer_id()
+        };
+
+        // A key that cannot be written leaves the old one in place.
+        fs::create_dir(dir.0.join("node.key.new"))?;
+        assert!(config.identity.rotate(&dir.0, &handle).await.is_err());
+        assert_eq!(key_id(&path), old_id);
+        assert!(!dir.0.join("node.key.old").exists());
+        fs::remove_dir(dir.0.join("node.key.new"))?;
+
+        let new = config.identity.rotate(&dir.0, &handle).await?;
+        assert_eq!(key_id(&path), new.public().to_peer_id());
+        assert_eq!(key_id(&dir.0.join("node.key.old")), old_id);
+        assert!(!dir.0.join("node.key.new").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        network::{Node, NodeConfig},
+        tests::{memory_config, wait_until_connected, TestMessage},
+        KeepAlivePolicy, PeerEvent,
+    };
+    use libp2p::PeerId;
+    use std::{collections::HashSet, error::Error, time::Duration};
+    use tokio::sync::broadcast;
+
+    async fn wait_for_disconnect(events: &mut broadcast::Receiver<PeerEvent>, peer: PeerId) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while let Ok(event) = events.recv().await {
+                if event == PeerEvent::Disconnected(peer) {
+                    return;
+                }
+            }
+        })
+        .await
+        .expect("idle connection was not closed");
+    }
+
+    #[test]
+    fn test_connection_settings_are_configurable() {
+        let peer = PeerId::random();
+        let config = NodeConfig::from_toml_str(&format!(
+            "request_timeout = \"2s\"\nmax_concurrent_streams = 8\nidle_connection_timeout = \"5m\"\n\n[keep_alive]\nbootstrap = false\npeers = [\"{}\"]\n",
+            peer
+        ))
+        .unwrap();
+        assert_eq!(config.request_timeout, Duration::from_secs(2));
+        assert_eq!(config.max_concurrent_streams, 8);
+        assert_eq!(config.idle_connection_timeout, Duration::from_secs(300));
+        assert_eq!(
+            config.keep_alive,
+            KeepAlivePolicy {
+                bootstrap: false,
+                peers: HashSet::from([peer]),
+            }
+        );
+
+        let mut config = NodeConfig::default();
+        assert!(config.keep_alive.bootstrap);
+        config.request_timeout = Duration::ZERO;
+        config.max_concurrent_streams = 0;
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("request_timeout"), "{}", message);
+        assert!(message.contains("max_concurrent_streams"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_kept_connections_are_not_closed_for_idleness() -> Result<(), Box<dyn Error>> {
+        let mut bootstrap = Node::<TestMessage>::new(memory_config(7570, None)).await?;
+        let bootstrap_id = bootstrap.local_peer_id();
+        tokio::spawn(async move { bootstrap.start().await });
+
+        let mut config = memory_config(7571, Some(7570));
+        config.idle_connection_timeout = Duration::from_millis(300);
+        let mut kept = Node::<TestMessage>::new(config).await?;
+        let kept_handle = kept.handle();
+        let mut kept_events = kept.subscribe_peer_events();
+        tokio::spawn(async move { kept.start().await });
+
+        let mut config = memory_config(7572, Some(7570));
+        config.idle_connection_timeout = Duration::from_millis(300);
+        config.keep_alive.bootstrap = false;
+        let mut idle = Node::<TestMessage>::new(config).await?;
+        let idle_handle = idle.handle();
+        let mut idle_events = idle.subscribe_peer_events();
+        tokio::spawn(async move { idle.start().await });
+
+        wait_until_connected(&kept_handle, bootstrap_id).await;
+        wait_until_connected(&idle_handle, bootstrap_id).await;
+        wait_for_disconnect(&mut idle_events, bootstrap_id).await;
+        assert!(kept_handle.connected_peers().await?.contains(&bootstrap_id));
+
+        // Once released, the connection is closed like any other.
+        kept_handle.release_keep_alive(bootstrap_id)?;
+        wait_for_disconnect(&mut kept_events, bootstrap_id).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::limits::{AdmissionControl, ConnectionLimits};
+    use libp2p::{
+        core::{ConnectedPoint, Endpoint},
+        swarm::{behaviour::ConnectionEstablished, ConnectionId, FromSwarm, NetworkBehaviour},
+        Multiaddr, PeerId,
+    };
+    use std::collections::HashSet;
+
+    fn accept_inbound(control: &mut AdmissionControl, id: usize, peer: PeerId) -> bool {
+        let connection_id = ConnectionId::new_unchecked(id);
+        let local: Multiaddr = "/ip4/127.0.0.1/tcp/8000".parse().unwrap();
+        let remote: Multiaddr = "/ip4/127.0.0.1/tcp/50000".parse().unwrap();
+        if control
+            .handle_established_inbound_connection(connection_id, peer, &local, &remote)
+            .is_err()
+        {
+            return false;
+        }
+        let endpoint = ConnectedPoint::Listener {
+            local_addr: local,
+            send_back_addr: remote,
+        };
+        control.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+            peer_id: peer,
+            connection_id,
+            endpoint: &endpoint,
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+        true
+    }
+
+    #[test]
+    fn test_reserved_slots_kept_for_reserved_peers() {
+        let reserved_peer = PeerId::random();
+        let mut control = AdmissionControl::new(ConnectionLimits {
+            max_established: Some(2),
+            reserved_slots: 1,
+            reserved_peers: HashSet::from([reserved_peer]),
+            ..Default::default()
+        });
+
+        assert!(accept_inbound(&mut control, 1, PeerId::random()));
+        // The only remaining slot is reserved.
+        assert!(!accept_inbound(&mut control, 2, PeerId::random()));
+        assert!(accept_inbound(&mut control, 3, reserved_peer));
+        // Reserved peers cannot exceed the hard limit either.
+        assert!(!accept_inbound(&mut control, 4, reserved_peer));
+        assert_eq!(control.established_connections(), 2);
+    }
+
+    #[test]
+    fn test_per_peer_and_outbound_limits() {
+        let peer = PeerId::random();
+        let mut control = AdmissionControl::new(ConnectionLimits {
+            max_established_per_peer: Some(1),
+            max_pending_outgoing: Some(1),
+            ..Default::default()
+        });
+
+        assert!(accept_inbound(&mut control, 1, peer));
+        assert!(!accept_inbound(&mut control, 2, peer));
+
+        let mut dial = |id| {
+            control.handle_pending_outbound_connection(
+                ConnectionId::new_unchecked(id),
+                None,
+                &[],
+                Endpoint::Dialer,
+            )
+        };
+        assert!(dial(3).is_ok());
+        assert!(dial(4).is_err());
+    }
+}
@@ -1,6 +1,14 @@
 use crate::p2plane::traits::Message;
+#[cfg(test)]
+use crate::p2plane::{
+    handle::NodeHandle,
+    network::{NodeConfig, PeerStorageKind},
+};
+#[cfg(test)]
+use libp2p::PeerId;
+#[cfg(test)]
+use std::{fs, path::PathBuf, time::Duration};
 use serde::{Serialize, Deserialize};
-use std::error::Error;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct TestMessage(pub String);
@@ -11,6 +19,49 @@ impl Message for TestMessage {
     }
 }
 
+/// Config of a node listening on `/memory/<port>` that keeps its peers in
+/// memory and optionally bootstraps from `/memory/<bootstrap>`.
+#[cfg(test)]
+pub(crate) fn memory_config(port: u64, bootstrap: Option<u64>) -> NodeConfig {
+    NodeConfig {
+        listen_addr: format!("/memory/{}", port),
+        bootstrap_addr: bootstrap.map(|p| format!("/memory/{}", p).parse().unwrap()),
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    }
+}
+
+/// Waits up to five seconds for the node behind `handle` to be connected to
+/// `peer`.
+#[cfg(test)]
+pub(crate) async fn wait_until_connected<M: Message>(handle: &NodeHandle<M>, peer: PeerId) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !handle.connected_peers().await.unwrap().contains(&peer) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("peers did not connect");
+}
+
+/// Directory under the system temp dir, removed again when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(pub PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("p2plane-test-{}", PeerId::random())))
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod behavior_tests;
 
@@ -18,4 +69,30 @@ mod behavior_tests;
 mod network_tests;
 
 #[cfg(test)]
-mod peer_manager_tests;
\ No newline at end of file
+mod peer_manager_tests;
+#[cfg(test)]
+mod limits_tests;
+#[cfg(test)]
+mod simulator_tests;
+#[cfg(test)]
+mod cluster_tests;
+#[cfg(test)]
+mod builder_tests;
+#[cfg(test)]
+mod config_tests;
+#[cfg(test)]
+mod address_book_tests;
+#[cfg(test)]
+mod storage_tests;
+#[cfg(test)]
+mod stream_tests;
+#[cfg(test)]
+mod queue_tests;
+#[cfg(test)]
+mod keep_alive_tests;
+#[cfg(test)]
+mod admin_tests;
+#[cfg(test)]
+mod identity_tests;
+#[cfg(all(test, feature = "pnet"))]
+mod swarm_key_tests;
@@ -1,17 +1,29 @@
 #[cfg(test)]
 mod tests {
     use crate::p2plane::{
-        network::{Node, NodeConfig},
-        tests::TestMessage,
+        network::{Node, NodeConfig, PeerStorageKind},
+        builder::{NodeBuilder, HIGH_PRIORITY_PROTOCOL, MESSAGE_PROTOCOL},
+        handle::NodeEvent,
+        tests::{memory_config, wait_until_connected, TestMessage},
+        peer_manager::PeerManager,
+        traits::{async_trait, AsyncPeerManagement, Message, PeerManagement},
+        ErrorChain, P2PlaneError, PeerEvent, Priority,
     };
-    use std::error::Error;
-    use libp2p::Multiaddr;
+    use serde::{Deserialize, Serialize};
+    use std::{
+        error::Error,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use libp2p::{core::ConnectedPoint, identity::Keypair, Multiaddr, PeerId};
+    use tokio::sync::broadcast;
 
     #[tokio::test]
     async fn test_node_creation() -> Result<(), Box<dyn Error>> {
         let config = NodeConfig {
             listen_addr: "/ip4/127.0.0.1/tcp/0".parse()?,
             bootstrap_addr: None,
+            ..Default::default()
         };
 
         let _node = Node::<TestMessage>::new(config).await?;
@@ -24,9 +36,481 @@ mod tests {
         let config = NodeConfig {
             listen_addr: addr.to_string(),
             bootstrap_addr: None,
+            ..Default::default()
         };
 
         assert_eq!(config.listen_addr, addr.to_string());
         Ok(())
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_errors_can_be_matched() -> Result<(), Box<dyn Error>> {
+        let result = Node::<TestMessage>::new(NodeConfig {
+            listen_addr: "not-an-address".to_string(),
+            peer_storage: PeerStorageKind::Memory,
+            ..Default::default()
+        })
+        .await;
+        assert!(matches!(result, Err(P2PlaneError::Config { .. })));
+
+        let node = Node::<TestMessage>::new(NodeConfig {
+            peer_storage: PeerStorageKind::Memory,
+            ..Default::default()
+        })
+        .await?;
+        let handle = node.handle();
+
+        drop(node);
+        assert!(matches!(
+            handle.peer_info(libp2p::PeerId::random()).await,
+            Err(P2PlaneError::NodeStopped)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrapped_errors_keep_their_source() {
+        for error in [
+            P2PlaneError::transport("no route"),
+            P2PlaneError::Codec("no route".into()),
+            P2PlaneError::storage("no route"),
+            P2PlaneError::stream("no route"),
+        ] {
+            assert_eq!(error.source().unwrap().to_string(), "no route");
+            assert!(!error.to_string().contains("no route"));
+            assert_eq!(ErrorChain(&error).to_string(), format!("{}: no route", error));
+        }
+    }
+
+    #[test]
+    fn test_node_future_is_send() {
+        fn assert_send<T: Send>(_: T) {}
+        let config = NodeConfig::default();
+        assert_send(async move {
+            let mut node = Node::<TestMessage>::new(config).await?;
+            node.start().await
+        });
+    }
+
+    #[tokio::test]
+    async fn test_identify_metadata_is_recorded() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7500, None)).await?;
+        let first_id = first.local_peer_id();
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7501, Some(7500))).await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+
+        let supporting = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let peers = handle.peers_supporting(MESSAGE_PROTOCOL).await.unwrap();
+                if !peers.is_empty() {
+                    return peers;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+        assert_eq!(supporting, vec![first_id]);
+        assert!(handle.peers_supporting("/unknown/1.0.0").await?.is_empty());
+
+        let metadata = handle.peer_info(first_id).await?.unwrap().metadata.unwrap();
+        assert_eq!(metadata.protocol_version, "/p2plane/1.0.0");
+        assert!(metadata.listen_addrs.contains(&"/memory/7500".parse()?));
+        Ok(())
+    }
+
+    /// Manager that broadcasts to a fixed set of peers and records the
+    /// connection events it receives.
+    #[derive(Debug)]
+    struct FixedPeers {
+        peers: Vec<PeerId>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PeerManagement for FixedPeers {
+        fn get_peers(&self) -> Vec<PeerId> {
+            self.peers.clone()
+        }
+
+        fn add_peer_with_addr(&mut self, _peer_id: PeerId, _addr: Multiaddr) {}
+
+        fn remove_peer(&mut self, _peer_id: &PeerId) -> bool {
+            false
+        }
+
+        fn get_address(&self, _peer_id: &PeerId) -> Option<Multiaddr> {
+            None
+        }
+
+        fn on_connected(&mut self, peer_id: PeerId, _endpoint: &ConnectedPoint) {
+            self.events.lock().unwrap().push(format!("connected {}", peer_id));
+        }
+
+        fn on_dial_success(&mut self, peer_id: PeerId, _addr: &Multiaddr) {
+            self.events.lock().unwrap().push(format!("dialed {}", peer_id));
+        }
+
+        fn on_disconnected(&mut self, peer_id: PeerId) {
+            self.events.lock().unwrap().push(format!("disconnected {}", peer_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_peer_manager_is_used() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7510, None)).await?;
+        let first_id = first.local_peer_id();
+        let mut messages = first.subscribe();
+        tokio::spawn(async move { first.start().await });
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut second = NodeBuilder::<TestMessage>::new(memory_config(7511, Some(7510)))
+            .peer_manager(FixedPeers {
+                peers: vec![first_id],
+                events: events.clone(),
+            })
+            .build()
+            .await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+
+        let wait_for_events = |count: usize| {
+            let events = events.clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while events.lock().unwrap().len() < count {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            })
+        };
+        wait_for_events(2).await?;
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![format!("dialed {}", first_id), format!("connected {}", first_id)]
+        );
+
+        // A second connection to a connected peer is still a successful dial.
+        handle.dial("/memory/7510".parse()?).await?;
+        wait_for_events(3).await?;
+        assert_eq!(events.lock().unwrap()[2], format!("dialed {}", first_id));
+
+        handle.broadcast_message(TestMessage("hello".to_string())).await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(NodeEvent::Message { message, .. }) = messages.recv().await {
+                    return message;
+                }
+            }
+        })
+        .await?;
+        assert_eq!(received.0, "hello");
+        assert!(!handle.remove_peer(first_id).await?);
+        Ok(())
+    }
+
+    /// Takes ten seconds to take note of every new connection.
+    #[derive(Debug)]
+    struct SlowPeers {
+        peers: Vec<PeerId>,
+    }
+
+    #[async_trait]
+    impl AsyncPeerManagement for SlowPeers {
+        async fn get_peers(&self) -> Vec<PeerId> {
+            self.peers.clone()
+        }
+
+        async fn add_peer_with_addr(&mut self, _peer_id: PeerId, _addr: Multiaddr) {}
+
+        async fn remove_peer(&mut self, _peer_id: &PeerId) -> bool {
+            false
+        }
+
+        async fn get_address(&self, _peer_id: &PeerId) -> Option<Multiaddr> {
+            None
+        }
+
+        async fn on_connected(&mut self, _peer_id: PeerId, _endpoint: &ConnectedPoint) {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_peer_manager_does_not_stall_the_node() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7602, None)).await?;
+        let first_id = first.local_peer_id();
+        let mut messages = first.subscribe();
+        tokio::spawn(async move { first.start().await });
+
+        let mut second = NodeBuilder::<TestMessage>::new(memory_config(7603, Some(7602)))
+            .peer_manager(SlowPeers { peers: vec![first_id] })
+            .build()
+            .await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+        wait_until_connected(&handle, first_id).await;
+
+        // The manager is still busy with the connection while the node
+        // delivers messages.
+        let received = tokio::time::timeout(Duration::from_secs(2), async {
+            handle.send_message(first_id, TestMessage("hello".to_string())).await?;
+            loop {
+                if let Ok(NodeEvent::Message { message, .. }) = messages.recv().await {
+                    return Ok::<_, Box<dyn Error>>(message);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(received.0, "hello");
+        Ok(())
+    }
+
+    /// Takes ten seconds to look up the peers to broadcast to or dial.
+    #[derive(Debug)]
+    struct SlowLookups;
+
+    #[async_trait]
+    impl AsyncPeerManagement for SlowLookups {
+        async fn get_peers(&self) -> Vec<PeerId> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Vec::new()
+        }
+
+        async fn add_peer_with_addr(&mut self, _peer_id: PeerId, _addr: Multiaddr) {}
+
+        async fn remove_peer(&mut self, _peer_id: &PeerId) -> bool {
+            false
+        }
+
+        async fn get_address(&self, _peer_id: &PeerId) -> Option<Multiaddr> {
+            None
+        }
+
+        async fn dial_candidates(&self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_peer_lookups_do_not_stall_the_node() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7609, None)).await?;
+        let first_id = first.local_peer_id();
+        let mut messages = first.subscribe();
+        tokio::spawn(async move { first.start().await });
+
+        let mut second = NodeBuilder::<TestMessage>::new(memory_config(7610, Some(7609)))
+            .peer_manager(SlowLookups)
+            .build()
+            .await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+        wait_until_connected(&handle, first_id).await;
+
+        // The manager is still looking up peers while the node delivers
+        // messages.
+        for command in [
+            tokio::spawn({
+                let handle = handle.clone();
+                async move { handle.broadcast_message(TestMessage("all".to_string())).await }
+            }),
+            tokio::spawn({
+                let handle = handle.clone();
+                async move { handle.dial_peer(PeerId::random()).await }
+            }),
+        ] {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!command.is_finished());
+        }
+        let received = tokio::time::timeout(Duration::from_secs(2), async {
+            handle.send_message(first_id, TestMessage("hello".to_string())).await?;
+            loop {
+                if let Ok(NodeEvent::Message { message, .. }) = messages.recv().await {
+                    return Ok::<_, Box<dyn Error>>(message);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(received.0, "hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_node_broadcasts_to_inbound_peers() -> Result<(), Box<dyn Error>> {
+        let mut bootstrap = Node::<TestMessage>::new(memory_config(7530, None)).await?;
+        let handle = bootstrap.handle();
+        tokio::spawn(async move { bootstrap.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7531, Some(7530))).await?;
+        let second_id = second.local_peer_id();
+        let mut messages = second.subscribe();
+        tokio::spawn(async move { second.start().await });
+
+        // The inbound peer is redialable through the address it listens on.
+        let listen: Multiaddr = "/memory/7531".parse()?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let info = handle.peer_info(second_id).await.unwrap();
+                if info.is_some_and(|info| info.addresses.contains(&listen)) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+        let info = handle.peer_info(second_id).await?.unwrap();
+        assert_eq!(info.addresses, vec![listen]);
+
+        handle.broadcast_message(TestMessage("hello".to_string())).await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(NodeEvent::Message { message, .. }) = messages.recv().await {
+                    return message;
+                }
+            }
+        })
+        .await?;
+        assert_eq!(received.0, "hello");
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum ConsensusMessage {
+        Vote(u64),
+        Transaction(u64),
+    }
+
+    impl Message for ConsensusMessage {
+        fn protocol_id(&self) -> &'static str {
+            "/test/consensus/1.0.0"
+        }
+
+        fn priority(&self) -> Priority {
+            match self {
+                ConsensusMessage::Vote(_) => Priority::High,
+                ConsensusMessage::Transaction(_) => Priority::Low,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_messages_use_their_own_protocol() -> Result<(), Box<dyn Error>> {
+        let config = |port: u64, bootstrap: Option<u64>| {
+            let mut config = memory_config(port, bootstrap);
+            config.outbound_queue.high_priority_protocol = true;
+            config
+        };
+        let mut first = Node::<ConsensusMessage>::new(config(7560, None)).await?;
+        let first_id = first.local_peer_id();
+        let mut messages = first.subscribe();
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<ConsensusMessage>::new(config(7561, Some(7560))).await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !handle
+                .peers_supporting(HIGH_PRIORITY_PROTOCOL)
+                .await
+                .unwrap()
+                .contains(&first_id)
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+
+        for i in 0..4 {
+            handle.send_message(first_id, ConsensusMessage::Transaction(i)).await?;
+        }
+        handle.send_message(first_id, ConsensusMessage::Vote(7)).await?;
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while received.len() < 5 {
+                if let Ok(NodeEvent::Message { message, .. }) = messages.recv().await {
+                    received.push(message);
+                }
+            }
+        })
+        .await?;
+        assert!(received.contains(&ConsensusMessage::Vote(7)));
+        assert!(received.contains(&ConsensusMessage::Transaction(3)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_a_slow_peer_stays_bounded() -> Result<(), Box<dyn Error>> {
+        let mut config = NodeConfig {
+            listen_addr: "/memory/7600".to_string(),
+            peer_storage: PeerStorageKind::Memory,
+            ..Default::default()
+        };
+        config.outbound_queue.capacity = 2;
+        config.outbound_queue.max_in_flight = 1;
+        let keypair = Keypair::generate_ed25519();
+        let mut manager =
+            PeerManager::with_storage(keypair.public().to_peer_id(), PeerStorageKind::Memory);
+        // Nothing answers on this address, and the event loop is not running,
+        // so no request ever completes.
+        let slow = PeerId::random();
+        manager.add_peer_with_addr(slow, "/memory/7601".parse()?);
+        let mut node = NodeBuilder::<TestMessage>::new(config)
+            .keypair(keypair)
+            .peer_manager(manager)
+            .build()
+            .await?;
+
+        let mut rejected = 0;
+        for i in 0..100 {
+            match node.broadcast_message(TestMessage(i.to_string())).await {
+                Ok(()) => {}
+                Err(P2PlaneError::QueueFull { peer }) if peer == slow => rejected += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let depth = node.queue_depth(&slow).expect("slow peer has a queue");
+        assert_eq!((depth.in_flight, depth.queued, depth.blocked), (1, 2, 0));
+        assert_eq!(rejected, 97);
+        Ok(())
+    }
+
+    async fn wait_for(events: &mut broadcast::Receiver<PeerEvent>, wanted: PeerEvent) {
+        let mut seen = Vec::new();
+        let found = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Ok(event) = events.recv().await {
+                if event == wanted {
+                    return;
+                }
+                seen.push(event);
+            }
+        })
+        .await;
+        assert!(found.is_ok(), "no {:?}, saw {:?}", wanted, seen);
+    }
+
+    #[tokio::test]
+    async fn test_banned_peer_is_disconnected_and_refused() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7520, None)).await?;
+        let first_handle = first.handle();
+        let mut events = first.subscribe_peer_events();
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7521, Some(7520))).await?;
+        let second_id = second.local_peer_id();
+        let second_handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+
+        wait_for(&mut events, PeerEvent::Connected(second_id)).await;
+
+        first_handle.ban_peer(second_id)?;
+        wait_for(&mut events, PeerEvent::Banned(second_id)).await;
+        wait_for(&mut events, PeerEvent::Disconnected(second_id)).await;
+
+        second_handle.dial("/memory/7520".parse()?).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(first_handle.connected_peers().await?.is_empty());
+
+        first_handle.unban_peer(second_id)?;
+        wait_for(&mut events, PeerEvent::Unbanned(second_id)).await;
+        second_handle.dial("/memory/7520".parse()?).await?;
+        wait_for(&mut events, PeerEvent::Connected(second_id)).await;
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
 #[cfg(test)]
-use crate::p2plane::peer_manager::PeerManager;
+use crate::p2plane::peer_manager::{PeerManager, PeerStorageKind};
+#[cfg(test)]
+use crate::p2plane::{
+    address_book::AddressSource, storage::MemoryStore, tests::TempDir, PeerEvent, PeerMetadata,
+};
 use crate::p2plane::traits::PeerManagement;
-use libp2p::{PeerId, Multiaddr};
+use libp2p::{core::ConnectedPoint, PeerId, Multiaddr};
+use std::time::Duration;
 
 #[cfg(test)]
 mod tests {
@@ -10,14 +15,14 @@ mod tests {
     #[test]
     fn test_peer_manager_creation() {
         let peer_id = PeerId::random();
-        let manager = PeerManager::new(peer_id);
+        let manager = PeerManager::with_storage(peer_id, PeerStorageKind::Memory);
         assert!(manager.get_peers().is_empty());
     }
 
     #[test]
     fn test_add_peer() {
         let local_peer_id = PeerId::random();
-        let mut manager = PeerManager::new(local_peer_id);
+        let mut manager = PeerManager::with_storage(local_peer_id, PeerStorageKind::Memory);
         
         let peer_id = PeerId::random();
         let addr: Multiaddr = "/ip4/127.0.0.1/tcp/8000".parse().unwrap();
@@ -26,4 +31,149 @@ mod tests {
         assert_eq!(manager.get_peers().len(), 1);
         assert!(manager.get_peers().contains(&peer_id));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_direct_address_preferred_over_relayed() {
+        let local_peer_id = PeerId::random();
+        let mut manager = PeerManager::with_storage(local_peer_id, PeerStorageKind::Memory);
+
+        let peer_id = PeerId::random();
+        let direct: Multiaddr = "/ip4/127.0.0.1/tcp/8000".parse().unwrap();
+        let relayed: Multiaddr = format!(
+            "/ip4/127.0.0.1/tcp/9000/p2p/{}/p2p-circuit",
+            PeerId::random()
+        )
+        .parse()
+        .unwrap();
+
+        manager.add_peer_with_addr(peer_id, direct.clone());
+        manager.add_peer_with_addr(peer_id, relayed);
+        assert_eq!(manager.get_peer_address(&peer_id), Some(&direct));
+    }
+
+    #[test]
+    fn test_ping_statistics() {
+        let mut manager = PeerManager::with_storage(PeerId::random(), PeerStorageKind::Memory);
+        let peer_id = PeerId::random();
+        assert!(manager.peer_info(&peer_id).is_none());
+
+        manager.record_ping(peer_id, Duration::from_millis(80));
+        manager.record_ping(peer_id, Duration::from_millis(160));
+        let info = manager.peer_info(&peer_id).unwrap();
+        assert_eq!(info.rtt, Some(Duration::from_millis(90)));
+
+        assert_eq!(manager.record_ping_failure(peer_id), 1);
+        assert_eq!(manager.record_ping_failure(peer_id), 2);
+        manager.record_ping(peer_id, Duration::from_millis(90));
+        assert_eq!(manager.peer_info(&peer_id).unwrap().missed_pings, 0);
+
+        PeerManagement::on_disconnected(&mut manager, peer_id);
+        assert!(manager.peer_info(&peer_id).is_none());
+    }
+
+    #[test]
+    fn test_address_book_persists_across_restarts() {
+        let local_peer_id = PeerId::random();
+        let peer_id = PeerId::random();
+        let first: Multiaddr = "/ip4/127.0.0.1/tcp/8000".parse().unwrap();
+        let second: Multiaddr = "/ip4/192.168.1.5/tcp/8000".parse().unwrap();
+        let dir = TempDir::new();
+        {
+            let mut manager = PeerManager::open(local_peer_id, &dir.0, Duration::ZERO);
+            manager.add_address(peer_id, first.clone(), AddressSource::Mdns);
+            manager.record_dial_success(peer_id, second.clone());
+        }
+
+        let manager = PeerManager::open(local_peer_id, &dir.0, Duration::ZERO);
+        assert_eq!(manager.dial_candidates(&peer_id), vec![second.clone(), first]);
+        let record = &manager.address_book().records(&peer_id)[0];
+        assert_eq!(record.source, AddressSource::Dialed);
+        assert!(record.last_success.is_some());
+    }
+
+    #[test]
+    fn test_identify_metadata() {
+        let mut manager = PeerManager::with_storage(PeerId::random(), PeerStorageKind::Memory);
+        let peer_id = PeerId::random();
+        let listen: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        manager.record_identify(
+            peer_id,
+            PeerMetadata {
+                agent_version: "narwhal/0.1.0".to_string(),
+                protocol_version: "/p2plane/1.0.0".to_string(),
+                protocols: vec!["/p2plane/message/1.0.0".to_string()],
+                listen_addrs: vec![listen.clone()],
+                observed_addr: "/ip4/10.0.0.1/tcp/50000".parse().unwrap(),
+            },
+        );
+
+        assert_eq!(manager.peers_supporting("/p2plane/message/1.0.0"), vec![peer_id]);
+        assert!(manager.peers_supporting("/ipfs/kad/1.0.0").is_empty());
+        assert_eq!(manager.metadata(&peer_id).unwrap().agent_version, "narwhal/0.1.0");
+        let record = &manager.address_book().records(&peer_id)[0];
+        assert_eq!((&record.addr, record.source), (&listen, AddressSource::Identify));
+    }
+
+    #[test]
+    fn test_inbound_peer_is_recorded_without_its_remote_address() {
+        let store = MemoryStore::new();
+        let mut manager = PeerManager::with_store(PeerId::random(), Box::new(store.clone()), Duration::ZERO);
+        let peer_id = PeerId::random();
+        manager.on_connected(
+            peer_id,
+            &ConnectedPoint::Listener {
+                local_addr: "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+                send_back_addr: "/ip4/10.0.0.4/tcp/53124".parse().unwrap(),
+            },
+        );
+        assert!(manager.get_peers().contains(&peer_id));
+        assert!(manager.dial_candidates(&peer_id).is_empty());
+        assert!(store.get(&peer_id).is_some());
+    }
+
+    #[test]
+    fn test_remove_peer_deletes_it_from_the_store() {
+        let store = MemoryStore::new();
+        let mut manager = PeerManager::with_store(PeerId::random(), Box::new(store.clone()), Duration::ZERO);
+        let peer_id = PeerId::random();
+        manager.add_peer_with_addr(peer_id, "/ip4/10.0.0.3/tcp/4001".parse().unwrap());
+        manager.record_ping(peer_id, Duration::from_millis(10));
+        assert_eq!(store.len(), 1);
+
+        assert!(PeerManagement::remove_peer(&mut manager, &peer_id));
+        assert!(!manager.remove_peer(&peer_id));
+        assert!(manager.peer_info(&peer_id).is_none());
+        assert!(PeerManagement::get_address(&manager, &peer_id).is_none());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_peer_events() {
+        let mut manager = PeerManager::with_storage(PeerId::random(), PeerStorageKind::Memory);
+        let mut events = manager.subscribe();
+        let peer_id = PeerId::random();
+        let direct: Multiaddr = "/ip4/10.0.0.4/tcp/4001".parse().unwrap();
+        let relayed: Multiaddr = format!("/ip4/10.0.0.9/tcp/4001/p2p/{}/p2p-circuit", PeerId::random())
+            .parse()
+            .unwrap();
+
+        manager.add_address(peer_id, relayed.clone(), AddressSource::Kademlia);
+        manager.add_address(peer_id, direct.clone(), AddressSource::Identify);
+        manager.add_address(peer_id, relayed.clone(), AddressSource::Kademlia);
+        manager.remove_peer(&peer_id);
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![
+                PeerEvent::Added(peer_id),
+                PeerEvent::AddressChanged { peer: peer_id, address: Some(relayed) },
+                PeerEvent::AddressChanged { peer: peer_id, address: Some(direct) },
+                PeerEvent::Removed(peer_id),
+            ]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        queue::{
+            Lane, OutboundQueueConfig, OutboundQueues, OverflowPolicy, Priority, PriorityWeights,
+            QueueDepth,
+        },
+        NodeConfig, P2PlaneError,
+    };
+    use libp2p::{futures::channel::oneshot, PeerId};
+
+    fn queues(
+        capacity: usize,
+        max_in_flight: usize,
+        overflow: OverflowPolicy,
+    ) -> OutboundQueues<u32> {
+        OutboundQueues::new(OutboundQueueConfig {
+            capacity,
+            max_in_flight,
+            overflow,
+            ..Default::default()
+        })
+    }
+
+    fn next(queues: &mut OutboundQueues<u32>, peer: &PeerId) -> Option<u32> {
+        queues.next(peer).map(|(message, _)| message)
+    }
+
+    #[test]
+    fn test_block_holds_the_caller_until_there_is_room() {
+        let mut queues = queues(1, 2, OverflowPolicy::Block);
+        let peer = PeerId::random();
+        queues.push(peer, Priority::Normal, 1, None).unwrap();
+        assert_eq!(next(&mut queues, &peer), Some(1));
+        queues.push(peer, Priority::Normal, 2, None).unwrap();
+
+        let (reply, mut rx) = oneshot::channel();
+        let waiter = queues.waiter(reply);
+        queues.push(peer, Priority::Normal, 3, Some(waiter)).unwrap();
+        queues.release(waiter, Ok(()));
+        assert!(matches!(rx.try_recv(), Ok(None)));
+        assert_eq!(
+            queues.depth(&peer),
+            Some(QueueDepth {
+                queued: 1,
+                queued_by_priority: [0, 1, 0],
+                in_flight: 1,
+                blocked: 1,
+                dropped: 0,
+            })
+        );
+
+        assert_eq!(next(&mut queues, &peer), Some(2));
+        assert!(matches!(rx.try_recv(), Ok(Some(Ok(())))));
+        // Both messages are in flight, so the third has to wait.
+        assert_eq!(next(&mut queues, &peer), None);
+        assert_eq!(queues.depth(&peer).unwrap().queued, 1);
+
+        // Without a caller to hold up, a message that does not fit is
+        // rejected instead of piling up.
+        assert!(matches!(
+            queues.push(peer, Priority::Normal, 4, None),
+            Err(P2PlaneError::QueueFull { .. })
+        ));
+        assert_eq!(queues.depth(&peer).unwrap().blocked, 0);
+    }
+
+    #[test]
+    fn test_drop_oldest_makes_room() {
+        let mut queues = queues(2, 1, OverflowPolicy::DropOldest);
+        let peer = PeerId::random();
+        for message in 1..=3 {
+            queues.push(peer, Priority::Normal, message, None).unwrap();
+        }
+        assert_eq!(queues.depth(&peer).unwrap().dropped, 1);
+        assert_eq!(next(&mut queues, &peer), Some(2));
+        assert_eq!(next(&mut queues, &peer), None);
+    }
+
+    #[test]
+    fn test_error_rejects_messages_for_full_queues() {
+        let mut queues = queues(1, 1, OverflowPolicy::Error);
+        let peer = PeerId::random();
+        queues.push(peer, Priority::Normal, 1, None).unwrap();
+        assert!(matches!(
+            queues.push(peer, Priority::Normal, 2, None),
+            Err(P2PlaneError::QueueFull { peer: full }) if full == peer
+        ));
+        assert!(queues.push(PeerId::random(), Priority::Normal, 3, None).is_ok());
+    }
+
+    #[test]
+    fn test_removing_a_peer_releases_blocked_callers() {
+        let mut queues = queues(1, 1, OverflowPolicy::Block);
+        let peer = PeerId::random();
+        queues.push(peer, Priority::Normal, 1, None).unwrap();
+        let (reply, mut rx) = oneshot::channel();
+        let waiter = queues.waiter(reply);
+        queues.push(peer, Priority::Normal, 2, Some(waiter)).unwrap();
+        queues.release(waiter, Ok(()));
+
+        queues.remove(&peer);
+        assert!(matches!(rx.try_recv(), Ok(Some(Ok(())))));
+        assert!(queues.depths().is_empty());
+    }
+
+    #[test]
+    fn test_outbound_queue_config() {
+        let config = NodeConfig::from_toml_str(
+            "[outbound_queue]\ncapacity = 8\noverflow = \"drop_oldest\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.outbound_queue.capacity, 8);
+        assert_eq!(config.outbound_queue.overflow, OverflowPolicy::DropOldest);
+        assert_eq!(config.outbound_queue.max_in_flight, 16);
+
+        let mut config = NodeConfig::default();
+        config.outbound_queue.capacity = 0;
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("outbound_queue.capacity"), "{}", message);
+
+        let config = NodeConfig::from_toml_str(
+            "[outbound_queue]\nhigh_priority_protocol = true\n\n[outbound_queue.weights]\nhigh = 8\nlow = 0\n",
+        )
+        .unwrap();
+        assert!(config.outbound_queue.high_priority_protocol);
+        assert_eq!(
+            config.outbound_queue.weights,
+            PriorityWeights {
+                high: 8,
+                normal: 4,
+                low: 0,
+            }
+        );
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("outbound_queue.weights.low"), "{}", message);
+    }
+
+    #[test]
+    fn test_weighted_round_robin_favours_urgent_messages() {
+        let mut queues = OutboundQueues::new(OutboundQueueConfig {
+            max_in_flight: 100,
+            weights: PriorityWeights {
+                high: 2,
+                normal: 1,
+                low: 1,
+            },
+            ..Default::default()
+        });
+        let peer = PeerId::random();
+        for i in 0..4 {
+            queues.push(peer, Priority::Low, 300 + i, None).unwrap();
+            queues.push(peer, Priority::Normal, 200 + i, None).unwrap();
+            queues.push(peer, Priority::High, 100 + i, None).unwrap();
+        }
+        assert_eq!(queues.depth(&peer).unwrap().queued_by_priority, [4, 4, 4]);
+
+        let order: Vec<u32> = std::iter::from_fn(|| next(&mut queues, &peer)).collect();
+        assert_eq!(
+            order,
+            vec![100, 101, 200, 300, 102, 103, 201, 301, 202, 302, 203, 303]
+        );
+    }
+
+    #[test]
+    fn test_high_priority_messages_get_their_own_lane() {
+        let mut queues = OutboundQueues::new(OutboundQueueConfig {
+            max_in_flight: 1,
+            high_priority_protocol: true,
+            ..Default::default()
+        });
+        let peer = PeerId::random();
+        queues.push(peer, Priority::Low, 1, None).unwrap();
+        queues.push(peer, Priority::Low, 2, None).unwrap();
+        assert_eq!(queues.next(&peer), Some((1, Lane::Shared)));
+        assert_eq!(queues.next(&peer), None);
+
+        // The shared lane is full, yet urgent messages still go out.
+        queues.push(peer, Priority::High, 3, None).unwrap();
+        queues.push(peer, Priority::High, 4, None).unwrap();
+        assert_eq!(queues.next(&peer), Some((3, Lane::HighPriority)));
+        assert_eq!(queues.next(&peer), None);
+        assert_eq!(queues.depth(&peer).unwrap().in_flight, 2);
+    }
+}
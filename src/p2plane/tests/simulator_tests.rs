@@ -0,0 +1,145 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        handle::{NodeEvent, NodeHandle},
+        testing::{LinkConfig, ScriptedEvent, Simulator},
+        tests::TestMessage,
+    };
+    use libp2p::PeerId;
+    use std::time::Duration;
+    use tokio::{
+        sync::broadcast,
+        time::{timeout, Instant},
+    };
+
+    async fn next_connection(events: &mut broadcast::Receiver<NodeEvent<TestMessage>>) -> PeerId {
+        loop {
+            if let NodeEvent::PeerConnected(peer) = events.recv().await.unwrap() {
+                return peer;
+            }
+        }
+    }
+
+    async fn next_message(events: &mut broadcast::Receiver<NodeEvent<TestMessage>>) -> TestMessage {
+        loop {
+            if let NodeEvent::Message { message, .. } = events.recv().await.unwrap() {
+                return message;
+            }
+        }
+    }
+
+    /// Starts node 0 and node 1 bootstrapping from it, waits until they are
+    /// connected and returns the second node's handle, the first node's
+    /// events and the first node's peer id.
+    async fn connected_pair(
+        sim: &Simulator,
+    ) -> (
+        NodeHandle<TestMessage>,
+        broadcast::Receiver<NodeEvent<TestMessage>>,
+        PeerId,
+    ) {
+        let first = sim.spawn_node(sim.node_config(0, None)).await.unwrap();
+        let first_events = first.subscribe();
+        let second = sim.spawn_node(sim.node_config(1, Some(0))).await.unwrap();
+        let mut second_events = second.subscribe();
+        let first_id = timeout(Duration::from_secs(10), next_connection(&mut second_events))
+            .await
+            .unwrap();
+        (second, first_events, first_id)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_delays_delivery() {
+        let latency = Duration::from_millis(250);
+        let sim = Simulator::new(7).with_default_link(LinkConfig {
+            latency,
+            ..Default::default()
+        });
+        let (second, mut first_events, first_id) = connected_pair(&sim).await;
+
+        let sent_at = Instant::now();
+        second
+            .send_message(first_id, TestMessage("hello".to_string()))
+            .await
+            .unwrap();
+        let message = timeout(Duration::from_secs(10), next_message(&mut first_events))
+            .await
+            .unwrap();
+        assert_eq!(message.0, "hello");
+        assert!(sent_at.elapsed() >= latency);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partition_holds_messages_until_healed() {
+        let sim = Simulator::new(7);
+        let (second, mut first_events, first_id) = connected_pair(&sim).await;
+
+        let heal_after = Duration::from_secs(3);
+        let started = Instant::now();
+        sim.run_script(vec![
+            (Duration::ZERO, ScriptedEvent::Partition(vec![vec![0], vec![1]])),
+            (heal_after, ScriptedEvent::Heal),
+        ]);
+        tokio::task::yield_now().await;
+
+        second
+            .send_message(first_id, TestMessage("across".to_string()))
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_secs(2), next_message(&mut first_events))
+            .await
+            .is_err());
+
+        let message = timeout(Duration::from_secs(5), next_message(&mut first_events))
+            .await
+            .unwrap();
+        assert_eq!(message.0, "across");
+        assert!(started.elapsed() >= heal_after);
+    }
+
+    #[test]
+    fn test_same_seed_same_schedule() {
+        let link = LinkConfig {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(30),
+            loss: 0.3,
+            retransmit_delay: Duration::from_millis(100),
+            bandwidth: None,
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let delays = |seed| {
+                let sim = Simulator::new(seed).with_default_link(link.clone());
+                (0..32)
+                    .map(|i| sim.sample_delay(i % 2, 1 - i % 2, 100))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(delays(42), delays(42));
+            assert_ne!(delays(42), delays(43));
+        });
+    }
+
+    #[test]
+    fn test_bandwidth_serializes_writes() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let sim = Simulator::new(1).with_default_link(LinkConfig {
+                bandwidth: Some(1_000),
+                ..Default::default()
+            });
+            assert_eq!(sim.sample_delay(0, 1, 500), Duration::from_millis(500));
+            // The second write waits for the first to leave the link.
+            assert_eq!(sim.sample_delay(0, 1, 500), Duration::from_millis(1_000));
+            // The other direction is independent.
+            assert_eq!(sim.sample_delay(1, 0, 500), Duration::from_millis(500));
+        });
+    }
+}
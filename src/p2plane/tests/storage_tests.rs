@@ -0,0 +1,198 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        address_book::{AddressRecord, AddressSource},
+        peer_manager::PeerManager,
+        storage::{JsonFileStore, MemoryStore, PeerStorage, PeerStore, StoredPeer, SCHEMA_VERSION},
+        tests::TempDir,
+    };
+    use libp2p::{Multiaddr, PeerId};
+    use std::{
+        fs,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_save_creates_data_dir_and_leaves_no_temp_file() {
+        let dir = TempDir::new();
+        let data_dir = dir.0.join("nested");
+        let local = PeerId::random();
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        {
+            let mut manager = PeerManager::open(local, &data_dir, Duration::ZERO);
+            manager.add_address(peer, addr.clone(), AddressSource::Kademlia);
+        }
+
+        let file = format!("peers_{}.json", local.to_base58());
+        assert_eq!(entries(&data_dir), vec![file]);
+        let content = fs::read_to_string(PeerStorage::path(&data_dir, &local)).unwrap();
+        assert!(content.contains(&format!("\"version\": {}", SCHEMA_VERSION)));
+
+        let manager = PeerManager::open(local, &data_dir, Duration::ZERO);
+        assert_eq!(manager.dial_candidates(&peer), vec![addr]);
+    }
+
+    #[test]
+    fn test_version_one_files_are_migrated() {
+        let dir = TempDir::new();
+        fs::create_dir_all(&dir.0).unwrap();
+        let local = PeerId::random();
+        let peer = PeerId::random();
+        fs::write(
+            PeerStorage::path(&dir.0, &local),
+            format!(
+                r#"{{"peers": ["{peer}"], "addresses": {{"{peer}": "/ip4/10.0.0.2/tcp/4001"}}}}"#
+            ),
+        )
+        .unwrap();
+
+        let manager = PeerManager::open(local, &dir.0, Duration::ZERO);
+        assert!(manager.get_peers().contains(&peer));
+        let records = manager.address_book().records(&peer);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].addr, "/ip4/10.0.0.2/tcp/4001".parse().unwrap());
+        assert_eq!(records[0].source, AddressSource::Dialed);
+    }
+
+    #[test]
+    fn test_corrupt_and_future_files_are_backed_up() {
+        for content in ["{\"peers\": [", "{\"version\": 99, \"peers\": []}"] {
+            let dir = TempDir::new();
+            fs::create_dir_all(&dir.0).unwrap();
+            let local = PeerId::random();
+            let path = PeerStorage::path(&dir.0, &local);
+            fs::write(&path, content).unwrap();
+
+            let manager = PeerManager::open(local, &dir.0, Duration::ZERO);
+            assert!(manager.get_peers().is_empty());
+            assert!(!path.exists());
+            let names = entries(&dir.0);
+            assert_eq!(names.len(), 1);
+            assert!(names[0].contains(".json.corrupt-"), "{:?}", names);
+            assert_eq!(fs::read_to_string(dir.0.join(&names[0])).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_changes_are_batched_until_flush() {
+        let dir = TempDir::new();
+        let local = PeerId::random();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let addr: Multiaddr = "/ip4/10.0.0.3/tcp/4001".parse().unwrap();
+        let stored_peers = || {
+            PeerManager::open(local, &dir.0, Duration::ZERO)
+                .get_peers()
+                .len()
+        };
+
+        let mut manager = PeerManager::open(local, &dir.0, Duration::from_secs(3600));
+        manager.add_address(first, addr.clone(), AddressSource::Manual);
+        manager.add_address(second, addr.clone(), AddressSource::Manual);
+        manager.flush_if_due();
+        assert_eq!(stored_peers(), 1);
+
+        manager.flush().unwrap();
+        assert_eq!(stored_peers(), 2);
+    }
+
+    fn stored_peer(port: u16) -> StoredPeer {
+        let addr = format!("/ip4/10.0.1.1/tcp/{}", port).parse().unwrap();
+        StoredPeer {
+            addresses: vec![AddressRecord::new(
+                addr,
+                AddressSource::Kademlia,
+                SystemTime::now(),
+            )],
+            ..StoredPeer::new(PeerId::random())
+        }
+    }
+
+    /// Runs the same checks against every backend. `reopen` returns a store
+    /// reading what the previous one flushed.
+    fn check_store(mut store: Box<dyn PeerStore>, reopen: impl Fn() -> Box<dyn PeerStore>) {
+        let (a, b, c) = (stored_peer(1), stored_peer(2), stored_peer(3));
+        store.save(&[a.clone(), b.clone()]).unwrap();
+        let mut b_moved = stored_peer(4);
+        b_moved.peer_id = b.peer_id;
+        store.upsert(&b_moved).unwrap();
+        store.upsert(&c).unwrap();
+        store.delete(&a.peer_id).unwrap();
+        store.delete(&PeerId::random()).unwrap();
+        store.flush().unwrap();
+
+        let mut loaded = reopen().load().unwrap();
+        loaded.sort_by_key(|p| p.peer_id);
+        let mut expected = vec![b_moved, c];
+        expected.sort_by_key(|p| p.peer_id);
+        assert_eq!(loaded, expected);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::new();
+        let shared = store.clone();
+        check_store(Box::new(store), move || Box::new(shared.clone()));
+    }
+
+    #[test]
+    fn test_json_file_store() {
+        let dir = TempDir::new();
+        let path = dir.0.join("peers.json");
+        check_store(Box::new(JsonFileStore::open(&path)), || {
+            Box::new(JsonFileStore::open(&path))
+        });
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store() {
+        use crate::p2plane::storage::SqliteStore;
+
+        let dir = TempDir::new();
+        let path = dir.0.join("peers.sqlite");
+        check_store(Box::new(SqliteStore::open(&path).unwrap()), || {
+            Box::new(SqliteStore::open(&path).unwrap())
+        });
+    }
+
+    #[test]
+    fn test_manager_writes_only_changed_peers() {
+        let store = MemoryStore::new();
+        let local = PeerId::random();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let addr: Multiaddr = "/ip4/10.0.0.4/tcp/4001".parse().unwrap();
+        {
+            let mut manager =
+                PeerManager::with_store(local, Box::new(store.clone()), Duration::ZERO);
+            manager.add_address(first, addr.clone(), AddressSource::Manual);
+            manager.add_address(second, addr.clone(), AddressSource::Manual);
+        }
+        assert_eq!(store.len(), 2);
+
+        // Rewriting the store behind the manager's back shows which peers
+        // the next flush touches.
+        let marker = StoredPeer::new(first);
+        let mut backend = store.clone();
+        backend.upsert(&marker).unwrap();
+
+        let mut manager = PeerManager::with_store(local, Box::new(store.clone()), Duration::ZERO);
+        assert_eq!(manager.dial_candidates(&second), vec![addr.clone()]);
+        manager.record_dial_success(second, addr.clone());
+        manager.flush().unwrap();
+        assert_eq!(store.get(&first), Some(marker));
+        assert!(store.get(&second).unwrap().addresses[0]
+            .last_success
+            .is_some());
+    }
+}
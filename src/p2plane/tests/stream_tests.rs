@@ -0,0 +1,259 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        blob::{BlobConfig, BlobStore, Manifest, BLOB_PROTOCOL, CHUNK_SIZE},
+        handle::NodeEvent,
+        network::Node,
+        tests::{memory_config, wait_until_connected, TestMessage},
+        BlobId, P2PlaneError,
+    };
+    use libp2p::{
+        futures::{AsyncReadExt, AsyncWriteExt, StreamExt},
+        PeerId, StreamProtocol,
+    };
+    use std::{error::Error, time::Duration};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_manifest_verifies_chunks() {
+        let data = pattern(10);
+        let manifest = Manifest::new(&data, 4);
+        assert_eq!(manifest.chunks.len(), 3);
+        assert!(manifest.verify_chunk(0, &data[..4]));
+        assert!(manifest.verify_chunk(2, &data[8..]));
+        assert!(!manifest.verify_chunk(1, &data[..4]));
+        assert!(!manifest.verify_chunk(2, &data[6..]));
+        assert!(!manifest.verify_chunk(3, &data[8..]));
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+        let id = manifest.id();
+        assert_eq!(id.to_string().parse::<BlobId>().unwrap(), id);
+        assert!("not-a-blob-id".parse::<BlobId>().is_err());
+        assert_eq!(id.to_string().to_uppercase().parse::<BlobId>().unwrap(), id);
+        let signed = format!("+{}", &id.to_string()[1..]);
+        assert!(signed.parse::<BlobId>().is_err());
+    }
+
+    #[test]
+    fn test_blob_store_resumes_partial_blobs() {
+        let data = pattern(2 * CHUNK_SIZE + 10);
+        let manifest = Manifest::new(&data, CHUNK_SIZE);
+        let id = manifest.id();
+        let store = BlobStore::new();
+
+        assert_eq!(store.missing(&manifest).unwrap(), vec![0, 1, 2]);
+        assert!(!store
+            .add_chunk(&id, 1, data[CHUNK_SIZE..2 * CHUNK_SIZE].to_vec())
+            .unwrap());
+        assert!(store
+            .add_chunk(&id, 0, data[CHUNK_SIZE..2 * CHUNK_SIZE].to_vec())
+            .is_err());
+        assert_eq!(store.progress(&id), Some((1, 3)));
+        assert_eq!(store.missing(&manifest).unwrap(), vec![0, 2]);
+
+        assert!(!store
+            .add_chunk(&id, 0, data[..CHUNK_SIZE].to_vec())
+            .unwrap());
+        assert!(store
+            .add_chunk(&id, 2, data[2 * CHUNK_SIZE..].to_vec())
+            .unwrap());
+        assert_eq!(store.progress(&id), None);
+        assert!(store.missing(&manifest).unwrap().is_empty());
+        assert_eq!(store.get(&id), Some(data.clone()));
+        assert_eq!(store.insert(data), id);
+    }
+
+    #[test]
+    fn test_blob_store_limits_unfinished_blobs() {
+        let store = BlobStore::with_config(BlobConfig {
+            max_bytes: 3 * CHUNK_SIZE as u64,
+            partial_timeout: Duration::from_millis(50),
+            ..BlobConfig::default()
+        });
+        let first = Manifest::new(&pattern(2 * CHUNK_SIZE), CHUNK_SIZE);
+        let second = Manifest::new(&pattern(2 * CHUNK_SIZE + 1), CHUNK_SIZE);
+
+        // An unfinished blob reserves its full size.
+        assert_eq!(store.missing(&first).unwrap(), vec![0, 1]);
+        assert_eq!(store.used_bytes(), 2 * CHUNK_SIZE as u64);
+        assert!(matches!(
+            store.missing(&second),
+            Err(P2PlaneError::Stream(_))
+        ));
+        assert_eq!(store.progress(&second.id()), None);
+
+        // Once the first one times out, its room is given to the second.
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(store.missing(&second).unwrap(), vec![0, 1, 2]);
+        assert_eq!(store.progress(&first.id()), None);
+        assert_eq!(store.used_bytes(), 2 * CHUNK_SIZE as u64 + 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_round_trip() -> Result<(), Box<dyn Error>> {
+        let protocol = StreamProtocol::new("/test/echo/1.0.0");
+        let mut first = Node::<TestMessage>::new(memory_config(7540, None)).await?;
+        let first_id = first.local_peer_id();
+        let mut incoming = first.stream_control().accept(protocol.clone())?;
+        tokio::spawn(async move { first.start().await });
+        tokio::spawn(async move {
+            while let Some((_, mut stream)) = incoming.next().await {
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).await.unwrap();
+                stream.write_all(&data).await.unwrap();
+                stream.close().await.unwrap();
+            }
+        });
+        let mut second = Node::<TestMessage>::new(memory_config(7541, Some(7540))).await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+        wait_until_connected(&handle, first_id).await;
+
+        let data = pattern(1024 * 1024);
+        let mut stream = tokio::time::timeout(
+            Duration::from_secs(5),
+            handle.open_stream(first_id, protocol),
+        )
+        .await??;
+        stream.write_all(&data).await?;
+        stream.close().await?;
+        let mut echoed = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut echoed)).await??;
+        assert_eq!(echoed, data);
+
+        let unknown = handle
+            .open_stream(first_id, StreamProtocol::new("/test/unknown/1.0.0"))
+            .await;
+        assert!(matches!(unknown, Err(P2PlaneError::Stream(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dropped_receivers_are_not_announced() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7606, None)).await?;
+        let first_id = first.local_peer_id();
+        let kept = first
+            .stream_control()
+            .accept(StreamProtocol::new("/test/kept/1.0.0"))?;
+        drop(
+            first
+                .stream_control()
+                .accept(StreamProtocol::new("/test/dropped/1.0.0"))?,
+        );
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7607, Some(7606))).await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+
+        let metadata = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(metadata) = handle
+                    .peer_info(first_id)
+                    .await?
+                    .and_then(|info| info.metadata)
+                {
+                    return Ok::<_, P2PlaneError>(metadata);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await??;
+        assert!(metadata.supports("/test/kept/1.0.0"));
+        assert!(!metadata.supports("/test/dropped/1.0.0"));
+        drop(kept);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blobs_are_sent_and_fetched() -> Result<(), Box<dyn Error>> {
+        let mut first = Node::<TestMessage>::new(memory_config(7550, None)).await?;
+        let first_id = first.local_peer_id();
+        let first_blobs = first.blobs().clone();
+        let mut events = first.subscribe();
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7551, Some(7550))).await?;
+        let second_id = second.local_peer_id();
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+        wait_until_connected(&handle, first_id).await;
+
+        // Pushed blobs are refused until the receiver accepts them.
+        let sent = pattern(3 * CHUNK_SIZE + 17);
+        let refused = handle.send_blob(first_id, sent.clone()).await;
+        assert!(matches!(refused, Err(P2PlaneError::Stream(_))));
+        assert_eq!(first_blobs.used_bytes(), 0);
+        first_blobs.accept_puts(move |peer, _| *peer == second_id);
+
+        let id = tokio::time::timeout(
+            Duration::from_secs(10),
+            handle.send_blob(first_id, sent.clone()),
+        )
+        .await??;
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(NodeEvent::BlobReceived { peer, id }) = events.recv().await {
+                    return (peer, id);
+                }
+            }
+        })
+        .await?;
+        assert_eq!(received, (second_id, id));
+        assert_eq!(first_blobs.get(&id), Some(sent));
+
+        // A download that was interrupted after the first chunk resumes.
+        let served = pattern(2 * CHUNK_SIZE + 1);
+        let served_id = first_blobs.insert(served.clone());
+        let manifest = Manifest::new(&served, CHUNK_SIZE);
+        handle.blobs().missing(&manifest)?;
+        handle
+            .blobs()
+            .add_chunk(&served_id, 0, served[..CHUNK_SIZE].to_vec())?;
+        let fetched = tokio::time::timeout(
+            Duration::from_secs(10),
+            handle.fetch_blob(first_id, served_id),
+        )
+        .await??;
+        assert_eq!(fetched, Some(served));
+        assert_eq!(handle.blobs().progress(&served_id), None);
+
+        let unknown = Manifest::new(b"unknown", CHUNK_SIZE).id();
+        assert_eq!(handle.fetch_blob(first_id, unknown).await?, None);
+        assert!(handle.fetch_blob(PeerId::random(), unknown).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blob_transfers_are_limited() -> Result<(), Box<dyn Error>> {
+        let mut config = memory_config(7604, None);
+        config.blobs.max_transfers = 1;
+        let mut first = Node::<TestMessage>::new(config).await?;
+        let first_id = first.local_peer_id();
+        first.blobs().accept_puts(|_, _| true);
+        tokio::spawn(async move { first.start().await });
+        let mut second = Node::<TestMessage>::new(memory_config(7605, Some(7604))).await?;
+        let handle = second.handle();
+        tokio::spawn(async move { second.start().await });
+        wait_until_connected(&handle, first_id).await;
+
+        // A stream stuck in the middle of a message takes the only slot.
+        let mut stuck = handle
+            .open_stream(first_id, StreamProtocol::new(BLOB_PROTOCOL))
+            .await?;
+        stuck.write_all(&[0, 0]).await?;
+        stuck.flush().await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let data = pattern(100);
+        assert!(handle.send_blob(first_id, data.clone()).await.is_err());
+
+        drop(stuck);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = tokio::time::timeout(Duration::from_secs(10), handle.send_blob(first_id, data))
+            .await??;
+        assert!(handle.blobs().contains(&id));
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+#[cfg(test)]
+mod tests {
+    use crate::p2plane::{
+        builder::NodeBuilder,
+        handle::NodeHandle,
+        network::{NodeConfig, PeerStorageKind},
+        swarm_key::{self, PreSharedKey},
+        tests::TestMessage,
+    };
+    use libp2p::{Multiaddr, PeerId};
+    use std::{error::Error, time::Duration};
+
+    fn config(listen_addr: &str) -> NodeConfig {
+        NodeConfig {
+            listen_addr: listen_addr.to_string(),
+            peer_storage: PeerStorageKind::Memory,
+            ..Default::default()
+        }
+    }
+
+    async fn start(
+        config: NodeConfig,
+        key: Option<PreSharedKey>,
+    ) -> Result<NodeHandle<TestMessage>, Box<dyn Error>> {
+        let mut builder = NodeBuilder::<TestMessage>::new(config);
+        if let Some(key) = key {
+            builder = builder.pre_shared_key(key);
+        }
+        let mut node = builder.build().await?;
+        let handle = node.handle();
+        tokio::spawn(async move { node.start().await });
+        Ok(handle)
+    }
+
+    async fn listen_addr(handle: &NodeHandle<TestMessage>) -> Multiaddr {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(addr) = handle.listen_addrs().await.unwrap().pop() {
+                    return addr;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("node did not start listening")
+    }
+
+    /// Dials `addr` from `dialer` and reports whether `peer` got connected.
+    async fn connects(dialer: &NodeHandle<TestMessage>, addr: Multiaddr, peer: PeerId) -> bool {
+        dialer.dial(addr).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !dialer.connected_peers().await.unwrap().contains(&peer) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[test]
+    fn test_swarm_key_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("p2plane-{}.key", PeerId::random()));
+        let key = swarm_key::generate();
+        swarm_key::save(&path, &key).unwrap();
+        assert_eq!(swarm_key::load(&path).unwrap(), key);
+
+        std::fs::write(&path, "/key/swarm/psk/1.0.0/\n/base16/\nnot-hex\n").unwrap();
+        assert!(swarm_key::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let mut config = NodeConfig {
+            swarm_key_file: Some(path),
+            ..Default::default()
+        };
+        config.transports.quic = true;
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("transports.quic"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_nodes_with_the_same_key_connect_over_tcp() -> Result<(), Box<dyn Error>> {
+        let key = swarm_key::generate();
+        let listener = start(config("/ip4/127.0.0.1/tcp/0"), Some(key)).await?;
+        let dialer = start(config("/ip4/127.0.0.1/tcp/0"), Some(key)).await?;
+        let addr = listen_addr(&listener).await;
+        assert!(connects(&dialer, addr, listener.local_peer_id()).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nodes_with_other_or_no_keys_cannot_connect() -> Result<(), Box<dyn Error>> {
+        let listener = start(config("/ip4/127.0.0.1/tcp/0"), Some(swarm_key::generate())).await?;
+        let addr = listen_addr(&listener).await;
+
+        let other_network =
+            start(config("/ip4/127.0.0.1/tcp/0"), Some(swarm_key::generate())).await?;
+        assert!(!connects(&other_network, addr.clone(), listener.local_peer_id()).await);
+        let public = start(config("/ip4/127.0.0.1/tcp/0"), None).await?;
+        assert!(!connects(&public, addr, listener.local_peer_id()).await);
+        assert!(listener.connected_peers().await?.is_empty());
+
+        // The same holds for in-process connections.
+        let listener = start(config("/memory/7592"), Some(swarm_key::generate())).await?;
+        let other_network = start(config("/memory/7593"), Some(swarm_key::generate())).await?;
+        let addr: Multiaddr = "/memory/7592".parse()?;
+        assert!(!connects(&other_network, addr, listener.local_peer_id()).await);
+        Ok(())
+    }
+}
@@ -1,9 +1,21 @@
-use libp2p::{Multiaddr, PeerId};
+use crate::p2plane::{address_book::AddressSource, queue::Priority, PeerEvent, PeerInfo, PeerMetadata};
+use libp2p::{core::ConnectedPoint, Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
+use tokio::sync::broadcast;
+
+pub use async_trait::async_trait;
 
 /// Trait for managing peer connections and addresses. Applications can implement this trait
-/// to customize how peers are stored and managed.
+/// to customize how peers are stored and managed, and hand their manager to
+/// [`NodeBuilder::peer_manager`](crate::p2plane::NodeBuilder::peer_manager).
+/// Managers that have to wait for a database or remote service implement
+/// [`AsyncPeerManagement`] instead.
+///
+/// The node reports connections and everything it learns about peers through the `on_*`
+/// hooks and asks the manager which peers to broadcast to and which addresses to dial.
+/// Only the first four methods are required; the others default to what can be derived
+/// from them.
 /// 
 /// # Example
 /// 
@@ -36,11 +48,290 @@ use std::fmt::Debug;
 ///         self.peers.insert(peer_id.clone());
 ///         self.addresses.insert(peer_id, addr);
 ///     }
+///
+///     fn remove_peer(&mut self, peer_id: &PeerId) -> bool {
+///         self.addresses.remove(peer_id);
+///         self.peers.remove(peer_id)
+///     }
+///
+///     fn get_address(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+///         self.addresses.get(peer_id).cloned()
+///     }
 /// }
 /// ```
-pub trait PeerManagement: Debug {
+pub trait PeerManagement: Debug + Send {
+    /// Peers that broadcast messages are sent to.
     fn get_peers(&self) -> Vec<PeerId>;
+
     fn add_peer_with_addr(&mut self, peer_id: PeerId, addr: Multiaddr);
+
+    /// Forgets `peer_id`. Returns true if it was known.
+    fn remove_peer(&mut self, peer_id: &PeerId) -> bool;
+
+    /// The address `peer_id` should be dialed on.
+    fn get_address(&self, peer_id: &PeerId) -> Option<Multiaddr>;
+
+    /// Records an address of `peer_id` learned from the network.
+    fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr, _source: AddressSource) {
+        self.add_peer_with_addr(peer_id, addr);
+    }
+
+    /// Addresses of `peer_id` in the order they should be dialed.
+    fn dial_candidates(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.get_address(peer_id).into_iter().collect()
+    }
+
+    /// Called when the first connection to `peer_id` is established, by
+    /// either side. For a [`ConnectedPoint::Listener`] the remote address is
+    /// usually an ephemeral port, so it should not be kept for redialing;
+    /// the peer's listen addresses follow in [`on_identify`](Self::on_identify).
+    fn on_connected(&mut self, _peer_id: PeerId, _endpoint: &ConnectedPoint) {}
+
+    /// Called when the last connection to `peer_id` is closed.
+    fn on_disconnected(&mut self, _peer_id: PeerId) {}
+
+    /// Called for every connection established by dialing `peer_id` on
+    /// `addr`, including further connections to a peer that is already
+    /// connected.
+    fn on_dial_success(&mut self, _peer_id: PeerId, _addr: &Multiaddr) {}
+
+    /// Called when dialing `peer_id` on `addr` failed.
+    fn on_dial_failure(&mut self, _peer_id: &PeerId, _addr: &Multiaddr) {}
+
+    /// Called with what `peer_id` reported over Identify. Its listen
+    /// addresses are added by default.
+    fn on_identify(&mut self, peer_id: PeerId, metadata: PeerMetadata) {
+        for addr in metadata.listen_addrs {
+            self.add_address(peer_id, addr, AddressSource::Identify);
+        }
+    }
+
+    /// Called with the round-trip time of a successful ping.
+    fn on_ping(&mut self, _peer_id: PeerId, _rtt: Duration) {}
+
+    /// Called when a ping failed. Returns the number of consecutive misses;
+    /// the node disconnects peers that reach `NodeConfig::max_missed_pings`.
+    /// Managers that do not count misses never have peers disconnected.
+    fn on_ping_failure(&mut self, _peer_id: PeerId) -> u32 {
+        0
+    }
+
+    /// Called periodically by the node, e.g. to persist batched changes.
+    fn on_tick(&mut self) {}
+
+    /// What is known about `peer_id`, or `None` for an unknown peer.
+    fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        let addresses = self.dial_candidates(peer_id);
+        if addresses.is_empty() && !self.get_peers().contains(peer_id) {
+            return None;
+        }
+        Some(PeerInfo {
+            peer_id: *peer_id,
+            addresses,
+            rtt: None,
+            missed_pings: 0,
+            metadata: None,
+        })
+    }
+
+    /// Known peers that announced support for `protocol` over Identify.
+    fn peers_supporting(&self, _protocol: &str) -> Vec<PeerId> {
+        Vec::new()
+    }
+
+    /// Channel the manager sends `Added`, `AddressChanged` and `Removed`
+    /// [`PeerEvent`]s on. The node sends its own events on the same channel.
+    fn peer_events(&self) -> Option<broadcast::Sender<PeerEvent>> {
+        None
+    }
+}
+
+/// Async variant of [`PeerManagement`], for managers backed by a database
+/// or remote registry that must not block the node's event loop. Every
+/// `PeerManagement` implementation that is `Sync` implements it as well.
+///
+/// The `on_*` hooks, `add_address` and `on_tick` run in order on a task of
+/// their own, so a slow manager delays what it knows about peers but not the
+/// swarm.
+///
+/// # Example
+///
+/// ```rust
+/// use libp2p::{Multiaddr, PeerId};
+/// use narwhal::p2plane::traits::{async_trait, AsyncPeerManagement};
+///
+/// /// Looks peers up in a service shared by all nodes.
+/// #[derive(Debug)]
+/// struct Registry {
+///     url: String,
+/// }
+///
+/// #[async_trait]
+/// impl AsyncPeerManagement for Registry {
+///     async fn get_peers(&self) -> Vec<PeerId> {
+///         // e.g. GET {url}/peers
+///         Vec::new()
+///     }
+///
+///     async fn add_peer_with_addr(&mut self, _peer_id: PeerId, _addr: Multiaddr) {}
+///
+///     async fn remove_peer(&mut self, _peer_id: &PeerId) -> bool {
+///         false
+///     }
+///
+///     async fn get_address(&self, _peer_id: &PeerId) -> Option<Multiaddr> {
+///         None
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait AsyncPeerManagement: Debug + Send + Sync {
+    /// See [`PeerManagement::get_peers`].
+    async fn get_peers(&self) -> Vec<PeerId>;
+
+    async fn add_peer_with_addr(&mut self, peer_id: PeerId, addr: Multiaddr);
+
+    /// See [`PeerManagement::remove_peer`].
+    async fn remove_peer(&mut self, peer_id: &PeerId) -> bool;
+
+    /// See [`PeerManagement::get_address`].
+    async fn get_address(&self, peer_id: &PeerId) -> Option<Multiaddr>;
+
+    /// See [`PeerManagement::add_address`].
+    async fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr, _source: AddressSource) {
+        self.add_peer_with_addr(peer_id, addr).await
+    }
+
+    /// See [`PeerManagement::dial_candidates`].
+    async fn dial_candidates(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.get_address(peer_id).await.into_iter().collect()
+    }
+
+    /// See [`PeerManagement::on_connected`].
+    async fn on_connected(&mut self, _peer_id: PeerId, _endpoint: &ConnectedPoint) {}
+
+    /// See [`PeerManagement::on_disconnected`].
+    async fn on_disconnected(&mut self, _peer_id: PeerId) {}
+
+    /// See [`PeerManagement::on_dial_success`].
+    async fn on_dial_success(&mut self, _peer_id: PeerId, _addr: &Multiaddr) {}
+
+    /// See [`PeerManagement::on_dial_failure`].
+    async fn on_dial_failure(&mut self, _peer_id: &PeerId, _addr: &Multiaddr) {}
+
+    /// See [`PeerManagement::on_identify`].
+    async fn on_identify(&mut self, peer_id: PeerId, metadata: PeerMetadata) {
+        for addr in metadata.listen_addrs {
+            self.add_address(peer_id, addr, AddressSource::Identify).await;
+        }
+    }
+
+    /// See [`PeerManagement::on_ping`].
+    async fn on_ping(&mut self, _peer_id: PeerId, _rtt: Duration) {}
+
+    /// See [`PeerManagement::on_ping_failure`].
+    async fn on_ping_failure(&mut self, _peer_id: PeerId) -> u32 {
+        0
+    }
+
+    /// See [`PeerManagement::on_tick`].
+    async fn on_tick(&mut self) {}
+
+    /// See [`PeerManagement::peer_info`].
+    async fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        let addresses = self.dial_candidates(peer_id).await;
+        if addresses.is_empty() && !self.get_peers().await.contains(peer_id) {
+            return None;
+        }
+        Some(PeerInfo {
+            peer_id: *peer_id,
+            addresses,
+            rtt: None,
+            missed_pings: 0,
+            metadata: None,
+        })
+    }
+
+    /// See [`PeerManagement::peers_supporting`].
+    async fn peers_supporting(&self, _protocol: &str) -> Vec<PeerId> {
+        Vec::new()
+    }
+
+    /// See [`PeerManagement::peer_events`].
+    fn peer_events(&self) -> Option<broadcast::Sender<PeerEvent>> {
+        None
+    }
+}
+
+#[async_trait]
+impl<T: PeerManagement + Sync> AsyncPeerManagement for T {
+    async fn get_peers(&self) -> Vec<PeerId> {
+        PeerManagement::get_peers(self)
+    }
+
+    async fn add_peer_with_addr(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        PeerManagement::add_peer_with_addr(self, peer_id, addr)
+    }
+
+    async fn remove_peer(&mut self, peer_id: &PeerId) -> bool {
+        PeerManagement::remove_peer(self, peer_id)
+    }
+
+    async fn get_address(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+        PeerManagement::get_address(self, peer_id)
+    }
+
+    async fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr, source: AddressSource) {
+        PeerManagement::add_address(self, peer_id, addr, source)
+    }
+
+    async fn dial_candidates(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        PeerManagement::dial_candidates(self, peer_id)
+    }
+
+    async fn on_connected(&mut self, peer_id: PeerId, endpoint: &ConnectedPoint) {
+        PeerManagement::on_connected(self, peer_id, endpoint)
+    }
+
+    async fn on_disconnected(&mut self, peer_id: PeerId) {
+        PeerManagement::on_disconnected(self, peer_id)
+    }
+
+    async fn on_dial_success(&mut self, peer_id: PeerId, addr: &Multiaddr) {
+        PeerManagement::on_dial_success(self, peer_id, addr)
+    }
+
+    async fn on_dial_failure(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        PeerManagement::on_dial_failure(self, peer_id, addr)
+    }
+
+    async fn on_identify(&mut self, peer_id: PeerId, metadata: PeerMetadata) {
+        PeerManagement::on_identify(self, peer_id, metadata)
+    }
+
+    async fn on_ping(&mut self, peer_id: PeerId, rtt: Duration) {
+        PeerManagement::on_ping(self, peer_id, rtt)
+    }
+
+    async fn on_ping_failure(&mut self, peer_id: PeerId) -> u32 {
+        PeerManagement::on_ping_failure(self, peer_id)
+    }
+
+    async fn on_tick(&mut self) {
+        PeerManagement::on_tick(self)
+    }
+
+    async fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        PeerManagement::peer_info(self, peer_id)
+    }
+
+    async fn peers_supporting(&self, protocol: &str) -> Vec<PeerId> {
+        PeerManagement::peers_supporting(self, protocol)
+    }
+
+    fn peer_events(&self) -> Option<broadcast::Sender<PeerEvent>> {
+        PeerManagement::peer_events(self)
+    }
 }
 
 /// Trait for application-specific messages that can be sent over the network.
@@ -73,4 +364,11 @@ pub trait Message:
     'static 
 {
     fn protocol_id(&self) -> &'static str;
+
+    /// Class the message is queued and scheduled under when sent through
+    /// [`Node`](crate::p2plane::network::Node), see
+    /// [`OutboundQueueConfig`](crate::p2plane::queue::OutboundQueueConfig).
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
 }
\ No newline at end of file
@@ -0,0 +1,251 @@
+#![cfg(feature = "cli")]
+
+use libp2p::PeerId;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// Directory under the system temp dir, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("p2plane-cli-test-{}", rand::random::<u64>()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn p2plane(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_p2plane"));
+    command.args(args).env_remove("P2PLANE_KEY_PASSPHRASE");
+    command
+}
+
+/// Runs the tool and returns its standard output, failing the test if it
+/// did not succeed.
+fn run(args: &[&str]) -> String {
+    check(p2plane(args).output().unwrap(), args)
+}
+
+fn check(output: Output, args: &[&str]) -> String {
+    assert!(
+        output.status.success(),
+        "p2plane {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs the tool expecting it to fail and returns its standard error.
+fn run_failing(args: &[&str]) -> String {
+    let output = p2plane(args).output().unwrap();
+    assert!(
+        !output.status.success(),
+        "p2plane {} succeeded",
+        args.join(" ")
+    );
+    String::from_utf8(output.stderr).unwrap()
+}
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn test_key_generate_and_inspect() {
+    let dir = TempDir::new();
+    let key = dir.join("node.key");
+
+    let peer_id: PeerId = run(&["key", "generate", "--out", path(&key)])
+        .trim()
+        .parse()
+        .unwrap();
+    let inspected = run(&["key", "inspect", path(&key)]);
+    assert!(inspected.contains(&format!("peer id:   {}", peer_id)));
+    assert!(inspected.contains("encrypted: no"));
+
+    let error = run_failing(&["key", "generate", "--out", path(&key)]);
+    assert!(error.contains("already exists"));
+    let replaced: PeerId = run(&[
+        "key",
+        "generate",
+        "--type",
+        "secp256k1",
+        "--force",
+        "--out",
+        path(&key),
+    ])
+    .trim()
+    .parse()
+    .unwrap();
+    assert_ne!(replaced, peer_id);
+    assert!(run(&["key", "inspect", path(&key)]).contains(&format!("peer id:   {}", replaced)));
+
+    // Encrypting needs a passphrase; the keystore is inspected without it.
+    let encrypted = dir.join("encrypted.key");
+    let args = ["key", "generate", "--encrypt", "--out", path(&encrypted)];
+    assert!(run_failing(&args).contains("P2PLANE_KEY_PASSPHRASE"));
+    let output = p2plane(&args)
+        .env("P2PLANE_KEY_PASSPHRASE", "secret")
+        .output()
+        .unwrap();
+    let peer_id: PeerId = check(output, &args).trim().parse().unwrap();
+    let inspected = run(&["key", "inspect", path(&encrypted)]);
+    assert!(inspected.contains("type:      ed25519"));
+    assert!(inspected.contains(&format!("peer id:   {}", peer_id)));
+    assert!(inspected.contains("encrypted: yes"));
+
+    assert!(
+        run_failing(&["key", "inspect", path(&dir.join("missing.key"))]).starts_with("error: ")
+    );
+}
+
+#[test]
+fn test_peers_add_list_and_remove() {
+    let dir = TempDir::new();
+    let store = dir.join("peers.json");
+    let store = path(&store);
+    let first = PeerId::random().to_string();
+    let second = PeerId::random().to_string();
+
+    assert_eq!(run(&["peers", "list", "--store", store]), "");
+    run(&[
+        "peers",
+        "add",
+        "--store",
+        store,
+        &first,
+        "/ip4/10.0.0.1/tcp/8000",
+    ]);
+    run(&["peers", "add", "--store", store, &second]);
+    // Adding a known peer adds only the addresses it does not have yet.
+    run(&[
+        "peers",
+        "add",
+        "--store",
+        store,
+        &first,
+        "/ip4/10.0.0.1/tcp/8000",
+        "/ip4/10.0.0.2/tcp/8000",
+    ]);
+
+    let mut lines: Vec<String> = run(&["peers", "list", "--store", store])
+        .lines()
+        .map(String::from)
+        .collect();
+    lines.sort();
+    let mut expected = vec![
+        format!("{} /ip4/10.0.0.1/tcp/8000 /ip4/10.0.0.2/tcp/8000", first),
+        format!("{} ", second),
+    ];
+    expected.sort();
+    assert_eq!(lines, expected);
+    let json: serde_json::Value =
+        serde_json::from_str(&run(&["peers", "list", "--store", store, "--json"])).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 2);
+
+    run(&["peers", "remove", "--store", store, &second]);
+    assert_eq!(
+        run(&["peers", "list", "--store", store]),
+        format!("{} /ip4/10.0.0.1/tcp/8000 /ip4/10.0.0.2/tcp/8000\n", first)
+    );
+    let error = run_failing(&["peers", "remove", "--store", store, &second]);
+    assert!(error.contains(&format!("unknown peer {}", second)));
+    assert!(
+        run_failing(&["peers", "add", "--store", store, "not-a-peer-id"]).contains("not-a-peer-id")
+    );
+}
+
+#[test]
+fn test_peers_export_and_import() {
+    let dir = TempDir::new();
+    let source = dir.join("source.json");
+    let source = path(&source);
+    for addr in ["/ip4/10.0.0.1/tcp/8000", "/ip4/10.0.0.2/tcp/8000"] {
+        run(&[
+            "peers",
+            "add",
+            "--store",
+            source,
+            &PeerId::random().to_string(),
+            addr,
+        ]);
+    }
+    let exported = run(&["peers", "export", "--store", source]);
+    let export = dir.join("export.json");
+    run(&["peers", "export", "--store", source, "--out", path(&export)]);
+    assert_eq!(fs::read_to_string(&export).unwrap().trim(), exported.trim());
+
+    let imported = dir.join("imported.json");
+    let output = p2plane(&["peers", "import", "--store", path(&imported), path(&export)])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("imported 2 peers"));
+    check(output, &["peers", "import"]);
+    assert_eq!(
+        run(&["peers", "export", "--store", path(&imported)]),
+        exported
+    );
+
+    // `-` imports from standard input.
+    let piped = dir.join("piped.json");
+    let args = ["peers", "import", "--store", path(&piped), "-"];
+    let mut child = p2plane(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(exported.as_bytes())
+        .unwrap();
+    check(child.wait_with_output().unwrap(), &args);
+    assert_eq!(run(&["peers", "export", "--store", path(&piped)]), exported);
+
+    fs::write(&export, "not json").unwrap();
+    assert!(
+        run_failing(&["peers", "import", "--store", path(&piped), path(&export)])
+            .starts_with("error: ")
+    );
+}
+
+#[test]
+fn test_config_validate_ignores_reserved_variables() {
+    let dir = TempDir::new();
+    let config = dir.join("node.toml");
+    fs::write(&config, "max_missed_pings = 5\n").unwrap();
+    let args = ["config", "validate", path(&config)];
+
+    // Variables read by the tool itself are not config overrides.
+    let output = p2plane(&args)
+        .env("P2PLANE_ADMIN_TOKEN", "secret")
+        .env("P2PLANE_KEY_PASSPHRASE", "secret")
+        .output()
+        .unwrap();
+    assert_eq!(check(output, &args), format!("{}: ok\n", path(&config)));
+
+    let output = p2plane(&args)
+        .env("P2PLANE_MAX_MISSED_PINGS", "0")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("max_missed_pings"));
+}
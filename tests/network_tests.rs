@@ -1,12 +1,17 @@
+use libp2p::{futures::StreamExt, kad::RecordKey, multiaddr::Protocol, Multiaddr, PeerId};
 use narwhal::p2plane::{
-    network::{Node, NodeConfig},
+    network::{Node, NodeConfig, PeerStorageKind, RelayMode},
     traits::Message,
+    NodeEvent, NodeHandle,
 };
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
-use libp2p::Multiaddr;
+use std::future::Future;
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::{
+    sync::broadcast,
+    time::{sleep, timeout},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TestMessage(String);
@@ -17,12 +22,47 @@ impl Message for TestMessage {
     }
 }
 
+/// Polls `condition` until it holds, failing after ten seconds.
+async fn wait_until<F, Fut>(mut condition: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    timeout(Duration::from_secs(10), async {
+        while !condition().await {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// Waits until the node behind `handle` listens on some address.
+async fn wait_until_listening(handle: &NodeHandle<TestMessage>) -> Result<(), Box<dyn Error>> {
+    wait_until(|| async { !handle.listen_addrs().await.unwrap_or_default().is_empty() }).await
+}
+
+async fn wait_until_connected(
+    handle: &NodeHandle<TestMessage>,
+    peer: PeerId,
+) -> Result<(), Box<dyn Error>> {
+    wait_until(|| async {
+        handle
+            .connected_peers()
+            .await
+            .is_ok_and(|peers| peers.contains(&peer))
+    })
+    .await
+}
+
 #[tokio::test]
 async fn test_node_integration() -> Result<(), Box<dyn Error>> {
-    let addr: Multiaddr = "/ip4/127.0.0.1/tcp/0".parse()?;
+    let addr: Multiaddr = "/memory/0".parse()?;
     let config = NodeConfig {
         listen_addr: addr.to_string(),
         bootstrap_addr: None,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
     };
 
     let _node = Node::<TestMessage>::new(config).await?;
@@ -32,57 +72,278 @@ async fn test_node_integration() -> Result<(), Box<dyn Error>> {
 #[tokio::test]
 async fn test_node_with_bootstrap() -> Result<(), Box<dyn Error>> {
     // Start bootstrap node
-    let bootstrap_addr: Multiaddr = "/ip4/127.0.0.1/tcp/8000".parse()?;
+    let bootstrap_addr: Multiaddr = "/memory/8000".parse()?;
     let bootstrap_config = NodeConfig {
         listen_addr: bootstrap_addr.to_string(),
         bootstrap_addr: None,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
     };
-    let _bootstrap_node = Node::<TestMessage>::new(bootstrap_config).await?;
-
-    // Allow bootstrap node to start
-    sleep(Duration::from_secs(1)).await;
+    let mut bootstrap_node = Node::<TestMessage>::new(bootstrap_config).await?;
+    let bootstrap_id = bootstrap_node.local_peer_id();
+    let bootstrap_handle = bootstrap_node.handle();
+    tokio::spawn(async move { bootstrap_node.start().await });
+    wait_until_listening(&bootstrap_handle).await?;
 
     // Start peer node
-    let peer_addr: Multiaddr = "/ip4/127.0.0.1/tcp/8001".parse()?;
+    let peer_addr: Multiaddr = "/memory/8001".parse()?;
     let peer_config = NodeConfig {
         listen_addr: peer_addr.to_string(),
         bootstrap_addr: Some(bootstrap_addr),
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
     };
-    let _peer_node = Node::<TestMessage>::new(peer_config).await?;
+    let mut peer_node = Node::<TestMessage>::new(peer_config).await?;
+    let peer_id = peer_node.local_peer_id();
+    let peer_handle = peer_node.handle();
+    tokio::spawn(async move { peer_node.start().await });
 
+    wait_until_connected(&peer_handle, bootstrap_id).await?;
+    wait_until_connected(&bootstrap_handle, peer_id).await?;
     Ok(())
 }
 
 #[tokio::test]
 async fn test_multiple_nodes() -> Result<(), Box<dyn Error>> {
-    let mut nodes = Vec::new();
     let base_port = 9000;
 
     // Create bootstrap node
-    let bootstrap_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", base_port).parse()?;
+    let bootstrap_addr: Multiaddr = format!("/memory/{}", base_port).parse()?;
     let bootstrap_config = NodeConfig {
         listen_addr: bootstrap_addr.to_string(),
         bootstrap_addr: None,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
     };
-    let bootstrap_node = Node::<TestMessage>::new(bootstrap_config).await?;
-    nodes.push(bootstrap_node);
-
-    // Allow bootstrap node to start
-    sleep(Duration::from_secs(1)).await;
+    let mut bootstrap_node = Node::<TestMessage>::new(bootstrap_config).await?;
+    let bootstrap_handle = bootstrap_node.handle();
+    tokio::spawn(async move { bootstrap_node.start().await });
+    wait_until_listening(&bootstrap_handle).await?;
 
     // Create additional nodes
     for i in 1..3 {
-        let peer_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", base_port + i).parse()?;
+        let peer_addr: Multiaddr = format!("/memory/{}", base_port + i).parse()?;
         let peer_config = NodeConfig {
             listen_addr: peer_addr.to_string(),
             bootstrap_addr: Some(bootstrap_addr.clone()),
+            peer_storage: PeerStorageKind::Memory,
+            ..Default::default()
         };
-        let peer_node = Node::<TestMessage>::new(peer_config).await?;
-        nodes.push(peer_node);
-        
-        // Allow each node to connect
-        sleep(Duration::from_millis(500)).await;
+        let mut peer_node = Node::<TestMessage>::new(peer_config).await?;
+        let peer_id = peer_node.local_peer_id();
+        tokio::spawn(async move { peer_node.start().await });
+
+        // Each node joins through the bootstrap node.
+        wait_until_connected(&bootstrap_handle, peer_id).await?;
     }
+    assert_eq!(bootstrap_handle.connected_peers().await?.len(), 2);
 
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_provide_and_fetch_content() -> Result<(), Box<dyn Error>> {
+    let provider_addr: Multiaddr = "/memory/9100".parse()?;
+    let mut provider = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: provider_addr.to_string(),
+        bootstrap_addr: None,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let provider_id = provider.local_peer_id();
+    let provider_handle = provider.handle();
+    tokio::spawn(async move { provider.start().await });
+    wait_until_listening(&provider_handle).await?;
+
+    let key = RecordKey::new(&"batch-digest");
+    provider_handle
+        .provide_content(key.clone(), b"batch contents".to_vec())
+        .await?;
+
+    let mut fetcher = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: "/memory/9101".to_string(),
+        bootstrap_addr: Some(provider_addr),
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let fetcher_handle = fetcher.handle();
+    tokio::spawn(async move { fetcher.start().await });
+
+    // The fetcher has to know the provider before querying the DHT.
+    wait_until_connected(&fetcher_handle, provider_id).await?;
+
+    let providers: Vec<_> = fetcher_handle.get_providers(key.clone())?.collect().await;
+    assert_eq!(providers.len(), 1);
+
+    let content = fetcher_handle.fetch(key).await?;
+    assert_eq!(content.as_deref(), Some(&b"batch contents"[..]));
+
+    let missing = fetcher_handle
+        .fetch(RecordKey::new(&"unknown-digest"))
+        .await?;
+    assert!(missing.is_none());
+
+    Ok(())
+}
+
+async fn next_message(
+    events: &mut broadcast::Receiver<NodeEvent<TestMessage>>,
+) -> Result<(PeerId, TestMessage), Box<dyn Error>> {
+    loop {
+        match timeout(Duration::from_secs(10), events.recv()).await?? {
+            NodeEvent::Message { peer, message } => return Ok((peer, message)),
+            _ => continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_messages_through_relay() -> Result<(), Box<dyn Error>> {
+    // Relay node with a direct listener
+    let relay_addr: Multiaddr = "/memory/9200".parse()?;
+    let mut relay = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: relay_addr.to_string(),
+        relay_mode: RelayMode::Server,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let relay_id = relay.local_peer_id();
+    let relay_handle = relay.handle();
+    tokio::spawn(async move { relay.start().await });
+    wait_until_listening(&relay_handle).await?;
+
+    // Node that is only reachable through the relay
+    let circuit_addr = relay_addr
+        .with(Protocol::P2p(relay_id))
+        .with(Protocol::P2pCircuit);
+    let mut listener = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: circuit_addr.to_string(),
+        relay_mode: RelayMode::Client,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let listener_id = listener.local_peer_id();
+    let listener_handle = listener.handle();
+    let mut listener_events = listener_handle.subscribe();
+    tokio::spawn(async move { listener.start().await });
+    // The relayed address is only listened on once the relay accepted the
+    // reservation.
+    wait_until_listening(&listener_handle).await?;
+
+    // Node that dials the listener through the relay
+    let mut dialer = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: "/memory/9201".to_string(),
+        bootstrap_addr: Some(circuit_addr.with(Protocol::P2p(listener_id))),
+        relay_mode: RelayMode::Client,
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let dialer_id = dialer.local_peer_id();
+    let dialer_handle = dialer.handle();
+    let mut dialer_events = dialer_handle.subscribe();
+    tokio::spawn(async move { dialer.start().await });
+    wait_until_connected(&dialer_handle, listener_id).await?;
+
+    dialer_handle
+        .send_message(listener_id, TestMessage("ping".to_string()))
+        .await?;
+    let (from, message) = next_message(&mut listener_events).await?;
+    assert_eq!(from, dialer_id);
+    assert_eq!(message.0, "ping");
+
+    listener_handle
+        .send_message(dialer_id, TestMessage("pong".to_string()))
+        .await?;
+    let (from, message) = next_message(&mut dialer_events).await?;
+    assert_eq!(from, listener_id);
+    assert_eq!(message.0, "pong");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ping_measures_rtt() -> Result<(), Box<dyn Error>> {
+    let first_addr: Multiaddr = "/memory/9300".parse()?;
+    let mut first = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: first_addr.to_string(),
+        ping_interval: Duration::from_millis(100),
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let first_id = first.local_peer_id();
+    let first_handle = first.handle();
+    tokio::spawn(async move { first.start().await });
+    wait_until_listening(&first_handle).await?;
+
+    let mut second = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: "/memory/9301".to_string(),
+        bootstrap_addr: Some(first_addr),
+        ping_interval: Duration::from_millis(100),
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let handle = second.handle();
+    tokio::spawn(async move { second.start().await });
+    wait_until(|| async {
+        handle
+            .peer_info(first_id)
+            .await
+            .is_ok_and(|info| info.is_some_and(|info| info.rtt.is_some()))
+    })
+    .await?;
+
+    let info = handle
+        .peer_info(first_id)
+        .await?
+        .expect("bootstrap peer should be known");
+    assert!(info.rtt.is_some());
+    assert_eq!(info.missed_pings, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_many_memory_nodes() -> Result<(), Box<dyn Error>> {
+    // Dozens of nodes in one process: no sockets are bound and no peer
+    // storage files are written.
+    let hub_addr: Multiaddr = "/memory/9400".parse()?;
+    let mut hub = Node::<TestMessage>::new(NodeConfig {
+        listen_addr: hub_addr.to_string(),
+        peer_storage: PeerStorageKind::Memory,
+        ..Default::default()
+    })
+    .await?;
+    let hub_id = hub.local_peer_id();
+    let hub_handle = hub.handle();
+    tokio::spawn(async move { hub.start().await });
+    wait_until_listening(&hub_handle).await?;
+
+    let mut handles = Vec::new();
+    for i in 1..=24 {
+        let mut node = Node::<TestMessage>::new(NodeConfig {
+            listen_addr: format!("/memory/{}", 9400 + i),
+            bootstrap_addr: Some(hub_addr.clone()),
+            peer_storage: PeerStorageKind::Memory,
+            ..Default::default()
+        })
+        .await?;
+        handles.push(node.handle());
+        tokio::spawn(async move { node.start().await });
+    }
+
+    for handle in &handles {
+        wait_until(|| async {
+            handle
+                .peer_info(hub_id)
+                .await
+                .is_ok_and(|info| info.is_some())
+        })
+        .await?;
+    }
+    Ok(())
+}